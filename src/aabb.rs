@@ -0,0 +1,63 @@
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+/// 軸平行境界ボックス(Axis-Aligned Bounding Box)
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    minimum: Vec3,
+    maximum: Vec3,
+}
+
+impl Aabb {
+    pub fn new(minimum: Vec3, maximum: Vec3) -> Aabb {
+        Aabb { minimum, maximum }
+    }
+
+    pub fn min(&self) -> Vec3 {
+        self.minimum
+    }
+
+    pub fn max(&self) -> Vec3 {
+        self.maximum
+    }
+
+    /// スラブ法による交差判定。レイがtmin〜tmaxの範囲でボックスを通過するかどうかを返す
+    pub fn hit(&self, ray: &Ray, tmin: f32, tmax: f32) -> bool {
+        self.clip(ray, tmin, tmax).is_some()
+    }
+
+    /// レイがtmin〜tmaxの範囲でボックスの中にある区間。通過しない場合はNone
+    pub fn clip(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<(f32, f32)> {
+        let mut tmin = tmin;
+        let mut tmax = tmax;
+        for a in 0..3 {
+            let inv_d = 1.0 / ray.direction()[a];
+            let mut t0 = (self.minimum[a] - ray.origin()[a]) * inv_d;
+            let mut t1 = (self.maximum[a] - ray.origin()[a]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            tmin = if t0 > tmin { t0 } else { tmin };
+            tmax = if t1 < tmax { t1 } else { tmax };
+            if tmax <= tmin {
+                return None;
+            }
+        }
+        Some((tmin, tmax))
+    }
+
+    /// 2つのボックスを両方とも囲むボックス
+    pub fn surrounding_box(box0: Aabb, box1: Aabb) -> Aabb {
+        let small = Vec3::new(
+            box0.min().x().min(box1.min().x()),
+            box0.min().y().min(box1.min().y()),
+            box0.min().z().min(box1.min().z()),
+        );
+        let big = Vec3::new(
+            box0.max().x().max(box1.max().x()),
+            box0.max().y().max(box1.max().y()),
+            box0.max().z().max(box1.max().z()),
+        );
+        Aabb::new(small, big)
+    }
+}
@@ -0,0 +1,662 @@
+use std::f32::consts::PI;
+
+use crate::hitrecord::HitRecord;
+use crate::hittable::Hittable;
+use crate::integrator::{power_heuristic, Integrator, SHADOW_EPSILON};
+use crate::light::Light;
+use crate::material::ScatterRecord;
+use crate::pdf::{CosinePdf, Pdf};
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::vec3::{color, random_f32, unit_vector, Vec3};
+
+/// 双方向パストレーシング
+///
+/// カメラから伸ばした経路と光源から伸ばした経路の頂点どうしをつなぎ、
+/// 同じ経路を作れるすべての方法の確率密度からMIS(べき乗ヒューリスティック)で重みを付けて足し合わせる。
+/// 光源側の経路をカメラに直接つなぐ方法(ライトトレーシング)は、他のピクセルに寄与するので使わない
+pub struct BidirectionalPathTracer {
+    max_depth: u32,
+}
+
+impl BidirectionalPathTracer {
+    pub fn new(max_depth: u32) -> BidirectionalPathTracer {
+        BidirectionalPathTracer { max_depth }
+    }
+}
+
+/// 経路の頂点
+struct Vertex<'a> {
+    point: Vec3,
+    /// 表面の法線。カメラや霧や煙の中の頂点はNone
+    normal: Option<Vec3>,
+    /// 物体の上の頂点のHitRecord。カメラはNone
+    record: Option<HitRecord<'a>>,
+    /// 鏡面反射や屈折の頂点。他の頂点とつなげない
+    delta: bool,
+    /// 経路の始点からこの頂点までの寄与
+    beta: Vec3,
+    /// 経路を伸ばす向きでこの頂点を選んだ確率密度(面積あたり)
+    pdf_fwd: f32,
+    /// 反対側から経路を伸ばした場合にこの頂点を選ぶ確率密度(面積あたり)
+    pdf_rev: f32,
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(point: Vec3) -> Vertex<'a> {
+        Vertex {
+            point,
+            normal: None,
+            record: None,
+            delta: false,
+            beta: color(1.0, 1.0, 1.0),
+            pdf_fwd: 1.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn surface(record: HitRecord<'a>, beta: Vec3) -> Vertex<'a> {
+        Vertex {
+            point: record.point(),
+            normal: if record.material().is_volume() {
+                None
+            } else {
+                Some(record.normal())
+            },
+            record: Some(record),
+            delta: false,
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    /// 光源の上で面積あたりpdf_posの確率密度で選んだ点
+    fn light(record: HitRecord<'a>, le: Vec3, pdf_pos: f32) -> Vertex<'a> {
+        let mut vertex = Vertex::surface(record, le / pdf_pos);
+        vertex.pdf_fwd = pdf_pos;
+        vertex
+    }
+
+    /// この頂点から見た立体角あたりの確率密度を、nextの位置での面積あたりの確率密度に直す
+    fn convert_density(&self, pdf: f32, next: &Vertex) -> f32 {
+        let d = next.point - self.point;
+        let distance_squared = d.length_squared();
+        if distance_squared <= 0.0 {
+            return 0.0;
+        }
+        match next.normal {
+            Some(normal) => {
+                pdf * normal.dot(d).abs() / (distance_squared * distance_squared.sqrt())
+            }
+            None => pdf / distance_squared,
+        }
+    }
+
+    /// 光源の上のこの頂点からnextの向きに光が出ていく確率密度(nextの面積あたり)
+    ///
+    /// 光源は両面から余弦に比例した向きに光を出す
+    fn pdf_light(&self, next: &Vertex) -> f32 {
+        let cosine = match self.normal {
+            Some(normal) => normal.dot(unit_vector(next.point - self.point)).abs(),
+            None => 1.0,
+        };
+        self.convert_density(cosine / (2.0 * PI), next)
+    }
+
+    /// prevから来た経路がこの頂点で散乱してnextを選ぶ確率密度(nextの面積あたり)
+    ///
+    /// prevがNoneの場合は光源側の経路の始点からnextへ光が出ていく確率密度
+    fn pdf(&self, prev: Option<&Vertex>, next: &Vertex, time: f32) -> f32 {
+        let prev = match prev {
+            Some(prev) => prev,
+            None => return self.pdf_light(next),
+        };
+        let record = match &self.record {
+            Some(record) => record,
+            None => return 0.0,
+        };
+        let ray_in = Ray::new(prev.point, self.point - prev.point, time);
+        match record.material().scatter(&ray_in, record) {
+            Some(ScatterRecord::Diffuse { pdf }) => {
+                self.convert_density(pdf.value(next.point - self.point), next)
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// prevから来た経路がこの頂点で散乱してnextへ向かう割合(BSDFと余弦の積)
+    fn eval(&self, prev: &Vertex, next: Vec3, time: f32) -> Vec3 {
+        match &self.record {
+            Some(record) => record.material().eval(
+                &Ray::new(prev.point, self.point - prev.point, time),
+                record,
+                &Ray::new(self.point, next - self.point, time),
+            ),
+            None => color(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+impl Integrator for BidirectionalPathTracer {
+    fn li(&self, ray: Ray, scene: &Scene) -> Vec3 {
+        let time = ray.time();
+        let max_depth = self.max_depth as usize;
+
+        let mut camera_path = vec![Vertex::camera(ray.origin())];
+        let escaped = random_walk(
+            scene,
+            ray,
+            color(1.0, 1.0, 1.0),
+            1.0,
+            max_depth + 2,
+            &mut camera_path,
+        );
+        let mut radiance = light_source_contribution(scene, &camera_path, escaped, time);
+
+        let mut light_path = Vec::new();
+        generate_light_path(scene, time, max_depth + 1, &mut light_path);
+
+        for t in 2..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                if s + t - 2 > max_depth {
+                    break;
+                }
+                let (contribution, sampled) = connect(scene, &camera_path, &light_path, s, t, time);
+                if is_black(contribution) {
+                    continue;
+                }
+                let weight = mis_weight(
+                    scene,
+                    &camera_path,
+                    &light_path,
+                    sampled.as_ref(),
+                    s,
+                    t,
+                    time,
+                );
+                radiance = radiance + contribution * weight;
+            }
+        }
+
+        radiance
+    }
+}
+
+/// rayの向きに経路を伸ばし、頂点をpathに追加していく。pdf_fwdはrayの向きを選んだ確率密度(立体角あたり)
+///
+/// 物体に当たらずに抜けた場合は、最後のレイとそこまでの経路の寄与を返す
+fn random_walk<'a>(
+    scene: &'a Scene,
+    ray: Ray,
+    beta: Vec3,
+    pdf_fwd: f32,
+    max_vertices: usize,
+    path: &mut Vec<Vertex<'a>>,
+) -> Option<(Ray, Vec3)> {
+    let time = ray.time();
+    let mut ray = ray;
+    let mut beta = beta;
+    let mut pdf_fwd = pdf_fwd;
+
+    while path.len() < max_vertices {
+        let record = match scene.world().hit(&ray, 0.001, f32::INFINITY, time) {
+            None => return Some((ray, beta)),
+            Some(record) => record,
+        };
+        let material = record.material();
+        let scatter = material.scatter(&ray, &record);
+
+        let prev = path.len() - 1;
+        let mut vertex = Vertex::surface(record, beta);
+        vertex.pdf_fwd = path[prev].convert_density(pdf_fwd, &vertex);
+
+        let (scattered, pdf_rev) = match scatter {
+            None => {
+                path.push(vertex);
+                break;
+            }
+            Some(ScatterRecord::Specular {
+                attenuation,
+                ray: scattered,
+            }) => {
+                // 鏡面反射は確率密度をもたないので0としておき、MISの重みを求めるときに除く
+                vertex.delta = true;
+                beta = beta * attenuation;
+                pdf_fwd = 0.0;
+                (scattered, 0.0)
+            }
+            Some(ScatterRecord::Diffuse { pdf }) => {
+                let direction = pdf.sample();
+                let pdf_value = pdf.value(direction);
+                if pdf_value <= 0.0 {
+                    path.push(vertex);
+                    break;
+                }
+                let record = vertex.record.as_ref().unwrap();
+                let scattered = Ray::new(record.point(), direction, time);
+                beta = beta * material.eval(&ray, record, &scattered) / pdf_value;
+                pdf_fwd = pdf_value;
+
+                // 逆向きに経路を伸ばした場合に、元の向きを選ぶ確率密度
+                let reverse_in = Ray::new(record.point() + direction, -direction, time);
+                let pdf_rev = match material.scatter(&reverse_in, record) {
+                    Some(ScatterRecord::Diffuse { pdf }) => pdf.value(-ray.direction()),
+                    _ => 0.0,
+                };
+                (scattered, pdf_rev)
+            }
+        };
+
+        path[prev].pdf_rev = vertex.convert_density(pdf_rev, &path[prev]);
+        path.push(vertex);
+        ray = scattered;
+    }
+
+    None
+}
+
+/// 光源の上の点と光を出す向きを選び、そこから経路を伸ばす
+fn generate_light_path<'a>(
+    scene: &'a Scene,
+    time: f32,
+    max_vertices: usize,
+    path: &mut Vec<Vertex<'a>>,
+) {
+    let lights = scene.lights();
+    let area = lights.area(time);
+    if area <= 0.0 || max_vertices == 0 {
+        return;
+    }
+    let record = match lights.sample_surface(time) {
+        Some(record) => record,
+        None => return,
+    };
+    let pdf_pos = 1.0 / area;
+
+    // 両面のどちらかを選んで、余弦に比例した向きに光を出す
+    let normal = record.normal();
+    let side = if random_f32(0.0, 1.0) < 0.5 {
+        normal
+    } else {
+        -normal
+    };
+    let direction = CosinePdf::new(side).sample();
+    let cosine = normal.dot(unit_vector(direction)).abs();
+    let pdf_dir = cosine / (2.0 * PI);
+    if pdf_dir <= 0.0 {
+        return;
+    }
+
+    let point = record.point();
+    let le = record
+        .material()
+        .emitted(&Ray::new(point + direction, -direction, time), &record);
+    path.push(Vertex::light(record, le, pdf_pos));
+
+    let beta = le * (cosine / (pdf_pos * pdf_dir));
+    random_walk(
+        scene,
+        Ray::new(point, direction, time),
+        beta,
+        pdf_dir,
+        max_vertices,
+        path,
+    );
+}
+
+/// カメラ側のt個の頂点と光源側のs個の頂点をつないだ経路の寄与
+///
+/// s = 1の場合は光源の上の点を選び直すので、その頂点も返す
+fn connect<'a>(
+    scene: &'a Scene,
+    camera_path: &[Vertex<'a>],
+    light_path: &[Vertex<'a>],
+    s: usize,
+    t: usize,
+    time: f32,
+) -> (Vec3, Option<Vertex<'a>>) {
+    let black = color(0.0, 0.0, 0.0);
+    let pt = &camera_path[t - 1];
+    let pt_minus = &camera_path[t - 2];
+
+    match s {
+        0 => {
+            // カメラ側の経路が光源に当たった
+            let record = match &pt.record {
+                Some(record) => record,
+                None => return (black, None),
+            };
+            let ray_in = Ray::new(pt_minus.point, pt.point - pt_minus.point, time);
+            (pt.beta * record.material().emitted(&ray_in, record), None)
+        }
+        1 => {
+            // 光源の上の点を選んでつなぐ
+            if pt.delta {
+                return (black, None);
+            }
+            let lights = scene.lights();
+            let area = lights.area(time);
+            if area <= 0.0 {
+                return (black, None);
+            }
+            let record = match lights.sample_surface(time) {
+                Some(record) => record,
+                None => return (black, None),
+            };
+            let pdf_pos = 1.0 / area;
+            let d = record.point() - pt.point;
+            let le = record
+                .material()
+                .emitted(&Ray::new(pt.point, d, time), &record);
+            let sampled = Vertex::light(record, le, pdf_pos);
+
+            let f = pt.eval(pt_minus, sampled.point, time);
+            // 余弦は点を選んだ側のものだけ掛ける。ptの側の余弦はfに含まれている
+            let g = pt.convert_density(1.0, &sampled);
+            let contribution = pt.beta * f * sampled.beta * g;
+            if is_black(contribution) || !visible(scene, pt.point, sampled.point, time) {
+                return (black, None);
+            }
+            (contribution, Some(sampled))
+        }
+        _ => {
+            let qs = &light_path[s - 1];
+            let qs_minus = &light_path[s - 2];
+            if pt.delta || qs.delta {
+                return (black, None);
+            }
+            let f_pt = pt.eval(pt_minus, qs.point, time);
+            let f_qs = qs.eval(qs_minus, pt.point, time);
+            let distance_squared = (qs.point - pt.point).length_squared();
+            let contribution = pt.beta * f_pt * f_qs * qs.beta / distance_squared;
+            if is_black(contribution) || !visible(scene, pt.point, qs.point, time) {
+                return (black, None);
+            }
+            (contribution, None)
+        }
+    }
+}
+
+/// s個とt個の頂点でつないだ経路のMISの重み
+///
+/// 同じ経路を他の分け方で作る確率密度との比を、つないだ点から両側にたどりながら掛け合わせていく
+fn mis_weight(
+    scene: &Scene,
+    camera_path: &[Vertex],
+    light_path: &[Vertex],
+    sampled: Option<&Vertex>,
+    s: usize,
+    t: usize,
+    time: f32,
+) -> f32 {
+    // (pdf_fwd, pdf_rev, delta)。つないだ点の周りは確率密度を求め直す
+    let mut camera_pdfs: Vec<(f32, f32, bool)> = camera_path[..t]
+        .iter()
+        .map(|v| (v.pdf_fwd, v.pdf_rev, v.delta))
+        .collect();
+    let mut light_pdfs: Vec<(f32, f32, bool)> = light_path[..s]
+        .iter()
+        .map(|v| (v.pdf_fwd, v.pdf_rev, v.delta))
+        .collect();
+
+    let pt = &camera_path[t - 1];
+    let pt_minus = &camera_path[t - 2];
+    if s == 0 {
+        // 光源に当たった点を、光源の上で選ぶ確率密度
+        let lights = scene.lights();
+        let on_light = lights.pdf_value(pt_minus.point, pt.point - pt_minus.point, time) > 0.0;
+        let area = lights.area(time);
+        if !on_light || area <= 0.0 {
+            // 光源として登録されていない物体の光は、カメラ側の経路からしか作れない
+            return 1.0;
+        }
+        camera_pdfs[t - 1].1 = 1.0 / area;
+        camera_pdfs[t - 2].1 = pt.pdf_light(pt_minus);
+    } else {
+        let qs = if s == 1 {
+            sampled.unwrap()
+        } else {
+            &light_path[s - 1]
+        };
+        let qs_minus = if s > 1 {
+            Some(&light_path[s - 2])
+        } else {
+            None
+        };
+        if s == 1 {
+            light_pdfs[0] = (qs.pdf_fwd, qs.pdf_rev, qs.delta);
+        }
+        camera_pdfs[t - 1].1 = qs.pdf(qs_minus, pt, time);
+        camera_pdfs[t - 2].1 = pt.pdf(Some(qs), pt_minus, time);
+        light_pdfs[s - 1].1 = pt.pdf(Some(pt_minus), qs, time);
+        if let Some(qs_minus) = qs_minus {
+            light_pdfs[s - 2].1 = qs.pdf(Some(pt), qs_minus, time);
+        }
+    }
+
+    // 鏡面反射の頂点の確率密度は0になっているので1に置き換えて比を求める
+    let remap = |pdf: f32| if pdf != 0.0 { pdf } else { 1.0 };
+    let mut sum = 0.0;
+
+    // カメラ側の頂点を光源側に移していく。カメラ側が1つだけになる方法は使わない
+    let mut ratio = 1.0;
+    for i in (2..t).rev() {
+        let r = remap(camera_pdfs[i].1) / remap(camera_pdfs[i].0);
+        ratio *= r * r;
+        if !camera_pdfs[i].2 && !camera_pdfs[i - 1].2 {
+            sum += ratio;
+        }
+    }
+
+    // 光源側の頂点をカメラ側に移していく
+    let mut ratio = 1.0;
+    for i in (0..s).rev() {
+        let r = remap(light_pdfs[i].1) / remap(light_pdfs[i].0);
+        ratio *= r * r;
+        let delta_prev = i > 0 && light_pdfs[i - 1].2;
+        if !light_pdfs[i].2 && !delta_prev {
+            sum += ratio;
+        }
+    }
+
+    1.0 / (1.0 + sum)
+}
+
+/// 物体とは別の光源と環境からの光
+///
+/// 光源側の経路は光る物体からしか始めないので、カメラ側の経路が光源に当たる方法と、
+/// カメラ側の各頂点から光源を直接サンプリングする方法の2つだけをMISで組み合わせる。
+/// escapedはカメラ側の経路が物体に当たらずに抜けた場合の最後のレイと経路の寄与
+fn light_source_contribution(
+    scene: &Scene,
+    camera_path: &[Vertex],
+    escaped: Option<(Ray, Vec3)>,
+    time: f32,
+) -> Vec3 {
+    let environment = scene.environment();
+    // 直接サンプリングする光源
+    let lights: Vec<&dyn Light> = scene
+        .light_sources()
+        .iter()
+        .map(|light| light.as_ref())
+        .chain(scene.environment_light())
+        .collect();
+    let count = lights.len() as f32;
+    let mut radiance = color(0.0, 0.0, 0.0);
+
+    for (i, vertex) in camera_path.iter().enumerate() {
+        // 頂点iから次の頂点へ向かうレイと、その先の経路の寄与
+        let (ray, tmax, beta) = match camera_path.get(i + 1) {
+            Some(next) => {
+                let d = next.point - vertex.point;
+                let distance = d.length();
+                (
+                    Ray::new(vertex.point, d / distance, time),
+                    distance,
+                    next.beta,
+                )
+            }
+            None => match &escaped {
+                Some((ray, beta)) => (
+                    Ray::new(ray.origin(), ray.direction(), ray.time()),
+                    f32::INFINITY,
+                    *beta,
+                ),
+                None => continue,
+            },
+        };
+
+        // 頂点iで散乱の向きを選ぶ分布。カメラや鏡面反射の頂点ではNone
+        let scatter_pdf = match (&vertex.record, i.checked_sub(1)) {
+            (Some(record), Some(prev)) if !vertex.delta => {
+                let ray_in = Ray::new(
+                    camera_path[prev].point,
+                    vertex.point - camera_path[prev].point,
+                    time,
+                );
+                match record.material().scatter(&ray_in, record) {
+                    Some(ScatterRecord::Diffuse { pdf }) => Some(pdf),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        // 経路が光源に当たる方法
+        let hit_weight = |light: &dyn Light| match &scatter_pdf {
+            Some(pdf) => power_heuristic(
+                pdf.value(ray.direction()),
+                light.pdf_value(vertex.point, ray.direction()) / count,
+            ),
+            None => 1.0,
+        };
+        for light in scene.light_sources() {
+            if let Some(le) = light.hit(&ray, tmax) {
+                radiance = radiance + beta * le * hit_weight(light.as_ref());
+            }
+        }
+        // 物体に当たらずに抜けた経路には環境の光が届く
+        if tmax == f32::INFINITY {
+            let le = environment.radiance(ray.direction());
+            if i == 0 {
+                // カメラから直接見える背景
+                if environment.camera_visible() {
+                    radiance = radiance + beta * le;
+                }
+            } else if let Some(light) = scene.environment_light() {
+                radiance = radiance + beta * le * hit_weight(light);
+            } else if environment.camera_visible()
+                && camera_path[1..=i].iter().all(|vertex| vertex.delta)
+            {
+                // 照らす光として使わない場合も、鏡やガラスに映る背景は見える
+                radiance = radiance + beta * le;
+            }
+        }
+
+        // 光源を直接サンプリングする方法
+        let pdf = match &scatter_pdf {
+            Some(pdf) if !lights.is_empty() => pdf,
+            _ => continue,
+        };
+        let index = ((random_f32(0.0, 1.0) * count) as usize).min(lights.len() - 1);
+        let light = lights[index];
+        let sample = match light.sample(vertex.point) {
+            Some(sample) => sample,
+            None => continue,
+        };
+        let light_pdf = sample.pdf() / count;
+        if light_pdf <= 0.0 {
+            continue;
+        }
+        let shadow_ray = Ray::new(vertex.point, sample.direction(), time);
+        if scene
+            .world()
+            .hit(&shadow_ray, 0.001, sample.distance() - SHADOW_EPSILON, time)
+            .is_some()
+        {
+            continue;
+        }
+        let f = vertex.eval(&camera_path[i - 1], vertex.point + sample.direction(), time);
+        let weight = if light.is_delta() {
+            1.0
+        } else {
+            power_heuristic(light_pdf, pdf.value(sample.direction()))
+        };
+        radiance = radiance + vertex.beta * f * sample.radiance() * (weight / light_pdf);
+    }
+
+    radiance
+}
+
+/// 2点の間に遮るものがないか
+fn visible(scene: &Scene, p0: Vec3, p1: Vec3, time: f32) -> bool {
+    let d = p1 - p0;
+    let distance = d.length();
+    let ray = Ray::new(p0, d / distance, time);
+    scene
+        .world()
+        .hit(&ray, 0.001, distance - SHADOW_EPSILON, time)
+        .is_none()
+}
+
+fn is_black(c: Vec3) -> bool {
+    c.x() <= 0.0 && c.y() <= 0.0 && c.z() <= 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::color::luminance;
+    use crate::integrator::PathTracer;
+    use crate::sampler::{with_sampler, Sampler};
+
+    struct SeededSampler(StdRng);
+
+    impl Sampler for SeededSampler {
+        fn next(&mut self) -> f32 {
+            self.0.gen()
+        }
+    }
+
+    /// size × sizeのピクセルをそれぞれstrata × strataに分けた区画の中心へレイを飛ばした平均の放射輝度
+    ///
+    /// 光源がカメラから直接見えるので、ピクセル内の位置はランダムにせず、どの方法でも同じにする
+    fn mean_radiance(integrator: &dyn Integrator, scene: &Scene, size: u32, strata: u32) -> Vec3 {
+        let sampler = Rc::new(RefCell::new(SeededSampler(StdRng::seed_from_u64(7))));
+        let n = size * strata;
+        with_sampler(sampler, || {
+            let mut sum = color(0.0, 0.0, 0.0);
+            for y in 0..n {
+                for x in 0..n {
+                    let u = (x as f32 + 0.5) / n as f32;
+                    let v = (y as f32 + 0.5) / n as f32;
+                    sum = sum + integrator.li(scene.camera().get_ray(u, v), scene);
+                }
+            }
+            sum / (n * n) as f32
+        })
+    }
+
+    #[test]
+    fn converges_to_the_path_tracer_in_the_cornell_box() {
+        let scene = Scene::cornell_box(1.0);
+        let path = mean_radiance(&PathTracer::new(50, 5), &scene, 16, 8);
+        let bdpt = mean_radiance(&BidirectionalPathTracer::new(50), &scene, 16, 8);
+        // 128 × 128個の経路での平均。ノイズによる差はシードを変えても1.5%程度に収まる
+        let relative_error = (luminance(bdpt) - luminance(path)).abs() / luminance(path);
+        assert!(
+            relative_error < 0.03,
+            "path: {:?}, bdpt: {:?}, error: {}",
+            path,
+            bdpt,
+            relative_error
+        );
+    }
+}
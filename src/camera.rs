@@ -16,6 +16,7 @@ pub struct Camera {
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         look_from: Vec3,
         look_at: Vec3,
@@ -56,6 +57,21 @@ impl Camera {
         }
     }
 
+    /// カメラの位置
+    pub fn look_from(&self) -> Vec3 {
+        self.look_from
+    }
+
+    /// シャッターが開く時刻
+    pub fn time0(&self) -> f32 {
+        self.time0
+    }
+
+    /// シャッターが閉じる時刻
+    pub fn time1(&self) -> f32 {
+        self.time1
+    }
+
     pub fn get_ray(&self, s: f32, t: f32) -> Ray {
         let rd = random_in_unit_disk() * self.lens_radius;
         let offset = self.u * rd.x() + self.v * rd.y();
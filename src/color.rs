@@ -16,6 +16,20 @@ pub fn write_color(pixel: &mut Rgb<u8>, color: Vec3, sample_per_pixel: u32) {
     *pixel = image::Rgb([ir, ig, ib]);
 }
 
+/// 色の明るさ(輝度)
+pub fn luminance(c: Vec3) -> f32 {
+    0.2126 * c.x() + 0.7152 * c.y() + 0.0722 * c.z()
+}
+
+/// sRGBで符号化された値(0〜1)を線形な値に直す
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
 fn clamp(x: f32, min: f32, max: f32) -> f32 {
     if x < min {
         min
@@ -0,0 +1,243 @@
+/// 区間[0, 1)を等分した各区間で一定の値をとる関数に比例した1次元の分布
+pub struct Distribution1D {
+    func: Vec<f32>,
+    /// 累積分布。cdf[i]はi番目の区間の始まりまでの確率
+    cdf: Vec<f32>,
+    integral: f32,
+}
+
+impl Distribution1D {
+    /// funcは各区間の値(0以上)。すべて0の場合は一様な分布にする。区間は1つ以上必要
+    pub fn new(func: Vec<f32>) -> Distribution1D {
+        assert!(!func.is_empty(), "分布を作るには区間が1つ以上必要です");
+        let n = func.len();
+        let mut cdf = Vec::with_capacity(n + 1);
+        cdf.push(0.0);
+        for (i, f) in func.iter().enumerate() {
+            cdf.push(cdf[i] + f / n as f32);
+        }
+        let integral = cdf[n];
+        if integral > 0.0 {
+            for c in &mut cdf {
+                *c /= integral;
+            }
+        } else {
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f32 / n as f32;
+            }
+        }
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    /// 関数の[0, 1)での積分
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    /// 一様な乱数uから値を選び、その値と確率密度、区間の番号を返す
+    pub fn sample(&self, u: f32) -> (f32, f32, usize) {
+        let n = self.func.len();
+        let offset = self
+            .cdf
+            .partition_point(|&c| c <= u)
+            .saturating_sub(1)
+            .min(n - 1);
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        let du = if width > 0.0 {
+            ((u - self.cdf[offset]) / width).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let x = ((offset as f32 + du) / n as f32).min(1.0 - f32::EPSILON);
+        (x, self.pdf_at(offset), offset)
+    }
+
+    /// xの確率密度
+    pub fn pdf(&self, x: f32) -> f32 {
+        let n = self.func.len();
+        self.pdf_at(((x * n as f32) as usize).min(n - 1))
+    }
+
+    fn pdf_at(&self, offset: usize) -> f32 {
+        if self.integral > 0.0 {
+            self.func[offset] / self.integral
+        } else {
+            1.0
+        }
+    }
+}
+
+/// [0, 1) × [0, 1)を格子に分けた各区画で一定の値をとる関数に比例した2次元の分布
+///
+/// まずvの周辺分布から行を選び、その行の中の条件付き分布からuを選ぶ
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// funcは横nu個、縦nv個の区画の値を行の順に並べたもの。nuとnvは1以上
+    pub fn new(func: &[f32], nu: usize, nv: usize) -> Distribution2D {
+        assert!(nu > 0 && nv > 0, "分布を作るには区画が1つ以上必要です");
+        assert_eq!(func.len(), nu * nv, "値の数が区画の数と違います");
+        let conditional: Vec<Distribution1D> = func
+            .chunks(nu)
+            .take(nv)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral()).collect());
+        Distribution2D {
+            conditional,
+            marginal,
+        }
+    }
+
+    /// 一様な乱数(u0, u1)から点(u, v)を選び、その点と確率密度を返す
+    pub fn sample(&self, u0: f32, u1: f32) -> ((f32, f32), f32) {
+        let (v, pdf_v, row) = self.marginal.sample(u1);
+        let (u, pdf_u, _) = self.conditional[row].sample(u0);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    /// 点(u, v)の確率密度
+    pub fn pdf(&self, u: f32, v: f32) -> f32 {
+        let nv = self.marginal.count();
+        let row = ((v * nv as f32) as usize).min(nv - 1);
+        self.conditional[row].pdf(u) * self.marginal.pdf(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 区間ごとの値が大きく違い、0の区間も含む関数
+    const FUNC: [f32; 8] = [0.0, 1.0, 3.0, 0.5, 2.0, 0.0, 4.0, 1.5];
+
+    /// 横6個、縦4個の区画の明るさが一様でない画像
+    fn image() -> Vec<f32> {
+        (0..24)
+            .map(|i| {
+                let (x, y) = (i % 6, i / 6);
+                if x == 4 && y == 1 {
+                    0.0
+                } else {
+                    ((x + 1) * (y * y + 1)) as f32
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pdf_1d_integrates_to_one() {
+        let distribution = Distribution1D::new(FUNC.to_vec());
+        let n = 1024;
+        let integral: f32 = (0..n)
+            .map(|i| distribution.pdf((i as f32 + 0.5) / n as f32) / n as f32)
+            .sum();
+        assert!((integral - 1.0).abs() < 1e-4, "integral: {}", integral);
+    }
+
+    #[test]
+    fn sample_1d_matches_pdf() {
+        let distribution = Distribution1D::new(FUNC.to_vec());
+        let n = 4096;
+        let mut counts = [0; 8];
+        for i in 0..n {
+            let (x, pdf, offset) = distribution.sample((i as f32 + 0.5) / n as f32);
+            assert!((0.0..1.0).contains(&x));
+            assert_eq!(offset, (x * 8.0) as usize);
+            assert!(pdf > 0.0, "値が0の区間が選ばれた: {}", offset);
+            assert_eq!(pdf, distribution.pdf(x));
+            counts[offset] += 1;
+        }
+        // 各区間が選ばれる割合は、確率密度を区間で積分した値になる
+        for (offset, count) in counts.iter().enumerate() {
+            let expected = distribution.pdf((offset as f32 + 0.5) / 8.0) / 8.0;
+            let actual = *count as f32 / n as f32;
+            assert!(
+                (actual - expected).abs() < 1e-3,
+                "offset: {}, expected: {}, actual: {}",
+                offset,
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn zero_function_is_uniform() {
+        let distribution = Distribution1D::new(vec![0.0; 4]);
+        assert_eq!(distribution.pdf(0.3), 1.0);
+        let (x, pdf, offset) = distribution.sample(0.6);
+        assert!((x - 0.6).abs() < 1e-6);
+        assert_eq!(pdf, 1.0);
+        assert_eq!(offset, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "区間が1つ以上必要")]
+    fn empty_1d_is_rejected() {
+        Distribution1D::new(Vec::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "区画が1つ以上必要")]
+    fn empty_2d_is_rejected() {
+        Distribution2D::new(&[], 0, 4);
+    }
+
+    #[test]
+    fn pdf_2d_integrates_to_one() {
+        let distribution = Distribution2D::new(&image(), 6, 4);
+        let n = 240;
+        let mut integral = 0.0;
+        for j in 0..n {
+            for i in 0..n {
+                let u = (i as f32 + 0.5) / n as f32;
+                let v = (j as f32 + 0.5) / n as f32;
+                integral += distribution.pdf(u, v) / (n * n) as f32;
+            }
+        }
+        assert!((integral - 1.0).abs() < 1e-3, "integral: {}", integral);
+    }
+
+    #[test]
+    fn sample_2d_matches_pdf() {
+        let func = image();
+        let total: f32 = func.iter().sum();
+        let distribution = Distribution2D::new(&func, 6, 4);
+        let n = 256;
+        let mut counts = [0; 24];
+        for j in 0..n {
+            for i in 0..n {
+                let u0 = (i as f32 + 0.5) / n as f32;
+                let u1 = (j as f32 + 0.5) / n as f32;
+                let ((u, v), pdf) = distribution.sample(u0, u1);
+                assert!((0.0..1.0).contains(&u) && (0.0..1.0).contains(&v));
+                assert!((pdf - distribution.pdf(u, v)).abs() <= pdf * 1e-5);
+                counts[(v * 4.0) as usize * 6 + (u * 6.0) as usize] += 1;
+            }
+        }
+        // 各区画が選ばれる割合は、その区画の値に比例する
+        for (i, count) in counts.iter().enumerate() {
+            let expected = func[i] / total;
+            let actual = *count as f32 / (n * n) as f32;
+            assert!(
+                (actual - expected).abs() < 2e-3,
+                "cell: {}, expected: {}, actual: {}",
+                i,
+                expected,
+                actual
+            );
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+
+use crate::environment_map::EnvironmentMap;
+use crate::light::{emission_from_infinity, EmissionSample, Light, LightSample};
+use crate::pdf::{Pdf, UniformSpherePdf};
+use crate::ray::Ray;
+use crate::vec3::{color, unit_vector, Vec3};
+
+/// 環境の光の決め方
+pub enum EnvironmentSource {
+    /// 真っ暗。光源だけがシーンを照らす
+    Black,
+    /// 白から青へのグラデーションの空。真上と真下の少し手前で白と青になり、その先はさらに外側へ伸ばした色になる
+    Sky,
+    /// どの向きも同じ色
+    Solid(Vec3),
+    /// 真下のbottomから真上のtopへ、高さに比例して変わる色
+    Gradient { bottom: Vec3, top: Vec3 },
+    /// HDR画像
+    Image(EnvironmentMap),
+}
+
+/// シーンを無限遠から囲み、レイが何にも当たらなかったときに届く光
+///
+/// カメラから直接見える背景と、シーンを照らす光のそれぞれで使うかどうかを選べる
+pub struct Environment {
+    source: EnvironmentSource,
+    camera_visible: bool,
+    lighting: bool,
+}
+
+impl Environment {
+    pub fn new(source: EnvironmentSource) -> Environment {
+        Environment {
+            source,
+            camera_visible: true,
+            lighting: true,
+        }
+    }
+
+    pub fn black() -> Environment {
+        Environment::new(EnvironmentSource::Black)
+    }
+
+    pub fn solid(color: Vec3) -> Environment {
+        Environment::new(EnvironmentSource::Solid(color))
+    }
+
+    pub fn gradient(bottom: Vec3, top: Vec3) -> Environment {
+        Environment::new(EnvironmentSource::Gradient { bottom, top })
+    }
+
+    /// 白から青へのグラデーションの空
+    pub fn sky() -> Environment {
+        Environment::new(EnvironmentSource::Sky)
+    }
+
+    pub fn image(environment_map: EnvironmentMap) -> Environment {
+        Environment::new(EnvironmentSource::Image(environment_map))
+    }
+
+    /// カメラから直接見えるかどうか。見えない場合、背景は黒になる
+    ///
+    /// 鏡面反射や屈折だけを経てカメラに届く場合(鏡やガラスに映る背景)も、カメラから直接見えるものとして扱う。
+    /// ただし照らす光として使う場合は、鏡やガラスにも常に映る
+    pub fn with_camera_visibility(mut self, camera_visible: bool) -> Environment {
+        self.camera_visible = camera_visible;
+        self
+    }
+
+    /// シーンを照らすかどうか。照らさない場合も、カメラから直接見える背景には使える
+    pub fn with_lighting(mut self, lighting: bool) -> Environment {
+        self.lighting = lighting;
+        self
+    }
+
+    pub fn source(&self) -> &EnvironmentSource {
+        &self.source
+    }
+
+    pub fn camera_visible(&self) -> bool {
+        self.camera_visible
+    }
+
+    pub fn lighting(&self) -> bool {
+        self.lighting
+    }
+
+    /// どの向きからも光が届かない
+    pub fn is_black(&self) -> bool {
+        matches!(self.source, EnvironmentSource::Black)
+    }
+
+    /// directionの向きから届く放射輝度
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        match &self.source {
+            EnvironmentSource::Black => color(0.0, 0.0, 0.0),
+            EnvironmentSource::Solid(color) => *color,
+            EnvironmentSource::Sky => {
+                let t = (unit_vector(direction).y() + FRAC_1_SQRT_2) * FRAC_1_SQRT_2;
+                color(1.0, 1.0, 1.0) * (1.0 - t) + color(0.5, 0.7, 1.0) * t
+            }
+            EnvironmentSource::Gradient { bottom, top } => {
+                let t = 0.5 * (unit_vector(direction).y() + 1.0);
+                *bottom * (1.0 - t) + *top * t
+            }
+            EnvironmentSource::Image(environment_map) => environment_map.radiance(direction),
+        }
+    }
+}
+
+/// 画像は明るい部分ほど選ばれやすい分布で、それ以外は全方向に一様に向きを選ぶ
+impl Light for Environment {
+    fn sample(&self, point: Vec3) -> Option<LightSample> {
+        match &self.source {
+            EnvironmentSource::Black => None,
+            EnvironmentSource::Image(environment_map) => environment_map.sample(point),
+            _ => {
+                let direction = UniformSpherePdf.sample();
+                Some(LightSample::new(
+                    direction,
+                    f32::INFINITY,
+                    self.radiance(direction),
+                    1.0 / (4.0 * PI),
+                ))
+            }
+        }
+    }
+
+    fn pdf_value(&self, point: Vec3, direction: Vec3) -> f32 {
+        match &self.source {
+            EnvironmentSource::Black => 0.0,
+            EnvironmentSource::Image(environment_map) => {
+                environment_map.pdf_value(point, direction)
+            }
+            _ => UniformSpherePdf.value(direction),
+        }
+    }
+
+    fn hit(&self, ray: &Ray, tmax: f32) -> Option<Vec3> {
+        // 無限遠にあるので、何にも当たらずに抜けたレイだけが届く
+        if tmax < f32::INFINITY || self.is_black() {
+            return None;
+        }
+        Some(self.radiance(ray.direction()))
+    }
+
+    fn sample_emission(&self, center: Vec3, radius: f32, distance: f32) -> Option<EmissionSample> {
+        emission_from_infinity(&self.sample(center)?, center, radius, distance)
+    }
+}
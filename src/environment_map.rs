@@ -0,0 +1,297 @@
+use std::f32::consts::PI;
+use std::path::Path;
+
+use image::error::{ParameterError, ParameterErrorKind};
+use image::{ImageError, ImageResult};
+
+use crate::color::luminance;
+use crate::distribution::Distribution2D;
+use crate::light::{emission_from_infinity, EmissionSample, Light, LightSample};
+use crate::ray::Ray;
+use crate::vec3::{color, random_f32, unit_vector, Vec3};
+
+/// 正距円筒図法(横が経度、縦が緯度)のHDR画像で、シーンを無限遠から囲む光
+///
+/// 画像の上端が真上(+y)、横の中央が-zの向きになる。
+/// 明るい部分ほど選ばれやすい分布で向きを選ぶので、小さく明るい光源を含む画像でもノイズが少ない
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+    intensity: f32,
+    /// y軸まわりの回転(弧度法)
+    rotation: f32,
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    /// pixelsは横width個、縦height個の放射輝度を左上から行の順に並べたもの。
+    /// intensityは明るさの倍率、rotationはy軸まわりの回転(度)。画像は1ピクセル以上必要
+    pub fn new(
+        width: usize,
+        height: usize,
+        pixels: Vec<Vec3>,
+        intensity: f32,
+        rotation: f32,
+    ) -> EnvironmentMap {
+        assert!(width > 0 && height > 0, "環境マップの画像が空です");
+        assert_eq!(
+            pixels.len(),
+            width * height,
+            "ピクセルの数が画像の大きさと違います"
+        );
+        // 明るさに比例させるが、極に近い行ほど立体角が小さいのでsinθを掛ける
+        let func: Vec<f32> = pixels
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let theta = PI * ((i / width) as f32 + 0.5) / height as f32;
+                luminance(*c) * theta.sin()
+            })
+            .collect();
+        let distribution = Distribution2D::new(&func, width, height);
+        EnvironmentMap {
+            width,
+            height,
+            pixels,
+            intensity,
+            rotation: rotation.to_radians(),
+            distribution,
+        }
+    }
+
+    /// 横width個、縦height個のピクセルの中心の向きの放射輝度をradianceで求めて作る
+    pub fn from_fn(
+        width: usize,
+        height: usize,
+        radiance: impl Fn(Vec3) -> Vec3,
+        intensity: f32,
+    ) -> EnvironmentMap {
+        let pixels = (0..width * height)
+            .map(|i| {
+                let u = ((i % width) as f32 + 0.5) / width as f32;
+                let v = ((i / width) as f32 + 0.5) / height as f32;
+                radiance(uv_to_direction(u, v, 0.0))
+            })
+            .collect();
+        EnvironmentMap::new(width, height, pixels, intensity, 0.0)
+    }
+
+    /// Radiance HDR(.hdr)やOpenEXR(.exr)の画像を読み込む
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        intensity: f32,
+        rotation: f32,
+    ) -> ImageResult<EnvironmentMap> {
+        let image = image::open(path)?.into_rgb32f();
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::DimensionMismatch,
+            )));
+        }
+        let pixels = image
+            .pixels()
+            .map(|p| color(p[0].max(0.0), p[1].max(0.0), p[2].max(0.0)))
+            .collect();
+        Ok(EnvironmentMap::new(
+            width as usize,
+            height as usize,
+            pixels,
+            intensity,
+            rotation,
+        ))
+    }
+
+    /// 向きを画像上の位置(u, v)に直す
+    fn direction_to_uv(&self, direction: Vec3) -> (f32, f32) {
+        let d = unit_vector(direction);
+        // 極の近くでもacosより精度が落ちないようにatan2で求める
+        let theta = d.x().hypot(d.z()).atan2(d.y());
+        let phi = d.x().atan2(-d.z()) + PI - self.rotation;
+        let u = (phi / (2.0 * PI)).rem_euclid(1.0);
+        (u, theta / PI)
+    }
+
+    /// 画像上の位置(u, v)の放射輝度
+    fn lookup(&self, u: f32, v: f32) -> Vec3 {
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x] * self.intensity
+    }
+
+    /// directionの向きから届く放射輝度
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        let (u, v) = self.direction_to_uv(direction);
+        self.lookup(u, v)
+    }
+
+    /// 画像上の確率密度を立体角あたりに直す
+    fn solid_angle_pdf(&self, pdf: f32, v: f32) -> f32 {
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        pdf / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+impl Light for EnvironmentMap {
+    fn sample(&self, _point: Vec3) -> Option<LightSample> {
+        let ((u, v), pdf) = self
+            .distribution
+            .sample(random_f32(0.0, 1.0), random_f32(0.0, 1.0));
+        let pdf = self.solid_angle_pdf(pdf, v);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(LightSample::new(
+            uv_to_direction(u, v, self.rotation),
+            f32::INFINITY,
+            self.lookup(u, v),
+            pdf,
+        ))
+    }
+
+    fn pdf_value(&self, _point: Vec3, direction: Vec3) -> f32 {
+        let (u, v) = self.direction_to_uv(direction);
+        self.solid_angle_pdf(self.distribution.pdf(u, v), v)
+    }
+
+    fn hit(&self, ray: &Ray, tmax: f32) -> Option<Vec3> {
+        // 無限遠にあるので、何にも当たらずに抜けたレイだけが届く
+        if tmax < f32::INFINITY {
+            return None;
+        }
+        Some(self.radiance(ray.direction()))
+    }
+
+    fn sample_emission(&self, center: Vec3, radius: f32, distance: f32) -> Option<EmissionSample> {
+        emission_from_infinity(&self.sample(center)?, center, radius, distance)
+    }
+}
+
+/// 画像上の位置(u, v)を向きに直す。rotationはy軸まわりの回転(弧度法)
+fn uv_to_direction(u: f32, v: f32, rotation: f32) -> Vec3 {
+    let theta = v * PI;
+    let psi = u * 2.0 * PI + rotation - PI;
+    let sin_theta = theta.sin();
+    Vec3::new(sin_theta * psi.sin(), theta.cos(), -sin_theta * psi.cos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 横8個、縦4個のピクセルで、右上の1ピクセルだけが特に明るい画像
+    fn environment_map(rotation: f32) -> EnvironmentMap {
+        let pixels = (0..32)
+            .map(|i| {
+                if i == 7 {
+                    color(50.0, 40.0, 30.0)
+                } else {
+                    color(0.2, 0.3, 0.4) * (1 + i % 3) as f32
+                }
+            })
+            .collect();
+        EnvironmentMap::new(8, 4, pixels, 1.0, rotation)
+    }
+
+    fn assert_near(actual: Vec3, expected: Vec3) {
+        assert!(
+            (actual - expected).length() < 1e-5,
+            "expected: {:?}, actual: {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "環境マップの画像が空です")]
+    fn empty_image_is_rejected() {
+        EnvironmentMap::new(0, 4, Vec::new(), 1.0, 0.0);
+    }
+
+    #[test]
+    fn uv_maps_to_documented_directions() {
+        assert_near(uv_to_direction(0.5, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_near(uv_to_direction(0.5, 0.5, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_near(uv_to_direction(0.75, 0.5, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert_near(uv_to_direction(0.0, 0.5, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        assert_near(uv_to_direction(0.5, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+    }
+
+    #[test]
+    fn direction_round_trips_through_uv() {
+        for rotation in [0.0, 90.0, -135.0] {
+            let map = environment_map(rotation);
+            for j in 1..16 {
+                for i in 0..32 {
+                    let u = (i as f32 + 0.25) / 32.0;
+                    let v = j as f32 / 16.0;
+                    let (u2, v2) = map.direction_to_uv(uv_to_direction(u, v, map.rotation));
+                    assert!(
+                        (u2 - u).abs() < 1e-4 && (v2 - v).abs() < 1e-4,
+                        "rotation: {}, ({}, {}) -> ({}, {})",
+                        rotation,
+                        u,
+                        v,
+                        u2,
+                        v2
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rotation_turns_the_image_around_y() {
+        let map = environment_map(90.0);
+        let unrotated = environment_map(0.0);
+        // 90度回すと、回す前に-zの近くから届いていた放射輝度が+xの近くから届く
+        for a in [-0.3_f32, 0.2, 0.7] {
+            assert_near(
+                map.radiance(Vec3::new(a.cos(), 0.2, a.sin())),
+                unrotated.radiance(Vec3::new(a.sin(), 0.2, -a.cos())),
+            );
+        }
+    }
+
+    #[test]
+    fn solid_angle_pdf_integrates_to_one() {
+        let map = environment_map(30.0);
+        let (n_theta, n_phi) = (200, 400);
+        let mut integral = 0.0;
+        for j in 0..n_theta {
+            let theta = PI * (j as f32 + 0.5) / n_theta as f32;
+            let d_omega = theta.sin() * (PI / n_theta as f32) * (2.0 * PI / n_phi as f32);
+            for i in 0..n_phi {
+                let phi = 2.0 * PI * (i as f32 + 0.5) / n_phi as f32;
+                let direction = Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                integral += map.pdf_value(Vec3::new(0.0, 0.0, 0.0), direction) * d_omega;
+            }
+        }
+        assert!((integral - 1.0).abs() < 1e-2, "integral: {}", integral);
+    }
+
+    #[test]
+    fn sampled_direction_matches_pdf_and_radiance() {
+        let map = environment_map(-45.0);
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        for _ in 0..1000 {
+            let sample = map.sample(origin).unwrap();
+            let pdf = map.pdf_value(origin, sample.direction());
+            assert!(
+                (sample.pdf() - pdf).abs() <= pdf * 1e-3,
+                "sampled: {}, evaluated: {}",
+                sample.pdf(),
+                pdf
+            );
+            assert_near(sample.radiance(), map.radiance(sample.direction()));
+        }
+    }
+}
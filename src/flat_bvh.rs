@@ -0,0 +1,492 @@
+use std::cell::Cell;
+use std::fmt;
+
+use rayon::join;
+use rayon::prelude::*;
+
+use crate::aabb::Aabb;
+use crate::hitrecord::HitRecord;
+use crate::hittable::Hittable;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+/// SAHで分割を評価するときのビンの数
+const BIN_COUNT: usize = 12;
+/// 葉に入れる物体の最大数
+const MAX_PRIMITIVES_IN_LEAF: usize = 4;
+/// これより多くの物体を含む部分木は別スレッドで構築する
+const PARALLEL_THRESHOLD: usize = 1024;
+/// ノードをたどるコスト(物体との交差判定のコストを1とした相対値)
+const TRAVERSAL_COST: f32 = 0.125;
+/// 物体との交差判定のコスト
+const INTERSECTION_COST: f32 = 1.0;
+/// 木の深さの上限。たどるときのスタックを固定長の配列にできるように、これより深くは分割しない
+const MAX_DEPTH: usize = 64;
+
+/// レイの追跡で調べたノードと物体の数
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TraversalCount {
+    pub nodes: u32,
+    pub primitives: u32,
+}
+
+thread_local! {
+    /// スレッドごとに積算したTraversalCount
+    static TRAVERSAL_COUNT: Cell<TraversalCount> = const {
+        Cell::new(TraversalCount {
+            nodes: 0,
+            primitives: 0,
+        })
+    };
+}
+
+/// このスレッドで前回呼び出してからFlatBvhが調べたノードと物体の数を返し、カウンタを0に戻す
+pub fn take_traversal_count() -> TraversalCount {
+    TRAVERSAL_COUNT.with(|count| count.take())
+}
+
+/// 表面積ヒューリスティック(SAH)で構築し、配列に平坦化したBVH
+pub struct FlatBvh<T: Hittable> {
+    primitives: Vec<T>,
+    nodes: Vec<LinearNode>,
+    stats: BvhStats,
+}
+
+/// BVHの構築結果の統計
+#[derive(Clone, Copy, Debug)]
+pub struct BvhStats {
+    pub primitive_count: usize,
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub max_depth: usize,
+    /// ルートの表面積で正規化したSAHコスト
+    pub sah_cost: f32,
+    /// BVHを使わずにすべての物体を順に調べた場合のコスト
+    pub linear_cost: f32,
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "primitives: {}, nodes: {}, leaves: {}, depth: {}, SAH cost: {:.2} (linear: {:.2})",
+            self.primitive_count,
+            self.node_count,
+            self.leaf_count,
+            self.max_depth,
+            self.sah_cost,
+            self.linear_cost
+        )
+    }
+}
+
+/// 平坦化したノード。内部ノードの場合は左の子がすぐ後ろに、右の子がoffsetの位置に置かれる
+#[derive(Clone, Copy, Debug)]
+struct LinearNode {
+    bbox: Aabb,
+    /// 葉の場合は最初の物体の位置、内部ノードの場合は右の子の位置
+    offset: u32,
+    /// 葉に含まれる物体の数。内部ノードの場合は0
+    count: u32,
+    /// 内部ノードを分割した軸
+    axis: u8,
+}
+
+#[derive(Clone, Copy)]
+struct PrimitiveInfo {
+    index: usize,
+    bbox: Aabb,
+    centroid: Vec3,
+}
+
+enum BuildNode {
+    Leaf {
+        bbox: Aabb,
+        first: usize,
+        count: usize,
+    },
+    Interior {
+        bbox: Aabb,
+        axis: usize,
+        children: Box<(BuildNode, BuildNode)>,
+    },
+}
+
+impl BuildNode {
+    fn bbox(&self) -> Aabb {
+        match self {
+            BuildNode::Leaf { bbox, .. } => *bbox,
+            BuildNode::Interior { bbox, .. } => *bbox,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Bin {
+    bbox: Option<Aabb>,
+    count: usize,
+}
+
+impl<T: Hittable> FlatBvh<T> {
+    /// 物体のリストからBVHを構築する。time0〜time1はシャッターが開いている時間で、動く物体の境界ボックスを求めるのに使う
+    pub fn new(primitives: Vec<T>, time0: f32, time1: f32) -> FlatBvh<T> {
+        let mut infos: Vec<PrimitiveInfo> = primitives
+            .par_iter()
+            .enumerate()
+            .map(|(index, primitive)| {
+                let bbox = primitive
+                    .bounding_box(time0, time1)
+                    .expect("境界ボックスを持たない物体はBVHに入れられません");
+                PrimitiveInfo {
+                    index,
+                    bbox,
+                    centroid: (bbox.min() + bbox.max()) * 0.5,
+                }
+            })
+            .collect();
+
+        let mut nodes = Vec::new();
+        let mut stats = BvhStats {
+            primitive_count: primitives.len(),
+            node_count: 0,
+            leaf_count: 0,
+            max_depth: 0,
+            sah_cost: 0.0,
+            linear_cost: primitives.len() as f32 * INTERSECTION_COST,
+        };
+
+        if !infos.is_empty() {
+            let root = build(&mut infos, 0, 0);
+            let root_area = surface_area(&root.bbox());
+            flatten(&root, &mut nodes, &mut stats, 0, root_area);
+        }
+
+        // 葉から連続した範囲で参照できるように物体を並べ替える
+        let mut slots: Vec<Option<T>> = primitives.into_iter().map(Some).collect();
+        let primitives = infos
+            .iter()
+            .map(|info| slots[info.index].take().unwrap())
+            .collect();
+
+        FlatBvh {
+            primitives,
+            nodes,
+            stats,
+        }
+    }
+
+    pub fn stats(&self) -> BvhStats {
+        self.stats
+    }
+
+    pub fn primitives(&self) -> &[T] {
+        &self.primitives
+    }
+}
+
+impl<T: Hittable> Hittable for FlatBvh<T> {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord<'_>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let direction_is_negative = [
+            ray.direction().x() < 0.0,
+            ray.direction().y() < 0.0,
+            ray.direction().z() < 0.0,
+        ];
+
+        let mut closest_record: Option<HitRecord<'_>> = None;
+        let mut closest_so_far = tmax;
+        // 後で調べるノードのスタック。積まれるノードの数は木の深さを超えない
+        let mut stack = [0_u32; MAX_DEPTH];
+        let mut stack_size = 0;
+        let mut current = 0;
+        let mut visited_nodes = 0;
+        let mut tested_primitives = 0;
+
+        loop {
+            let node = &self.nodes[current];
+            visited_nodes += 1;
+            if node.bbox.hit(ray, tmin, closest_so_far) {
+                if node.count > 0 {
+                    // 葉の場合は含まれている物体を調べる
+                    tested_primitives += node.count;
+                    let first = node.offset as usize;
+                    for primitive in &self.primitives[first..first + node.count as usize] {
+                        if let Some(hit_record) = primitive.hit(ray, tmin, closest_so_far, time) {
+                            closest_so_far = hit_record.t();
+                            closest_record = Some(hit_record);
+                        }
+                    }
+                } else if direction_is_negative[node.axis as usize] {
+                    // レイの向きから見て手前にある子を先に調べる
+                    stack[stack_size] = current as u32 + 1;
+                    stack_size += 1;
+                    current = node.offset as usize;
+                    continue;
+                } else {
+                    stack[stack_size] = node.offset;
+                    stack_size += 1;
+                    current += 1;
+                    continue;
+                }
+            }
+
+            if stack_size == 0 {
+                break;
+            }
+            stack_size -= 1;
+            current = stack[stack_size] as usize;
+        }
+
+        TRAVERSAL_COUNT.with(|count| {
+            let mut total = count.get();
+            total.nodes += visited_nodes;
+            total.primitives += tested_primitives;
+            count.set(total);
+        });
+
+        closest_record
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<Aabb> {
+        self.nodes.first().map(|node| node.bbox)
+    }
+}
+
+fn surface_area(bbox: &Aabb) -> f32 {
+    let d = bbox.max() - bbox.min();
+    2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+}
+
+fn union(a: Option<Aabb>, b: Aabb) -> Aabb {
+    match a {
+        None => b,
+        Some(a) => Aabb::surrounding_box(a, b),
+    }
+}
+
+/// infosを分割しながら再帰的に木を構築する。offsetはinfosの先頭が全体の何番目にあたるか、depthはノードの深さ
+fn build(infos: &mut [PrimitiveInfo], offset: usize, depth: usize) -> BuildNode {
+    let bbox = infos.iter().skip(1).fold(infos[0].bbox, |acc, info| {
+        Aabb::surrounding_box(acc, info.bbox)
+    });
+    let leaf = BuildNode::Leaf {
+        bbox,
+        first: offset,
+        count: infos.len(),
+    };
+
+    if infos.len() == 1 || depth + 1 >= MAX_DEPTH {
+        return leaf;
+    }
+
+    // 重心の広がりが最も大きい軸で分割する
+    let centroid_bounds = infos.iter().skip(1).fold(
+        Aabb::new(infos[0].centroid, infos[0].centroid),
+        |acc, info| Aabb::surrounding_box(acc, Aabb::new(info.centroid, info.centroid)),
+    );
+    let extent = centroid_bounds.max() - centroid_bounds.min();
+    let axis = if extent.x() > extent.y() && extent.x() > extent.z() {
+        0
+    } else if extent.y() > extent.z() {
+        1
+    } else {
+        2
+    };
+
+    if extent[axis] <= 0.0 {
+        // 重心がすべて同じ位置にあるので分割できない
+        return leaf;
+    }
+
+    // 重心の位置でビンに振り分ける
+    let axis_min = centroid_bounds.min()[axis];
+    let bin_index = |info: &PrimitiveInfo| -> usize {
+        let b = (BIN_COUNT as f32 * (info.centroid[axis] - axis_min) / extent[axis]) as usize;
+        b.min(BIN_COUNT - 1)
+    };
+    let mut bins = [Bin {
+        bbox: None,
+        count: 0,
+    }; BIN_COUNT];
+    for info in infos.iter() {
+        let bin = &mut bins[bin_index(info)];
+        bin.bbox = Some(union(bin.bbox, info.bbox));
+        bin.count += 1;
+    }
+
+    // ビンの境界ごとにSAHコストを求める。左右から累積していくことで線形時間で計算する
+    let mut right_areas = [0.0_f32; BIN_COUNT];
+    let mut right_counts = [0_usize; BIN_COUNT];
+    let mut accumulated: Option<Aabb> = None;
+    let mut count = 0;
+    for i in (1..BIN_COUNT).rev() {
+        if let Some(b) = bins[i].bbox {
+            accumulated = Some(union(accumulated, b));
+        }
+        count += bins[i].count;
+        right_areas[i] = accumulated.as_ref().map_or(0.0, surface_area);
+        right_counts[i] = count;
+    }
+
+    let mut best_cost = f32::INFINITY;
+    let mut best_split = 0;
+    let mut accumulated: Option<Aabb> = None;
+    let mut count = 0;
+    for i in 0..BIN_COUNT - 1 {
+        if let Some(b) = bins[i].bbox {
+            accumulated = Some(union(accumulated, b));
+        }
+        count += bins[i].count;
+        let left_area = accumulated.as_ref().map_or(0.0, surface_area);
+        let cost = left_area * count as f32 + right_areas[i + 1] * right_counts[i + 1] as f32;
+        if cost < best_cost {
+            best_cost = cost;
+            best_split = i;
+        }
+    }
+
+    let split_cost = TRAVERSAL_COST + INTERSECTION_COST * best_cost / surface_area(&bbox);
+    let leaf_cost = INTERSECTION_COST * infos.len() as f32;
+    if infos.len() <= MAX_PRIMITIVES_IN_LEAF && leaf_cost <= split_cost {
+        return leaf;
+    }
+
+    // 選んだ境界の左右に物体を分ける
+    let mut mid = 0;
+    for i in 0..infos.len() {
+        if bin_index(&infos[i]) <= best_split {
+            infos.swap(i, mid);
+            mid += 1;
+        }
+    }
+
+    let (left_infos, right_infos) = infos.split_at_mut(mid);
+    let (left, right) = if left_infos.len() + right_infos.len() > PARALLEL_THRESHOLD {
+        join(
+            || build(left_infos, offset, depth + 1),
+            || build(right_infos, offset + mid, depth + 1),
+        )
+    } else {
+        (
+            build(left_infos, offset, depth + 1),
+            build(right_infos, offset + mid, depth + 1),
+        )
+    };
+
+    BuildNode::Interior {
+        bbox,
+        axis,
+        children: Box::new((left, right)),
+    }
+}
+
+/// 木を深さ優先の順に配列へ並べる。並べたノードの位置を返す
+fn flatten(
+    node: &BuildNode,
+    nodes: &mut Vec<LinearNode>,
+    stats: &mut BvhStats,
+    depth: usize,
+    root_area: f32,
+) -> usize {
+    let position = nodes.len();
+    stats.node_count += 1;
+    stats.max_depth = stats.max_depth.max(depth);
+    let area_ratio = if root_area > 0.0 {
+        surface_area(&node.bbox()) / root_area
+    } else {
+        1.0
+    };
+
+    match node {
+        BuildNode::Leaf { bbox, first, count } => {
+            stats.leaf_count += 1;
+            stats.sah_cost += area_ratio * INTERSECTION_COST * *count as f32;
+            nodes.push(LinearNode {
+                bbox: *bbox,
+                offset: *first as u32,
+                count: *count as u32,
+                axis: 0,
+            });
+        }
+        BuildNode::Interior {
+            bbox,
+            axis,
+            children,
+        } => {
+            stats.sah_cost += area_ratio * TRAVERSAL_COST;
+            nodes.push(LinearNode {
+                bbox: *bbox,
+                offset: 0,
+                count: 0,
+                axis: *axis as u8,
+            });
+            flatten(&children.0, nodes, stats, depth + 1, root_area);
+            let right = flatten(&children.1, nodes, stats, depth + 1, root_area);
+            nodes[position].offset = right as u32;
+        }
+    }
+
+    position
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::item::Sphere;
+    use crate::material::{Lambertian, Material};
+    use crate::vec3::color;
+
+    fn random_vec3(rng: &mut StdRng, min: f32, max: f32) -> Vec3 {
+        Vec3::new(
+            rng.gen_range(min..max),
+            rng.gen_range(min..max),
+            rng.gen_range(min..max),
+        )
+    }
+
+    #[test]
+    fn nearest_hit_matches_linear_scan() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(color(0.5, 0.5, 0.5)));
+        let spheres: Vec<Sphere> = (0..500)
+            .map(|_| {
+                let center = random_vec3(&mut rng, -10.0, 10.0);
+                let radius = rng.gen_range(0.05..0.8);
+                Sphere::new(center, center, radius, material.clone(), 0.0, 1.0)
+            })
+            .collect();
+        let linear = spheres.clone();
+        let bvh = FlatBvh::new(spheres, 0.0, 1.0);
+
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let ray = Ray::new(
+                random_vec3(&mut rng, -12.0, 12.0),
+                random_vec3(&mut rng, -1.0, 1.0),
+                0.0,
+            );
+            let expected = linear
+                .iter()
+                .filter_map(|sphere| sphere.hit(&ray, 0.001, f32::INFINITY, 0.0))
+                .map(|hit_record| hit_record.t())
+                .fold(None, |closest: Option<f32>, t| {
+                    Some(closest.map_or(t, |c| c.min(t)))
+                });
+            let actual = bvh
+                .hit(&ray, 0.001, f32::INFINITY, 0.0)
+                .map(|hit_record| hit_record.t());
+            assert_eq!(expected, actual);
+            hits += usize::from(actual.is_some());
+        }
+        // 当たらないレイばかりでは確かめたことにならない
+        assert!(hits > 200);
+    }
+}
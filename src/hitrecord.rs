@@ -1,50 +1,35 @@
-use std::f32::INFINITY;
-
 use crate::material::Material;
 use crate::ray::Ray;
 use crate::vec3::Vec3;
 
-#[derive(Debug)]
-pub struct HitRecord {
+pub struct HitRecord<'a> {
     point: Vec3,
     normal: Vec3,
     front_face: bool,
     t: f32,
-    material: Material,
-    attenuation: Vec3,
-    metal_fuzz: f32,
+    u: f32,
+    v: f32,
+    material: &'a dyn Material,
 }
 
-impl HitRecord {
+impl<'a> HitRecord<'a> {
     pub fn new(
         point: Vec3,
         normal: Vec3,
         front_face: bool,
         t: f32,
-        material: Material,
-        attenuation: Vec3,
-        metal_fuzz: f32,
-    ) -> HitRecord {
+        u: f32,
+        v: f32,
+        material: &'a dyn Material,
+    ) -> HitRecord<'a> {
         HitRecord {
             point,
             normal,
             front_face,
             t,
+            u,
+            v,
             material,
-            attenuation,
-            metal_fuzz,
-        }
-    }
-
-    pub fn not_hit() -> HitRecord {
-        HitRecord {
-            point: Vec3::new(0.0, 0.0, 0.0),
-            normal: Vec3::new(0.0, 0.0, 0.0),
-            front_face: false,
-            t: INFINITY,
-            material: Material::None,
-            attenuation: Vec3::new(0.0, 0.0, 0.0),
-            metal_fuzz: 0.0,
         }
     }
 
@@ -56,7 +41,7 @@ impl HitRecord {
         self.normal
     }
 
-    pub fn material(&self) -> Material {
+    pub fn material(&self) -> &'a dyn Material {
         self.material
     }
 
@@ -68,12 +53,22 @@ impl HitRecord {
         self.t
     }
 
-    pub fn attenuation(&self) -> Vec3 {
-        self.attenuation
+    /// テクスチャ座標
+    pub fn u(&self) -> f32 {
+        self.u
+    }
+
+    pub fn v(&self) -> f32 {
+        self.v
+    }
+
+    pub fn set_point(&mut self, point: Vec3) {
+        self.point = point;
     }
 
-    pub fn metal_fuzz(&self) -> f32 {
-        self.metal_fuzz
+    /// 法線を置き換える。表裏の判定は変えない
+    pub fn set_normal(&mut self, normal: Vec3) {
+        self.normal = normal;
     }
 
     pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: Vec3) {
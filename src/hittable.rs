@@ -1,6 +1,159 @@
+use std::sync::Arc;
+
+use crate::aabb::Aabb;
 use crate::hitrecord::HitRecord;
 use crate::ray::Ray;
+use crate::vec3::{random_f32, Vec3};
+
+pub trait Hittable: Send + Sync {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord<'_>>;
+
+    /// time0〜time1の間に物体が占める範囲を囲むボックス。無限に広がる物体の場合はNone
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb>;
+
+    /// originからdirectionの向きを、randomでこの物体を狙って選ぶ確率密度(立体角あたり)
+    ///
+    /// 光源として直接サンプリングできない物体は0
+    fn pdf_value(&self, _origin: Vec3, _direction: Vec3, _time: f32) -> f32 {
+        0.0
+    }
+
+    /// originからこの物体の上のランダムな点へ向かうベクトル
+    fn random(&self, _origin: Vec3, _time: f32) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+
+    /// 表面上の点を面積あたり一様な確率で選び、その点のHitRecordを返す。法線は外向き
+    ///
+    /// 光源から経路を伸ばすときに使う。光源として使えない物体はNone
+    fn sample_surface(&self, _time: f32) -> Option<HitRecord<'_>> {
+        None
+    }
+
+    /// 表面積。sample_surfaceで点を選ぶ確率密度は1/area
+    fn area(&self, _time: f32) -> f32 {
+        0.0
+    }
+}
+
+impl<T: Hittable + ?Sized> Hittable for Box<T> {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord<'_>> {
+        self.as_ref().hit(ray, tmin, tmax, time)
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        self.as_ref().bounding_box(time0, time1)
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        self.as_ref().pdf_value(origin, direction, time)
+    }
+
+    fn random(&self, origin: Vec3, time: f32) -> Vec3 {
+        self.as_ref().random(origin, time)
+    }
+
+    fn sample_surface(&self, time: f32) -> Option<HitRecord<'_>> {
+        self.as_ref().sample_surface(time)
+    }
+
+    fn area(&self, time: f32) -> f32 {
+        self.as_ref().area(time)
+    }
+}
+
+impl<T: Hittable + ?Sized> Hittable for Arc<T> {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord<'_>> {
+        self.as_ref().hit(ray, tmin, tmax, time)
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        self.as_ref().bounding_box(time0, time1)
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        self.as_ref().pdf_value(origin, direction, time)
+    }
+
+    fn random(&self, origin: Vec3, time: f32) -> Vec3 {
+        self.as_ref().random(origin, time)
+    }
+
+    fn sample_surface(&self, time: f32) -> Option<HitRecord<'_>> {
+        self.as_ref().sample_surface(time)
+    }
+
+    fn area(&self, time: f32) -> f32 {
+        self.as_ref().area(time)
+    }
+}
+
+/// 物体のリスト。すべての物体を順に調べて最も手前で当たったものを返す
+impl Hittable for Vec<Box<dyn Hittable>> {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord<'_>> {
+        let mut closest_record: Option<HitRecord<'_>> = None;
+        let mut closest_so_far = tmax;
+        for item in self {
+            if let Some(hit_record) = item.hit(ray, tmin, closest_so_far, time) {
+                closest_so_far = hit_record.t();
+                closest_record = Some(hit_record);
+            }
+        }
+        closest_record
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        let mut result: Option<Aabb> = None;
+        for item in self {
+            let item_box = item.bounding_box(time0, time1)?;
+            result = Some(match result {
+                None => item_box,
+                Some(b) => Aabb::surrounding_box(b, item_box),
+            });
+        }
+        result
+    }
+
+    /// 各物体を等しい確率で選ぶ場合の確率密度
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let sum: f32 = self
+            .iter()
+            .map(|item| item.pdf_value(origin, direction, time))
+            .sum();
+        sum / self.len() as f32
+    }
+
+    fn random(&self, origin: Vec3, time: f32) -> Vec3 {
+        if self.is_empty() {
+            return Vec3::new(1.0, 0.0, 0.0);
+        }
+        let index = ((random_f32(0.0, 1.0) * self.len() as f32) as usize).min(self.len() - 1);
+        self[index].random(origin, time)
+    }
+
+    /// 表面積に比例した確率で物体を選ぶので、全体の表面上で一様になる
+    fn sample_surface(&self, time: f32) -> Option<HitRecord<'_>> {
+        let mut target = random_f32(0.0, 1.0) * self.area(time);
+        let mut last = None;
+        for item in self {
+            let area = item.area(time);
+            if area <= 0.0 {
+                continue;
+            }
+            if target < area {
+                return item.sample_surface(time);
+            }
+            target -= area;
+            last = Some(item);
+        }
+        // 丸め誤差で最後まで選ばれなかった場合
+        last.and_then(|item| item.sample_surface(time))
+    }
 
-pub trait Hittable: Sync {
-    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord>;
+    fn area(&self, time: f32) -> f32 {
+        self.iter().map(|item| item.area(time)).sum()
+    }
 }
@@ -0,0 +1,475 @@
+use crate::flat_bvh::take_traversal_count;
+use crate::hitrecord::HitRecord;
+use crate::hittable::Hittable;
+use crate::light::Light;
+use crate::material::ScatterRecord;
+use crate::pdf::{CosinePdf, HittablePdf, Pdf};
+use crate::photon::CausticPhotonMaps;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::vec3::{color, random_f32, Vec3};
+
+/// 影のレイで光源の手前を調べるときや、2つの交点を同じ点とみなすときの距離の許容誤差
+pub(crate) const SHADOW_EPSILON: f32 = 0.001;
+
+/// カメラからのレイが運んでくる色を求める方法
+pub trait Integrator: Send + Sync {
+    /// rayの向きからカメラに届く色
+    fn li(&self, ray: Ray, scene: &Scene) -> Vec3;
+
+    /// 画像全体をまとめて描き、ピクセルごとの色を左上から行の順に返す。ピクセルごとにliで描く場合はNone
+    ///
+    /// camera_rayは画像上の位置(左上が(0, 0)、右下が(width, height))を通るレイを返す
+    fn render(
+        &self,
+        _scene: &Scene,
+        _width: u32,
+        _height: u32,
+        _samples_per_pixel: u32,
+        _camera_ray: &(dyn Fn(f32, f32) -> Ray + Sync),
+    ) -> Option<Vec<Vec3>> {
+        None
+    }
+}
+
+/// パストレーシング
+///
+/// 経路の寄与をthroughputとして持ち回り、反射のたびに掛け合わせていく。
+/// 反射回数がrr_depth以上になったらロシアンルーレットで確率的に追跡を打ち切り、
+/// 生き残った経路の寄与を生存確率で割ることで期待値を変えないようにする
+///
+/// フォトンマップを持つ場合、拡散面から鏡面反射や屈折を経て光源に届く経路(コースティクス)は
+/// レイを追跡する代わりにフォトンの密度から求める
+pub struct PathTracer {
+    max_depth: u32,
+    rr_depth: u32,
+    caustics: Option<CausticPhotonMaps>,
+}
+
+impl PathTracer {
+    pub fn new(max_depth: u32, rr_depth: u32) -> PathTracer {
+        PathTracer {
+            max_depth,
+            rr_depth,
+            caustics: None,
+        }
+    }
+
+    /// コースティクスをフォトンマップで求める
+    pub fn with_caustics(mut self, caustics: CausticPhotonMaps) -> PathTracer {
+        self.caustics = Some(caustics);
+        self
+    }
+}
+
+impl Integrator for PathTracer {
+    fn li(&self, ray: Ray, scene: &Scene) -> Vec3 {
+        let mut radiance = color(0.0, 0.0, 0.0);
+        let mut throughput = color(1.0, 1.0, 1.0);
+        let mut ray = ray;
+        // 直前の反射でこのレイの向きを選んだ確率密度。カメラからのレイや鏡面反射の場合はNone
+        let mut bsdf_pdf: Option<f32> = None;
+        // 最後の鏡面反射でない反射が拡散面だった。その後鏡面反射を経て光源に当たればコースティクスになる
+        let mut after_diffuse = false;
+        // カメラから鏡面反射や屈折だけを経てきた
+        let mut specular_chain = true;
+
+        // 反射回数が一定よりも多くなったら、その時点で追跡をやめる
+        for depth in 0..self.max_depth {
+            // カメラに最も近い物体のHitRecordを探す
+            let closest_record = scene.world().hit(&ray, 0.001, f32::INFINITY, ray.time());
+
+            // 拡散面から鏡面反射や屈折を経て光源に届いた経路は、フォトンマップで求めた分と重複するので数えない
+            let caustic = after_diffuse && bsdf_pdf.is_none() && self.caustics.is_some();
+
+            // 物体より手前にある光源からの光
+            let tmax = closest_record
+                .as_ref()
+                .map_or(f32::INFINITY, |record| record.t());
+            if !caustic {
+                radiance =
+                    radiance + throughput * light_source_emission(&ray, tmax, bsdf_pdf, scene);
+            }
+
+            let closest_record = match closest_record {
+                // 何にも衝突しなかったので環境の光
+                None => {
+                    let environment = scene.environment();
+                    let le = environment.radiance(ray.direction());
+                    if depth == 0 {
+                        // カメラから直接見える背景
+                        if environment.camera_visible() {
+                            radiance = radiance + throughput * le;
+                        }
+                    } else if let (Some(light), false) = (scene.environment_light(), caustic) {
+                        radiance = radiance
+                            + throughput * le * light_hit_weight(light, &ray, bsdf_pdf, scene);
+                    } else if specular_chain && environment.camera_visible() {
+                        // 照らす光として使わない場合も、鏡やガラスに映る背景は見える
+                        radiance = radiance + throughput * le;
+                    }
+                    break;
+                }
+                Some(closest_record) => closest_record,
+            };
+            let material = closest_record.material();
+
+            // 光源に当たった場合、直前の反射で光源を直接サンプリングした分と重複しないようにMISで重み付けする
+            let mut emitted = material.emitted(&ray, &closest_record);
+            if caustic && is_light_hit(&ray, &closest_record, scene) {
+                // フォトンは直接サンプリングする光る物体からしか放たないので、それ以外の光る物体は数える
+                emitted = color(0.0, 0.0, 0.0);
+            } else if let (Some(bsdf_pdf), true) =
+                (bsdf_pdf, is_light_hit(&ray, &closest_record, scene))
+            {
+                // 直接サンプリングする光る物体に当たった場合だけ重み付けする。それ以外の光る物体の光はここでしか数えない
+                let light_pdf = scene
+                    .lights()
+                    .pdf_value(ray.origin(), ray.direction(), ray.time())
+                    / light_count(scene) as f32;
+                emitted = emitted * power_heuristic(bsdf_pdf, light_pdf);
+            }
+            radiance = radiance + throughput * emitted;
+
+            // 物体に当たった場合はマテリアルに従って散乱させる
+            match material.scatter(&ray, &closest_record) {
+                None => break,
+                Some(ScatterRecord::Specular {
+                    attenuation,
+                    ray: scattered,
+                }) => {
+                    // 鏡面反射や屈折では光源を直接サンプリングしない
+                    throughput = throughput * attenuation;
+                    ray = scattered;
+                    bsdf_pdf = None;
+                }
+                Some(ScatterRecord::Diffuse { pdf }) => {
+                    specular_chain = false;
+                    if let Some(caustics) = &self.caustics {
+                        if !material.is_volume() {
+                            radiance =
+                                radiance + throughput * caustics.estimate(&ray, &closest_record);
+                        }
+                    }
+                    after_diffuse = !material.is_volume();
+
+                    // 光源のサンプリングとマテリアルのサンプリングをMISで組み合わせる
+                    let direct = sample_light(&ray, &closest_record, pdf.as_ref(), scene);
+                    radiance = radiance + throughput * direct;
+
+                    let scattered = Ray::new(closest_record.point(), pdf.sample(), ray.time());
+                    let scattering_pdf = pdf.value(scattered.direction());
+                    if scattering_pdf <= 0.0 {
+                        break;
+                    }
+                    throughput = throughput * material.eval(&ray, &closest_record, &scattered)
+                        / scattering_pdf;
+                    ray = scattered;
+                    bsdf_pdf = Some(scattering_pdf);
+                }
+            }
+
+            // ロシアンルーレット。寄与が小さい経路ほど打ち切られやすい
+            if depth + 1 >= self.rr_depth {
+                let survival = throughput
+                    .x()
+                    .max(throughput.y())
+                    .max(throughput.z())
+                    .min(0.95);
+                if random_f32(0.0, 1.0) >= survival {
+                    break;
+                }
+                throughput = throughput / survival;
+            }
+        }
+
+        radiance
+    }
+}
+
+/// 直接サンプリングする光源の数。光る物体はまとめて1つと数える
+fn light_count(scene: &Scene) -> usize {
+    scene.light_sources().len()
+        + usize::from(scene.environment_light().is_some())
+        + usize::from(!scene.lights().is_empty())
+}
+
+/// 光源を1つ選んで影のレイを飛ばし、直接光を求める(Next Event Estimation)
+///
+/// bsdf_pdfはマテリアルが散乱の向きを選ぶ分布で、MISの重みを求めるのに使う
+fn sample_light(ray: &Ray, hit_record: &HitRecord, bsdf_pdf: &dyn Pdf, scene: &Scene) -> Vec3 {
+    let count = light_count(scene);
+    if count == 0 {
+        return color(0.0, 0.0, 0.0);
+    }
+    // 物体とは別の光源、環境、光る物体の順に番号を付けて選ぶ
+    let index = ((random_f32(0.0, 1.0) * count as f32) as usize).min(count - 1);
+    let light_sources = scene.light_sources();
+    let light = match light_sources.get(index) {
+        Some(light) => Some(light.as_ref()),
+        None if index == light_sources.len() => scene.environment_light(),
+        None => None,
+    };
+    match light {
+        Some(light) => sample_light_source(light, count, ray, hit_record, bsdf_pdf, scene),
+        None => sample_emissive_objects(count, ray, hit_record, bsdf_pdf, scene),
+    }
+}
+
+/// 光る物体の上の点を選んで直接光を求める。countは光源の数で、1 / countの確率で選ばれたとする
+fn sample_emissive_objects(
+    count: usize,
+    ray: &Ray,
+    hit_record: &HitRecord,
+    bsdf_pdf: &dyn Pdf,
+    scene: &Scene,
+) -> Vec3 {
+    let lights = scene.lights();
+    let light_pdf = HittablePdf::new(lights, hit_record.point(), ray.time());
+    let direction = light_pdf.sample();
+    let light_pdf_value = light_pdf.value(direction) / count as f32;
+    if light_pdf_value <= 0.0 {
+        return color(0.0, 0.0, 0.0);
+    }
+
+    let shadow_ray = Ray::new(hit_record.point(), direction, ray.time());
+    let material = hit_record.material();
+    let bsdf = material.eval(ray, hit_record, &shadow_ray);
+
+    // 最初に当たる物体が選んだ光る物体の同じ点でなければ影になる。手前にあるのが直接サンプリングしない光る物体でも、
+    // その光はマテリアルのサンプリングで数えるので、ここでは遮るものとして扱う
+    let light_record = match scene
+        .lights()
+        .hit(&shadow_ray, 0.001, f32::INFINITY, ray.time())
+    {
+        Some(light_record) => light_record,
+        None => return color(0.0, 0.0, 0.0),
+    };
+    let blocked = scene
+        .world()
+        .hit(&shadow_ray, 0.001, f32::INFINITY, ray.time())
+        .is_none_or(|world_record| !same_point(&world_record, &light_record));
+    if blocked {
+        return color(0.0, 0.0, 0.0);
+    }
+
+    let weight = power_heuristic(light_pdf_value, bsdf_pdf.value(direction));
+    light_record.material().emitted(&shadow_ray, &light_record) * bsdf * (weight / light_pdf_value)
+}
+
+/// rayが最初に当たったhit_recordの物体が、直接サンプリングする光る物体かどうか
+fn is_light_hit(ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> bool {
+    scene
+        .lights()
+        .hit(ray, 0.001, f32::INFINITY, ray.time())
+        .is_some_and(|light_record| same_point(&light_record, hit_record))
+}
+
+/// 2つの交点が同じ点か。光源までの距離によらず、決まった距離の許容誤差で比べる
+fn same_point(a: &HitRecord, b: &HitRecord) -> bool {
+    (a.point() - b.point()).length() < SHADOW_EPSILON
+}
+
+/// 物体とは別の光源lightの上の点を選んで直接光を求める。countは光源の数で、1 / countの確率で選ばれたとする
+fn sample_light_source(
+    light: &dyn Light,
+    count: usize,
+    ray: &Ray,
+    hit_record: &HitRecord,
+    bsdf_pdf: &dyn Pdf,
+    scene: &Scene,
+) -> Vec3 {
+    let sample = match light.sample(hit_record.point()) {
+        Some(sample) => sample,
+        None => return color(0.0, 0.0, 0.0),
+    };
+    let light_pdf_value = sample.pdf() / count as f32;
+    if light_pdf_value <= 0.0 {
+        return color(0.0, 0.0, 0.0);
+    }
+
+    // 光源までの間に物体があれば影になる
+    let shadow_ray = Ray::new(hit_record.point(), sample.direction(), ray.time());
+    if scene
+        .world()
+        .hit(
+            &shadow_ray,
+            0.001,
+            sample.distance() - SHADOW_EPSILON,
+            ray.time(),
+        )
+        .is_some()
+    {
+        return color(0.0, 0.0, 0.0);
+    }
+
+    let bsdf = hit_record.material().eval(ray, hit_record, &shadow_ray);
+    // 大きさのない光源にはマテリアルのサンプリングで当たらないので、重みは1
+    let weight = if light.is_delta() {
+        1.0
+    } else {
+        power_heuristic(light_pdf_value, bsdf_pdf.value(sample.direction()))
+    };
+    sample.radiance() * bsdf * (weight / light_pdf_value)
+}
+
+/// rayがtmaxより手前で物体とは別の光源に当たった場合に届く光
+fn light_source_emission(ray: &Ray, tmax: f32, bsdf_pdf: Option<f32>, scene: &Scene) -> Vec3 {
+    let mut emission = color(0.0, 0.0, 0.0);
+    for light in scene.light_sources() {
+        if let Some(radiance) = light.hit(ray, tmax) {
+            emission = emission + radiance * light_hit_weight(light.as_ref(), ray, bsdf_pdf, scene);
+        }
+    }
+    emission
+}
+
+/// 直接サンプリングする光源lightにrayが当たった場合のMISの重み
+///
+/// bsdf_pdfは直前の反射でrayの向きを選んだ確率密度。カメラからのレイや鏡面反射の場合はNoneで、重みは1
+fn light_hit_weight(light: &dyn Light, ray: &Ray, bsdf_pdf: Option<f32>, scene: &Scene) -> f32 {
+    match bsdf_pdf {
+        Some(bsdf_pdf) => power_heuristic(
+            bsdf_pdf,
+            light.pdf_value(ray.origin(), ray.direction()) / light_count(scene) as f32,
+        ),
+        None => 1.0,
+    }
+}
+
+/// MISのべき乗ヒューリスティック(β = 2)。確率密度aの戦略の重み
+///
+/// 光源をかすめる向きでは確率密度が非常に大きくなり2乗があふれるので、比で計算する
+pub(crate) fn power_heuristic(a: f32, b: f32) -> f32 {
+    if a <= 0.0 {
+        return 0.0;
+    }
+    if a.is_infinite() {
+        return if b.is_infinite() { 0.5 } else { 1.0 };
+    }
+    let r = b / a;
+    1.0 / (1.0 + r * r)
+}
+
+/// 法線の向きを色で表示する。xyzの-1〜1をRGBの0〜1に対応させる
+pub struct NormalIntegrator;
+
+impl Integrator for NormalIntegrator {
+    fn li(&self, ray: Ray, scene: &Scene) -> Vec3 {
+        match scene.world().hit(&ray, 0.001, f32::INFINITY, ray.time()) {
+            Some(record) => (record.normal() + color(1.0, 1.0, 1.0)) * 0.5,
+            None => color(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/// カメラからの距離を明るさで表示する。近いほど明るく、max_distance以上は黒
+pub struct DepthIntegrator {
+    max_distance: f32,
+}
+
+impl DepthIntegrator {
+    pub fn new(max_distance: f32) -> DepthIntegrator {
+        DepthIntegrator { max_distance }
+    }
+}
+
+impl Integrator for DepthIntegrator {
+    fn li(&self, ray: Ray, scene: &Scene) -> Vec3 {
+        match scene.world().hit(&ray, 0.001, f32::INFINITY, ray.time()) {
+            Some(record) => {
+                let distance = record.t() * ray.direction().length();
+                let brightness = (1.0 - distance / self.max_distance).max(0.0);
+                color(brightness, brightness, brightness)
+            }
+            None => color(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/// 最初に当たった物体のマテリアルの色を表示する
+pub struct AlbedoIntegrator;
+
+impl Integrator for AlbedoIntegrator {
+    fn li(&self, ray: Ray, scene: &Scene) -> Vec3 {
+        match scene.world().hit(&ray, 0.001, f32::INFINITY, ray.time()) {
+            Some(record) => record.material().albedo(&record),
+            None => color(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/// テクスチャ座標(u, v)を赤と緑で表示する
+pub struct UvIntegrator;
+
+impl Integrator for UvIntegrator {
+    fn li(&self, ray: Ray, scene: &Scene) -> Vec3 {
+        match scene.world().hit(&ray, 0.001, f32::INFINITY, ray.time()) {
+            Some(record) => color(record.u(), record.v(), 0.0),
+            None => color(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/// アンビエントオクルージョン
+///
+/// 当たった点から余弦に比例した向きにレイを飛ばし、distance以内に物体がなければ白、あれば黒とする
+pub struct AmbientOcclusionIntegrator {
+    distance: f32,
+}
+
+impl AmbientOcclusionIntegrator {
+    pub fn new(distance: f32) -> AmbientOcclusionIntegrator {
+        AmbientOcclusionIntegrator { distance }
+    }
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn li(&self, ray: Ray, scene: &Scene) -> Vec3 {
+        let record = match scene.world().hit(&ray, 0.001, f32::INFINITY, ray.time()) {
+            Some(record) => record,
+            None => return color(0.0, 0.0, 0.0),
+        };
+
+        let direction = CosinePdf::new(record.normal()).sample();
+        let occlusion_ray = Ray::new(record.point(), direction, ray.time());
+        let tmax = self.distance / direction.length();
+        match scene.world().hit(&occlusion_ray, 0.001, tmax, ray.time()) {
+            Some(_) => color(0.0, 0.0, 0.0),
+            None => color(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+/// BVHをたどったノードと交差判定をした物体の数をヒートマップで表示する
+///
+/// 0は青、max_cost以上は赤になる。ノード1つは物体との交差判定1回と同じ重みで数える
+pub struct CostIntegrator {
+    max_cost: f32,
+}
+
+impl CostIntegrator {
+    pub fn new(max_cost: f32) -> CostIntegrator {
+        CostIntegrator { max_cost }
+    }
+}
+
+impl Integrator for CostIntegrator {
+    fn li(&self, ray: Ray, scene: &Scene) -> Vec3 {
+        take_traversal_count();
+        scene.world().hit(&ray, 0.001, f32::INFINITY, ray.time());
+        let count = take_traversal_count();
+        heatmap((count.nodes + count.primitives) as f32 / self.max_cost)
+    }
+}
+
+/// 0〜1の値を青→水色→緑→黄→赤の色に対応させる
+fn heatmap(x: f32) -> Vec3 {
+    let x = x.clamp(0.0, 1.0) * 4.0;
+    match x as u32 {
+        0 => color(0.0, x, 1.0),
+        1 => color(0.0, 1.0, 2.0 - x),
+        2 => color(x - 2.0, 1.0, 0.0),
+        _ => color(1.0, (4.0 - x).max(0.0), 0.0),
+    }
+}
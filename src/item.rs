@@ -1,17 +1,22 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use crate::aabb::Aabb;
+use crate::distribution::Distribution1D;
+use crate::flat_bvh::FlatBvh;
 use crate::hitrecord::HitRecord;
 use crate::hittable::Hittable;
 use crate::material::Material;
+use crate::pdf::{Pdf, SphereSolidAnglePdf};
 use crate::ray::Ray;
-use crate::vec3::Vec3;
+use crate::vec3::{random_f32, random_unit_vector, unit_vector, Vec3};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone)]
 pub struct Sphere {
     center0: Vec3,
     center1: Vec3,
     radius: f32,
-    material: Material,
-    attenuation: Vec3,
-    metal_fuzz: f32,
+    material: Arc<dyn Material>,
     time0: f32,
     time1: f32,
 }
@@ -21,9 +26,7 @@ impl Sphere {
         center0: Vec3,
         center1: Vec3,
         radius: f32,
-        material: Material,
-        attenuation: Vec3,
-        metal_fuzz: f32,
+        material: Arc<dyn Material>,
         time0: f32,
         time1: f32,
     ) -> Sphere {
@@ -32,8 +35,6 @@ impl Sphere {
             center1,
             radius,
             material,
-            attenuation,
-            metal_fuzz,
             time0,
             time1,
         }
@@ -46,7 +47,7 @@ impl Sphere {
 }
 
 impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord<'_>> {
         let oc = ray.origin() - self.center(time);
         let a = ray.direction().length_squared();
         let b = oc.dot(ray.direction()) * 2.0;
@@ -59,27 +60,866 @@ impl Hittable for Sphere {
             None
         } else {
             // 解がある場合はtが小さいほう(原点から近いほう)のHitRecordを返す
-            let t: f32 = (-b - discriminant.sqrt()) / (2.0 * a);
-
-            if tmin < t && t < tmax {
-                let point = ray.at(t);
-                let outward_normal = (point - self.center(time)) / self.radius;
-
-                let mut hit_record = HitRecord::new(
-                    point,
-                    outward_normal,
-                    true,
-                    t,
-                    self.material,
-                    self.attenuation,
-                    self.metal_fuzz,
-                );
-
-                hit_record.set_face_normal(ray, outward_normal);
-                Some(hit_record)
+            // 範囲外の場合は遠いほうを調べる(球の内側からレイが出ていく場合)
+            let sqrtd = discriminant.sqrt();
+            let mut t: f32 = (-b - sqrtd) / (2.0 * a);
+            if t <= tmin || tmax <= t {
+                t = (-b + sqrtd) / (2.0 * a);
+                if t <= tmin || tmax <= t {
+                    return None;
+                }
+            }
+
+            let point = ray.at(t);
+            let outward_normal = (point - self.center(time)) / self.radius;
+
+            let (u, v) = sphere_uv(outward_normal);
+
+            let mut hit_record =
+                HitRecord::new(point, outward_normal, true, t, u, v, self.material.as_ref());
+
+            hit_record.set_face_normal(ray, outward_normal);
+            Some(hit_record)
+        }
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        // 時刻time0とtime1における球をそれぞれ囲むボックスを合わせる
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        let box0 = Aabb::new(self.center(time0) - r, self.center(time0) + r);
+        let box1 = Aabb::new(self.center(time1) - r, self.center(time1) + r);
+        Some(Aabb::surrounding_box(box0, box1))
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        SphereSolidAnglePdf::new(origin, self.center(time), self.radius).value(direction)
+    }
+
+    fn random(&self, origin: Vec3, time: f32) -> Vec3 {
+        SphereSolidAnglePdf::new(origin, self.center(time), self.radius).sample()
+    }
+
+    fn sample_surface(&self, time: f32) -> Option<HitRecord<'_>> {
+        let outward_normal = random_unit_vector();
+        let (u, v) = sphere_uv(outward_normal);
+        Some(HitRecord::new(
+            self.center(time) + outward_normal * self.radius,
+            outward_normal,
+            true,
+            0.0,
+            u,
+            v,
+            self.material.as_ref(),
+        ))
+    }
+
+    fn area(&self, _time: f32) -> f32 {
+        4.0 * PI * self.radius * self.radius
+    }
+}
+
+/// 平面的な光源をサンプリングしたときの確率密度。面積あたりの密度1/areaを立体角あたりに直す
+fn rect_pdf_value(hit_record: Option<HitRecord>, direction: Vec3, area: f32) -> f32 {
+    match hit_record {
+        Some(hit_record) => {
+            let distance_squared = hit_record.t() * hit_record.t() * direction.length_squared();
+            let cosine = (direction.dot(hit_record.normal()) / direction.length()).abs();
+            if cosine <= 0.0 {
+                0.0
             } else {
-                None
+                distance_squared / (cosine * area)
+            }
+        }
+        None => 0.0,
+    }
+}
+
+/// 球の中心から表面への単位ベクトルに対するテクスチャ座標
+///
+/// uは-x軸から+z、+x、-zと回って0〜1、vは南極(-y)から北極(+y)へ0〜1
+fn sphere_uv(p: Vec3) -> (f32, f32) {
+    let theta = (-p.y()).clamp(-1.0, 1.0).acos();
+    let phi = (-p.z()).atan2(p.x()) + PI;
+    (phi / (2.0 * PI), theta / PI)
+}
+
+/// xy平面に平行な長方形(z = k)
+#[derive(Clone)]
+pub struct XyRect {
+    x0: f32,
+    x1: f32,
+    y0: f32,
+    y1: f32,
+    k: f32,
+    material: Arc<dyn Material>,
+}
+
+impl XyRect {
+    pub fn new(x0: f32, x1: f32, y0: f32, y1: f32, k: f32, material: Arc<dyn Material>) -> XyRect {
+        XyRect {
+            x0,
+            x1,
+            y0,
+            y1,
+            k,
+            material,
+        }
+    }
+}
+
+impl Hittable for XyRect {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, _time: f32) -> Option<HitRecord<'_>> {
+        let t = (self.k - ray.origin().z()) / ray.direction().z();
+        if !(tmin < t && t < tmax) {
+            return None;
+        }
+        let x = ray.origin().x() + t * ray.direction().x();
+        let y = ray.origin().y() + t * ray.direction().y();
+        if x < self.x0 || self.x1 < x || y < self.y0 || self.y1 < y {
+            return None;
+        }
+
+        let u = (x - self.x0) / (self.x1 - self.x0);
+        let v = (y - self.y0) / (self.y1 - self.y0);
+        let outward_normal = Vec3::new(0.0, 0.0, 1.0);
+        let mut hit_record = HitRecord::new(
+            ray.at(t),
+            outward_normal,
+            true,
+            t,
+            u,
+            v,
+            self.material.as_ref(),
+        );
+        hit_record.set_face_normal(ray, outward_normal);
+        Some(hit_record)
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<Aabb> {
+        // 厚みが0だとボックスとの交差判定ができないので少しだけ厚くする
+        Some(Aabb::new(
+            Vec3::new(self.x0, self.y0, self.k - 0.0001),
+            Vec3::new(self.x1, self.y1, self.k + 0.0001),
+        ))
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        let hit_record = self.hit(
+            &Ray::new(origin, direction, time),
+            0.001,
+            f32::INFINITY,
+            time,
+        );
+        let area = (self.x1 - self.x0) * (self.y1 - self.y0);
+        rect_pdf_value(hit_record, direction, area)
+    }
+
+    fn random(&self, origin: Vec3, _time: f32) -> Vec3 {
+        let point = Vec3::new(
+            random_f32(self.x0, self.x1),
+            random_f32(self.y0, self.y1),
+            self.k,
+        );
+        point - origin
+    }
+
+    fn sample_surface(&self, _time: f32) -> Option<HitRecord<'_>> {
+        let u = random_f32(0.0, 1.0);
+        let v = random_f32(0.0, 1.0);
+        let point = Vec3::new(
+            self.x0 + u * (self.x1 - self.x0),
+            self.y0 + v * (self.y1 - self.y0),
+            self.k,
+        );
+        Some(HitRecord::new(
+            point,
+            Vec3::new(0.0, 0.0, 1.0),
+            true,
+            0.0,
+            u,
+            v,
+            self.material.as_ref(),
+        ))
+    }
+
+    fn area(&self, _time: f32) -> f32 {
+        (self.x1 - self.x0) * (self.y1 - self.y0)
+    }
+}
+
+/// xz平面に平行な長方形(y = k)
+#[derive(Clone)]
+pub struct XzRect {
+    x0: f32,
+    x1: f32,
+    z0: f32,
+    z1: f32,
+    k: f32,
+    material: Arc<dyn Material>,
+}
+
+impl XzRect {
+    pub fn new(x0: f32, x1: f32, z0: f32, z1: f32, k: f32, material: Arc<dyn Material>) -> XzRect {
+        XzRect {
+            x0,
+            x1,
+            z0,
+            z1,
+            k,
+            material,
+        }
+    }
+}
+
+impl Hittable for XzRect {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, _time: f32) -> Option<HitRecord<'_>> {
+        let t = (self.k - ray.origin().y()) / ray.direction().y();
+        if !(tmin < t && t < tmax) {
+            return None;
+        }
+        let x = ray.origin().x() + t * ray.direction().x();
+        let z = ray.origin().z() + t * ray.direction().z();
+        if x < self.x0 || self.x1 < x || z < self.z0 || self.z1 < z {
+            return None;
+        }
+
+        let u = (x - self.x0) / (self.x1 - self.x0);
+        let v = (z - self.z0) / (self.z1 - self.z0);
+        let outward_normal = Vec3::new(0.0, 1.0, 0.0);
+        let mut hit_record = HitRecord::new(
+            ray.at(t),
+            outward_normal,
+            true,
+            t,
+            u,
+            v,
+            self.material.as_ref(),
+        );
+        hit_record.set_face_normal(ray, outward_normal);
+        Some(hit_record)
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<Aabb> {
+        Some(Aabb::new(
+            Vec3::new(self.x0, self.k - 0.0001, self.z0),
+            Vec3::new(self.x1, self.k + 0.0001, self.z1),
+        ))
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        let hit_record = self.hit(
+            &Ray::new(origin, direction, time),
+            0.001,
+            f32::INFINITY,
+            time,
+        );
+        let area = (self.x1 - self.x0) * (self.z1 - self.z0);
+        rect_pdf_value(hit_record, direction, area)
+    }
+
+    fn random(&self, origin: Vec3, _time: f32) -> Vec3 {
+        let point = Vec3::new(
+            random_f32(self.x0, self.x1),
+            self.k,
+            random_f32(self.z0, self.z1),
+        );
+        point - origin
+    }
+
+    fn sample_surface(&self, _time: f32) -> Option<HitRecord<'_>> {
+        let u = random_f32(0.0, 1.0);
+        let v = random_f32(0.0, 1.0);
+        let point = Vec3::new(
+            self.x0 + u * (self.x1 - self.x0),
+            self.k,
+            self.z0 + v * (self.z1 - self.z0),
+        );
+        Some(HitRecord::new(
+            point,
+            Vec3::new(0.0, 1.0, 0.0),
+            true,
+            0.0,
+            u,
+            v,
+            self.material.as_ref(),
+        ))
+    }
+
+    fn area(&self, _time: f32) -> f32 {
+        (self.x1 - self.x0) * (self.z1 - self.z0)
+    }
+}
+
+/// yz平面に平行な長方形(x = k)
+#[derive(Clone)]
+pub struct YzRect {
+    y0: f32,
+    y1: f32,
+    z0: f32,
+    z1: f32,
+    k: f32,
+    material: Arc<dyn Material>,
+}
+
+impl YzRect {
+    pub fn new(y0: f32, y1: f32, z0: f32, z1: f32, k: f32, material: Arc<dyn Material>) -> YzRect {
+        YzRect {
+            y0,
+            y1,
+            z0,
+            z1,
+            k,
+            material,
+        }
+    }
+}
+
+impl Hittable for YzRect {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, _time: f32) -> Option<HitRecord<'_>> {
+        let t = (self.k - ray.origin().x()) / ray.direction().x();
+        if !(tmin < t && t < tmax) {
+            return None;
+        }
+        let y = ray.origin().y() + t * ray.direction().y();
+        let z = ray.origin().z() + t * ray.direction().z();
+        if y < self.y0 || self.y1 < y || z < self.z0 || self.z1 < z {
+            return None;
+        }
+
+        let u = (y - self.y0) / (self.y1 - self.y0);
+        let v = (z - self.z0) / (self.z1 - self.z0);
+        let outward_normal = Vec3::new(1.0, 0.0, 0.0);
+        let mut hit_record = HitRecord::new(
+            ray.at(t),
+            outward_normal,
+            true,
+            t,
+            u,
+            v,
+            self.material.as_ref(),
+        );
+        hit_record.set_face_normal(ray, outward_normal);
+        Some(hit_record)
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<Aabb> {
+        Some(Aabb::new(
+            Vec3::new(self.k - 0.0001, self.y0, self.z0),
+            Vec3::new(self.k + 0.0001, self.y1, self.z1),
+        ))
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        let hit_record = self.hit(
+            &Ray::new(origin, direction, time),
+            0.001,
+            f32::INFINITY,
+            time,
+        );
+        let area = (self.y1 - self.y0) * (self.z1 - self.z0);
+        rect_pdf_value(hit_record, direction, area)
+    }
+
+    fn random(&self, origin: Vec3, _time: f32) -> Vec3 {
+        let point = Vec3::new(
+            self.k,
+            random_f32(self.y0, self.y1),
+            random_f32(self.z0, self.z1),
+        );
+        point - origin
+    }
+
+    fn sample_surface(&self, _time: f32) -> Option<HitRecord<'_>> {
+        let u = random_f32(0.0, 1.0);
+        let v = random_f32(0.0, 1.0);
+        let point = Vec3::new(
+            self.k,
+            self.y0 + u * (self.y1 - self.y0),
+            self.z0 + v * (self.z1 - self.z0),
+        );
+        Some(HitRecord::new(
+            point,
+            Vec3::new(1.0, 0.0, 0.0),
+            true,
+            0.0,
+            u,
+            v,
+            self.material.as_ref(),
+        ))
+    }
+
+    fn area(&self, _time: f32) -> f32 {
+        (self.y1 - self.y0) * (self.z1 - self.z0)
+    }
+}
+
+/// 各面が座標軸に平行な直方体。6枚の長方形でできている
+pub struct Cuboid {
+    box_min: Vec3,
+    box_max: Vec3,
+    sides: Vec<Box<dyn Hittable>>,
+}
+
+impl Cuboid {
+    pub fn new(p0: Vec3, p1: Vec3, material: Arc<dyn Material>) -> Cuboid {
+        let sides: Vec<Box<dyn Hittable>> = vec![
+            Box::new(XyRect::new(
+                p0.x(),
+                p1.x(),
+                p0.y(),
+                p1.y(),
+                p1.z(),
+                material.clone(),
+            )),
+            Box::new(XyRect::new(
+                p0.x(),
+                p1.x(),
+                p0.y(),
+                p1.y(),
+                p0.z(),
+                material.clone(),
+            )),
+            Box::new(XzRect::new(
+                p0.x(),
+                p1.x(),
+                p0.z(),
+                p1.z(),
+                p1.y(),
+                material.clone(),
+            )),
+            Box::new(XzRect::new(
+                p0.x(),
+                p1.x(),
+                p0.z(),
+                p1.z(),
+                p0.y(),
+                material.clone(),
+            )),
+            Box::new(YzRect::new(
+                p0.y(),
+                p1.y(),
+                p0.z(),
+                p1.z(),
+                p1.x(),
+                material.clone(),
+            )),
+            Box::new(YzRect::new(
+                p0.y(),
+                p1.y(),
+                p0.z(),
+                p1.z(),
+                p0.x(),
+                material,
+            )),
+        ];
+        Cuboid {
+            box_min: p0,
+            box_max: p1,
+            sides,
+        }
+    }
+}
+
+impl Hittable for Cuboid {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord<'_>> {
+        let mut hit_record = self.sides.hit(ray, tmin, tmax, time)?;
+        // 各面の法線は座標軸の正の向きなので、手前側の面では外向きになるよう向きを直す
+        let center = (self.box_min + self.box_max) * 0.5;
+        let outward_normal = if hit_record.normal().dot(hit_record.point() - center) < 0.0 {
+            -hit_record.normal()
+        } else {
+            hit_record.normal()
+        };
+        hit_record.set_face_normal(ray, outward_normal);
+        Some(hit_record)
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<Aabb> {
+        Some(Aabb::new(self.box_min, self.box_max))
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        self.sides.pdf_value(origin, direction, time)
+    }
+
+    fn random(&self, origin: Vec3, time: f32) -> Vec3 {
+        self.sides.random(origin, time)
+    }
+
+    fn sample_surface(&self, time: f32) -> Option<HitRecord<'_>> {
+        let mut hit_record = self.sides.sample_surface(time)?;
+        let center = (self.box_min + self.box_max) * 0.5;
+        if hit_record.normal().dot(hit_record.point() - center) < 0.0 {
+            hit_record.set_normal(-hit_record.normal());
+        }
+        Some(hit_record)
+    }
+
+    fn area(&self, time: f32) -> f32 {
+        self.sides.area(time)
+    }
+}
+
+/// 無限に広がる平面。pointを通り、normalが外向きの法線
+///
+/// テクスチャ座標は平面上に取った直交する2軸に沿ったpointからの距離
+#[derive(Clone)]
+pub struct Plane {
+    point: Vec3,
+    normal: Vec3,
+    u_axis: Vec3,
+    v_axis: Vec3,
+    material: Arc<dyn Material>,
+}
+
+impl Plane {
+    pub fn new(point: Vec3, normal: Vec3, material: Arc<dyn Material>) -> Plane {
+        let normal = unit_vector(normal);
+        // 法線と平行でない軸を選んで平面上の軸を作る
+        let a = if normal.x().abs() > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let v_axis = unit_vector(normal.cross(a));
+        let u_axis = v_axis.cross(normal);
+        Plane {
+            point,
+            normal,
+            u_axis,
+            v_axis,
+            material,
+        }
+    }
+}
+
+impl Hittable for Plane {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, _time: f32) -> Option<HitRecord<'_>> {
+        let denom = self.normal.dot(ray.direction());
+        // レイが平面と平行な場合は当たらない
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+        let t = (self.point - ray.origin()).dot(self.normal) / denom;
+        if !(tmin < t && t < tmax) {
+            return None;
+        }
+
+        let point = ray.at(t);
+        let u = (point - self.point).dot(self.u_axis);
+        let v = (point - self.point).dot(self.v_axis);
+        let mut hit_record =
+            HitRecord::new(point, self.normal, true, t, u, v, self.material.as_ref());
+        hit_record.set_face_normal(ray, self.normal);
+        Some(hit_record)
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<Aabb> {
+        None
+    }
+}
+
+/// 三角形メッシュの頂点とインデックスのバッファ。複数の三角形から共有される
+pub struct MeshData {
+    positions: Vec<Vec3>,
+    indices: Vec<[u32; 3]>,
+    normals: Option<Vec<Vec3>>,
+    uvs: Option<Vec<[f32; 2]>>,
+    material: Arc<dyn Material>,
+}
+
+impl MeshData {
+    /// normalsとuvsは与えられた場合、positionsと同じ数だけ必要
+    pub fn new(
+        positions: Vec<Vec3>,
+        indices: Vec<[u32; 3]>,
+        normals: Option<Vec<Vec3>>,
+        uvs: Option<Vec<[f32; 2]>>,
+        material: Arc<dyn Material>,
+    ) -> MeshData {
+        if let Some(normals) = &normals {
+            assert_eq!(
+                normals.len(),
+                positions.len(),
+                "法線の数が頂点の数と違います"
+            );
+        }
+        if let Some(uvs) = &uvs {
+            assert_eq!(uvs.len(), positions.len(), "UV座標の数が頂点の数と違います");
+        }
+        MeshData {
+            positions,
+            indices,
+            normals,
+            uvs,
+            material,
+        }
+    }
+
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    pub fn indices(&self) -> &[[u32; 3]] {
+        &self.indices
+    }
+
+    pub fn normals(&self) -> Option<&[Vec3]> {
+        self.normals.as_deref()
+    }
+
+    pub fn uvs(&self) -> Option<&[[f32; 2]]> {
+        self.uvs.as_deref()
+    }
+}
+
+/// 三角形。メッシュのバッファの中の1つの面を指す
+#[derive(Clone)]
+pub struct Triangle {
+    mesh: Arc<MeshData>,
+    face: u32,
+}
+
+impl Triangle {
+    /// 単独の三角形
+    pub fn new(v0: Vec3, v1: Vec3, v2: Vec3, material: Arc<dyn Material>) -> Triangle {
+        let mesh = MeshData::new(vec![v0, v1, v2], vec![[0, 1, 2]], None, None, material);
+        Triangle {
+            mesh: Arc::new(mesh),
+            face: 0,
+        }
+    }
+
+    fn vertex_indices(&self) -> [usize; 3] {
+        let [i0, i1, i2] = self.mesh.indices[self.face as usize];
+        [i0 as usize, i1 as usize, i2 as usize]
+    }
+
+    /// 重心座標(1 - b1 - b2, b1, b2)におけるUV座標。メッシュがUV座標を持たない場合はNone
+    pub fn uv_at(&self, b1: f32, b2: f32) -> Option<[f32; 2]> {
+        let uvs = self.mesh.uvs.as_ref()?;
+        let [i0, i1, i2] = self.vertex_indices();
+        let b0 = 1.0 - b1 - b2;
+        Some([
+            uvs[i0][0] * b0 + uvs[i1][0] * b1 + uvs[i2][0] * b2,
+            uvs[i0][1] * b0 + uvs[i1][1] * b1 + uvs[i2][1] * b2,
+        ])
+    }
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, _time: f32) -> Option<HitRecord<'_>> {
+        // Möller–Trumboreの交差判定
+        let [i0, i1, i2] = self.vertex_indices();
+        let p0 = self.mesh.positions[i0];
+        let edge1 = self.mesh.positions[i1] - p0;
+        let edge2 = self.mesh.positions[i2] - p0;
+
+        let pvec = ray.direction().cross(edge2);
+        let det = edge1.dot(pvec);
+        // レイが三角形と平行な場合は当たらない
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let tvec = ray.origin() - p0;
+        let b1 = tvec.dot(pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let qvec = tvec.cross(edge1);
+        let b2 = ray.direction().dot(qvec) * inv_det;
+        if b2 < 0.0 || 1.0 < b1 + b2 {
+            return None;
+        }
+        let t = edge2.dot(qvec) * inv_det;
+        if !(tmin < t && t < tmax) {
+            return None;
+        }
+
+        let geometric_normal = unit_vector(edge1.cross(edge2));
+        let front_face = ray.direction().dot(geometric_normal) < 0.0;
+
+        // 頂点法線がある場合は補間してなめらかに見せる。向きは面の法線と同じ側にそろえる
+        let outward_normal = match &self.mesh.normals {
+            Some(normals) => {
+                let b0 = 1.0 - b1 - b2;
+                let n = unit_vector(normals[i0] * b0 + normals[i1] * b1 + normals[i2] * b2);
+                if n.dot(geometric_normal) < 0.0 {
+                    -n
+                } else {
+                    n
+                }
             }
+            None => geometric_normal,
+        };
+        let normal = if front_face {
+            outward_normal
+        } else {
+            -outward_normal
+        };
+
+        // UV座標がない場合は重心座標をそのまま使う
+        let [u, v] = self.uv_at(b1, b2).unwrap_or([b1, b2]);
+
+        Some(HitRecord::new(
+            ray.at(t),
+            normal,
+            front_face,
+            t,
+            u,
+            v,
+            self.mesh.material.as_ref(),
+        ))
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<Aabb> {
+        let [i0, i1, i2] = self.vertex_indices();
+        let p0 = self.mesh.positions[i0];
+        let p1 = self.mesh.positions[i1];
+        let p2 = self.mesh.positions[i2];
+        let bbox = Aabb::surrounding_box(
+            Aabb::surrounding_box(Aabb::new(p0, p0), Aabb::new(p1, p1)),
+            Aabb::new(p2, p2),
+        );
+        // 軸に平行な三角形でも厚みを持つように少しだけ広げる
+        let padding = Vec3::new(0.0001, 0.0001, 0.0001);
+        Some(Aabb::new(bbox.min() - padding, bbox.max() + padding))
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        let hit_record = self.hit(
+            &Ray::new(origin, direction, time),
+            0.001,
+            f32::INFINITY,
+            time,
+        );
+        rect_pdf_value(hit_record, direction, self.area(time))
+    }
+
+    fn random(&self, origin: Vec3, time: f32) -> Vec3 {
+        let hit_record = self.sample_surface(time).unwrap();
+        hit_record.point() - origin
+    }
+
+    fn sample_surface(&self, _time: f32) -> Option<HitRecord<'_>> {
+        // 三角形の上で一様に分布する重心座標
+        let [i0, i1, i2] = self.vertex_indices();
+        let r1 = random_f32(0.0, 1.0).sqrt();
+        let r2 = random_f32(0.0, 1.0);
+        let b1 = r1 * (1.0 - r2);
+        let b2 = r1 * r2;
+        let p0 = self.mesh.positions[i0];
+        let edge1 = self.mesh.positions[i1] - p0;
+        let edge2 = self.mesh.positions[i2] - p0;
+        let [u, v] = self.uv_at(b1, b2).unwrap_or([b1, b2]);
+        Some(HitRecord::new(
+            p0 + edge1 * b1 + edge2 * b2,
+            unit_vector(edge1.cross(edge2)),
+            true,
+            0.0,
+            u,
+            v,
+            self.mesh.material.as_ref(),
+        ))
+    }
+
+    fn area(&self, _time: f32) -> f32 {
+        let [i0, i1, i2] = self.vertex_indices();
+        let p0 = self.mesh.positions[i0];
+        (self.mesh.positions[i1] - p0)
+            .cross(self.mesh.positions[i2] - p0)
+            .length()
+            * 0.5
+    }
+}
+
+/// 頂点とインデックスのバッファを共有する三角形メッシュ。内部にBVHを持つ
+///
+/// 光源として使う場合は、面積に比例した確率で三角形を選んで表面上の点を選ぶ
+pub struct TriangleMesh {
+    mesh: Arc<MeshData>,
+    bvh: FlatBvh<Triangle>,
+    /// BVHに並んだ順の三角形の面積の分布。三角形がない場合はNone
+    areas: Option<Distribution1D>,
+    area: f32,
+}
+
+impl TriangleMesh {
+    pub fn new(mesh: MeshData) -> TriangleMesh {
+        let mesh = Arc::new(mesh);
+        let triangles = (0..mesh.indices.len() as u32)
+            .map(|face| Triangle {
+                mesh: mesh.clone(),
+                face,
+            })
+            .collect();
+        let bvh = FlatBvh::new(triangles, 0.0, 0.0);
+        let areas: Vec<f32> = bvh
+            .primitives()
+            .iter()
+            .map(|triangle| triangle.area(0.0))
+            .collect();
+        let area = areas.iter().sum();
+        TriangleMesh {
+            mesh,
+            bvh,
+            areas: (!areas.is_empty()).then(|| Distribution1D::new(areas)),
+            area,
+        }
+    }
+
+    pub fn mesh(&self) -> &MeshData {
+        &self.mesh
+    }
+
+    pub fn bvh(&self) -> &FlatBvh<Triangle> {
+        &self.bvh
+    }
+}
+
+impl Hittable for TriangleMesh {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord<'_>> {
+        self.bvh.hit(ray, tmin, tmax, time)
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        self.bvh.bounding_box(time0, time1)
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        if self.area <= 0.0 {
+            return 0.0;
         }
+        // 三角形を面積に比例した確率で選ぶので、レイが通り抜けるすべての三角形の確率密度を足し合わせる。
+        // どの三角形でも面積の比を掛けると、メッシュ全体の面積で割ったのと同じになる
+        let ray = Ray::new(origin, direction, time);
+        let mut tmin = 0.001;
+        let mut pdf = 0.0;
+        while let Some(hit_record) = self.bvh.hit(&ray, tmin, f32::INFINITY, time) {
+            tmin = hit_record.t() + 0.001;
+            pdf += rect_pdf_value(Some(hit_record), direction, self.area);
+        }
+        pdf
+    }
+
+    fn random(&self, origin: Vec3, time: f32) -> Vec3 {
+        match self.sample_surface(time) {
+            Some(hit_record) => hit_record.point() - origin,
+            None => Vec3::new(1.0, 0.0, 0.0),
+        }
+    }
+
+    fn sample_surface(&self, time: f32) -> Option<HitRecord<'_>> {
+        if self.area <= 0.0 {
+            return None;
+        }
+        let (_, _, index) = self.areas.as_ref()?.sample(random_f32(0.0, 1.0));
+        self.bvh.primitives()[index].sample_surface(time)
+    }
+
+    fn area(&self, _time: f32) -> f32 {
+        self.area
     }
 }
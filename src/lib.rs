@@ -0,0 +1,29 @@
+pub mod aabb;
+pub mod bdpt;
+pub mod camera;
+pub mod color;
+pub mod distribution;
+pub mod environment;
+pub mod environment_map;
+pub mod flat_bvh;
+pub mod hitrecord;
+pub mod hittable;
+pub mod integrator;
+pub mod item;
+pub mod light;
+pub mod material;
+pub mod microfacet;
+pub mod mlt;
+pub mod obj;
+pub mod onb;
+pub mod pdf;
+pub mod perlin;
+pub mod photon;
+pub mod ray;
+pub mod sampler;
+pub mod scene;
+pub mod sky;
+pub mod texture;
+pub mod transform;
+pub mod vec3;
+pub mod volume;
@@ -0,0 +1,657 @@
+use std::f32::consts::PI;
+
+use crate::onb::Onb;
+use crate::pdf::{CosinePdf, Pdf, SphereSolidAnglePdf, UniformSpherePdf};
+use crate::ray::Ray;
+use crate::vec3::{random_f32, random_unit_vector, unit_vector, Vec3};
+
+/// 光源から照らされる点の立体角がこれより小さい長方形の光源は、面積で一様に点を選ぶ
+const MIN_SOLID_ANGLE: f32 = 1e-4;
+
+/// 光源の上の点を選んだ結果
+pub struct LightSample {
+    direction: Vec3,
+    distance: f32,
+    radiance: Vec3,
+    pdf: f32,
+}
+
+impl LightSample {
+    pub fn new(direction: Vec3, distance: f32, radiance: Vec3, pdf: f32) -> LightSample {
+        LightSample {
+            direction,
+            distance,
+            radiance,
+            pdf,
+        }
+    }
+
+    /// 照らされる点から光源へ向かう単位ベクトル
+    pub fn direction(&self) -> Vec3 {
+        self.direction
+    }
+
+    /// 光源までの距離。無限遠にある光源はf32::INFINITY
+    pub fn distance(&self) -> f32 {
+        self.distance
+    }
+
+    /// 照らされる点に届く放射輝度。点光源のように大きさのない光源では放射照度
+    pub fn radiance(&self) -> Vec3 {
+        self.radiance
+    }
+
+    /// この向きを選んだ確率密度(立体角あたり)。大きさのない光源では1
+    pub fn pdf(&self) -> f32 {
+        self.pdf
+    }
+}
+
+/// 光源から光を放つ点と向きを選んだ結果
+pub struct EmissionSample {
+    origin: Vec3,
+    direction: Vec3,
+    power: Vec3,
+}
+
+impl EmissionSample {
+    pub fn new(origin: Vec3, direction: Vec3, power: Vec3) -> EmissionSample {
+        EmissionSample {
+            origin,
+            direction,
+            power,
+        }
+    }
+
+    /// 光を放つ点
+    pub fn origin(&self) -> Vec3 {
+        self.origin
+    }
+
+    /// 光を放つ向き(単位ベクトル)
+    pub fn direction(&self) -> Vec3 {
+        self.direction
+    }
+
+    /// 放つ光の量(放射束)を、この点と向きを選んだ確率密度で割ったもの
+    pub fn power(&self) -> Vec3 {
+        self.power
+    }
+}
+
+/// 無限遠の光源から届く光を、centerからdistance離れた半径radiusの円板から放つ
+///
+/// 円板はcenterを中心とする半径radiusの球を光の向きに覆う。sampleはその光源で選んだ向き
+pub(crate) fn emission_from_infinity(
+    sample: &LightSample,
+    center: Vec3,
+    radius: f32,
+    distance: f32,
+) -> Option<EmissionSample> {
+    if sample.pdf() <= 0.0 || radius <= 0.0 {
+        return None;
+    }
+    let uvw = Onb::from_w(sample.direction());
+    let r = radius * random_f32(0.0, 1.0).sqrt();
+    let phi = random_f32(0.0, 2.0 * PI);
+    let origin = center + uvw.local(Vec3::new(r * phi.cos(), r * phi.sin(), distance));
+    Some(EmissionSample::new(
+        origin,
+        -sample.direction(),
+        sample.radiance() * (PI * radius * radius / sample.pdf()),
+    ))
+}
+
+/// 物体とは別にシーンに置く光源
+///
+/// 光源自体は物体ではないので、影を落とすこともレイをさえぎることもない
+pub trait Light: Send + Sync {
+    /// pointを照らす光源の上の点を選ぶ。pointに光が届かない場合はNone
+    fn sample(&self, point: Vec3) -> Option<LightSample>;
+
+    /// pointからdirectionの向きをsampleで選ぶ確率密度(立体角あたり)
+    fn pdf_value(&self, _point: Vec3, _direction: Vec3) -> f32 {
+        0.0
+    }
+
+    /// rayがtmaxより手前でこの光源に当たる場合、rayの向きから届く放射輝度
+    fn hit(&self, _ray: &Ray, _tmax: f32) -> Option<Vec3> {
+        None
+    }
+
+    /// 点光源のように大きさがなく、レイが当たることのない光源
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    /// 光源から光を放つ点と向きを選ぶ。光を放たない場合はNone
+    ///
+    /// 無限遠にある光源は、中心center、半径radiusの球を覆う円板をcenterからdistance離して置き、そこから平行に放つ
+    fn sample_emission(
+        &self,
+        _center: Vec3,
+        _radius: f32,
+        _distance: f32,
+    ) -> Option<EmissionSample> {
+        None
+    }
+}
+
+/// 全方向に同じ強さで光る点光源
+pub struct PointLight {
+    position: Vec3,
+    intensity: Vec3,
+}
+
+impl PointLight {
+    /// intensityは放射強度。距離の2乗に反比例して暗くなる
+    pub fn new(position: Vec3, intensity: Vec3) -> PointLight {
+        PointLight {
+            position,
+            intensity,
+        }
+    }
+}
+
+impl Light for PointLight {
+    fn sample(&self, point: Vec3) -> Option<LightSample> {
+        let to_light = self.position - point;
+        let distance_squared = to_light.length_squared();
+        if distance_squared <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            direction: unit_vector(to_light),
+            distance: distance_squared.sqrt(),
+            radiance: self.intensity / distance_squared,
+            pdf: 1.0,
+        })
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn sample_emission(
+        &self,
+        _center: Vec3,
+        _radius: f32,
+        _distance: f32,
+    ) -> Option<EmissionSample> {
+        Some(EmissionSample::new(
+            self.position,
+            UniformSpherePdf.sample(),
+            self.intensity * (4.0 * PI),
+        ))
+    }
+}
+
+/// 円錐の中だけを照らすスポットライト
+pub struct SpotLight {
+    position: Vec3,
+    direction: Vec3,
+    intensity: Vec3,
+    cos_total: f32,
+    cos_falloff: f32,
+}
+
+impl SpotLight {
+    /// directionは円錐の軸の向き。軸からfalloff_angle(度)までは同じ明るさで、
+    /// そこからtotal_angle(度)にかけてなめらかに暗くなる
+    pub fn new(
+        position: Vec3,
+        direction: Vec3,
+        intensity: Vec3,
+        total_angle: f32,
+        falloff_angle: f32,
+    ) -> SpotLight {
+        let total_angle = total_angle.to_radians();
+        let falloff_angle = falloff_angle.to_radians().min(total_angle);
+        SpotLight {
+            position,
+            direction: unit_vector(direction),
+            intensity,
+            cos_total: total_angle.cos(),
+            cos_falloff: falloff_angle.cos(),
+        }
+    }
+
+    /// 軸との角度の余弦がcos_thetaの向きの明るさの割合
+    fn falloff(&self, cos_theta: f32) -> f32 {
+        if cos_theta >= self.cos_falloff {
+            return 1.0;
+        }
+        if cos_theta <= self.cos_total {
+            return 0.0;
+        }
+        let t = (cos_theta - self.cos_total) / (self.cos_falloff - self.cos_total);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, point: Vec3) -> Option<LightSample> {
+        let to_light = self.position - point;
+        let distance_squared = to_light.length_squared();
+        if distance_squared <= 0.0 {
+            return None;
+        }
+        let direction = unit_vector(to_light);
+        let falloff = self.falloff(-direction.dot(self.direction));
+        if falloff <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            direction,
+            distance: distance_squared.sqrt(),
+            radiance: self.intensity * (falloff / distance_squared),
+            pdf: 1.0,
+        })
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn sample_emission(
+        &self,
+        _center: Vec3,
+        _radius: f32,
+        _distance: f32,
+    ) -> Option<EmissionSample> {
+        // 円錐の中で一様に向きを選ぶ
+        let one_minus_cos = 1.0 - self.cos_total;
+        if one_minus_cos <= 0.0 {
+            return None;
+        }
+        let z = 1.0 - random_f32(0.0, 1.0) * one_minus_cos;
+        let phi = random_f32(0.0, 2.0 * PI);
+        let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+        let direction = Onb::from_w(self.direction).local(Vec3::new(
+            phi.cos() * sin_theta,
+            phi.sin() * sin_theta,
+            z,
+        ));
+        Some(EmissionSample::new(
+            self.position,
+            unit_vector(direction),
+            self.intensity * (self.falloff(z) * 2.0 * PI * one_minus_cos),
+        ))
+    }
+}
+
+/// 太陽のように無限遠から平行に届く光
+///
+/// 見かけの大きさ(視直径)を持たせると、その円錐の中で一様に向きを選ぶので影の縁がぼける
+pub struct DirectionalLight {
+    uvw: Onb,
+    irradiance: Vec3,
+    /// 円錐の立体角を2πで割ったもの(1 - cos(視直径 / 2))
+    one_minus_cos: f32,
+}
+
+impl DirectionalLight {
+    /// to_lightは光源がある向き。irradianceは光に垂直な面が受ける放射照度。angular_diameterは視直径(度)
+    pub fn new(to_light: Vec3, irradiance: Vec3, angular_diameter: f32) -> DirectionalLight {
+        // 1 - cos(θ) = 2sin²(θ / 2)で計算し、小さな角度でも桁落ちしないようにする
+        let half_sin = (angular_diameter.to_radians() / 4.0).sin();
+        DirectionalLight {
+            uvw: Onb::from_w(to_light),
+            irradiance,
+            one_minus_cos: 2.0 * half_sin * half_sin,
+        }
+    }
+
+    fn solid_angle(&self) -> f32 {
+        2.0 * PI * self.one_minus_cos
+    }
+
+    /// directionが光源の円錐の中にある
+    fn contains(&self, direction: Vec3) -> bool {
+        1.0 - unit_vector(direction).dot(self.uvw.w()) <= self.one_minus_cos
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _point: Vec3) -> Option<LightSample> {
+        if self.is_delta() {
+            return Some(LightSample {
+                direction: self.uvw.w(),
+                distance: f32::INFINITY,
+                radiance: self.irradiance,
+                pdf: 1.0,
+            });
+        }
+        let z = 1.0 - random_f32(0.0, 1.0) * self.one_minus_cos;
+        let phi = random_f32(0.0, 2.0 * PI);
+        let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+        let direction = self
+            .uvw
+            .local(Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z));
+        let solid_angle = self.solid_angle();
+        Some(LightSample {
+            direction: unit_vector(direction),
+            distance: f32::INFINITY,
+            radiance: self.irradiance / solid_angle,
+            pdf: 1.0 / solid_angle,
+        })
+    }
+
+    fn pdf_value(&self, _point: Vec3, direction: Vec3) -> f32 {
+        if self.is_delta() || !self.contains(direction) {
+            return 0.0;
+        }
+        1.0 / self.solid_angle()
+    }
+
+    fn hit(&self, ray: &Ray, tmax: f32) -> Option<Vec3> {
+        // 無限遠にあるので、何にも当たらずに抜けたレイだけが届く
+        if self.is_delta() || tmax < f32::INFINITY || !self.contains(ray.direction()) {
+            return None;
+        }
+        Some(self.irradiance / self.solid_angle())
+    }
+
+    fn is_delta(&self) -> bool {
+        self.one_minus_cos <= 0.0
+    }
+
+    fn sample_emission(&self, center: Vec3, radius: f32, distance: f32) -> Option<EmissionSample> {
+        emission_from_infinity(&self.sample(center)?, center, radius, distance)
+    }
+}
+
+/// 表面全体が同じ明るさで光る球
+pub struct SphereLight {
+    center: Vec3,
+    radius: f32,
+    radiance: Vec3,
+}
+
+impl SphereLight {
+    pub fn new(center: Vec3, radius: f32, radiance: Vec3) -> SphereLight {
+        SphereLight {
+            center,
+            radius,
+            radiance,
+        }
+    }
+
+    /// originからdirectionの向きに進んで球の外側から当たるまでのパラメータt
+    fn intersect(&self, origin: Vec3, direction: Vec3) -> Option<f32> {
+        let oc = origin - self.center;
+        let a = direction.length_squared();
+        let half_b = oc.dot(direction);
+        let c = oc.length_squared() - self.radius * self.radius;
+        let discriminant = half_b * half_b - a * c;
+        if c <= 0.0 || discriminant < 0.0 {
+            return None;
+        }
+        let t = (-half_b - discriminant.sqrt()) / a;
+        if t > 0.0 {
+            Some(t)
+        } else {
+            None
+        }
+    }
+}
+
+impl Light for SphereLight {
+    fn sample(&self, point: Vec3) -> Option<LightSample> {
+        // 球の中からは照らさない
+        if (point - self.center).length_squared() <= self.radius * self.radius {
+            return None;
+        }
+        let pdf = SphereSolidAnglePdf::new(point, self.center, self.radius);
+        let direction = unit_vector(pdf.sample());
+        // 球をかすめる向きでは交点が求まらないことがあるので、中心までの距離の射影で代わりにする
+        let distance = self
+            .intersect(point, direction)
+            .unwrap_or_else(|| (self.center - point).dot(direction));
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.radiance,
+            pdf: pdf.value(direction),
+        })
+    }
+
+    fn pdf_value(&self, point: Vec3, direction: Vec3) -> f32 {
+        if self.intersect(point, direction).is_none() {
+            return 0.0;
+        }
+        SphereSolidAnglePdf::new(point, self.center, self.radius).value(direction)
+    }
+
+    fn hit(&self, ray: &Ray, tmax: f32) -> Option<Vec3> {
+        match self.intersect(ray.origin(), ray.direction()) {
+            Some(t) if t > 0.001 && t < tmax => Some(self.radiance),
+            _ => None,
+        }
+    }
+
+    fn sample_emission(
+        &self,
+        _center: Vec3,
+        _radius: f32,
+        _distance: f32,
+    ) -> Option<EmissionSample> {
+        // 表面で一様に点を選び、外向きに余弦に比例した向きへ放つ
+        let normal = random_unit_vector();
+        let area = 4.0 * PI * self.radius * self.radius;
+        Some(EmissionSample::new(
+            self.center + normal * self.radius,
+            unit_vector(CosinePdf::new(normal).sample()),
+            self.radiance * (PI * area),
+        ))
+    }
+}
+
+/// 長方形の光源。u × vの向きの面だけが光る
+///
+/// 照らされる点から見た長方形の立体角の中で一様に向きを選ぶ(Ureña et al. 2013)
+pub struct QuadLight {
+    corner: Vec3,
+    u: Vec3,
+    v: Vec3,
+    radiance: Vec3,
+    normal: Vec3,
+    area: f32,
+}
+
+impl QuadLight {
+    /// cornerを1つの頂点とし、u、vを2辺とする長方形
+    pub fn new(corner: Vec3, u: Vec3, v: Vec3, radiance: Vec3) -> QuadLight {
+        let n = u.cross(v);
+        QuadLight {
+            corner,
+            u,
+            v,
+            radiance,
+            normal: unit_vector(n),
+            area: n.length(),
+        }
+    }
+
+    /// originからdirectionの向きに進んで光る面に当たるまでのパラメータt
+    fn intersect(&self, origin: Vec3, direction: Vec3) -> Option<f32> {
+        let denom = direction.dot(self.normal);
+        if denom >= 0.0 {
+            return None;
+        }
+        let t = (self.corner - origin).dot(self.normal) / denom;
+        if t <= 0.0 {
+            return None;
+        }
+        let p = origin + direction * t - self.corner;
+        let a = p.dot(self.u) / self.u.length_squared();
+        let b = p.dot(self.v) / self.v.length_squared();
+        if (0.0..=1.0).contains(&a) && (0.0..=1.0).contains(&b) {
+            Some(t)
+        } else {
+            None
+        }
+    }
+
+    /// 面積あたり一様に選んだ場合の立体角あたりの確率密度
+    fn area_pdf(&self, point: Vec3, direction: Vec3) -> f32 {
+        match self.intersect(point, direction) {
+            Some(t) => {
+                let d = direction * t;
+                let cosine = unit_vector(d).dot(self.normal).abs();
+                d.length_squared() / (cosine * self.area)
+            }
+            None => 0.0,
+        }
+    }
+}
+
+/// pointから見た長方形の立体角
+struct SphericalQuad {
+    ex: Vec3,
+    ey: Vec3,
+    ez: Vec3,
+    x0: f32,
+    x1: f32,
+    y0: f32,
+    y1: f32,
+    z0: f32,
+    b0: f32,
+    b1: f32,
+    k: f32,
+    solid_angle: f32,
+}
+
+impl SphericalQuad {
+    /// pointが光る面の裏側にある場合はNone
+    fn new(quad: &QuadLight, point: Vec3) -> Option<SphericalQuad> {
+        let ex = unit_vector(quad.u);
+        let ey = unit_vector(quad.v);
+        let ez = quad.normal;
+        let d = quad.corner - point;
+        let z0 = d.dot(ez);
+        if z0 >= 0.0 {
+            return None;
+        }
+        let x0 = d.dot(ex);
+        let y0 = d.dot(ey);
+        let x1 = x0 + quad.u.length();
+        let y1 = y0 + quad.v.length();
+
+        // 長方形の4つの頂点へ向かうベクトルが作る面の法線
+        let v00 = Vec3::new(x0, y0, z0);
+        let v01 = Vec3::new(x0, y1, z0);
+        let v10 = Vec3::new(x1, y0, z0);
+        let v11 = Vec3::new(x1, y1, z0);
+        let n0 = unit_vector(v00.cross(v10));
+        let n1 = unit_vector(v10.cross(v11));
+        let n2 = unit_vector(v11.cross(v01));
+        let n3 = unit_vector(v01.cross(v00));
+
+        // 球面上の四角形の内角
+        let g0 = (-n0.dot(n1)).clamp(-1.0, 1.0).acos();
+        let g1 = (-n1.dot(n2)).clamp(-1.0, 1.0).acos();
+        let g2 = (-n2.dot(n3)).clamp(-1.0, 1.0).acos();
+        let g3 = (-n3.dot(n0)).clamp(-1.0, 1.0).acos();
+        let k = 2.0 * PI - g2 - g3;
+
+        Some(SphericalQuad {
+            ex,
+            ey,
+            ez,
+            x0,
+            x1,
+            y0,
+            y1,
+            z0,
+            b0: n0.z(),
+            b1: n2.z(),
+            k,
+            solid_angle: g0 + g1 - k,
+        })
+    }
+
+    /// 一様な乱数(s, t)から立体角の中の向きを選ぶ。pointから長方形の上の点へのベクトルを返す
+    fn sample(&self, s: f32, t: f32) -> Vec3 {
+        let au = s * self.solid_angle + self.k;
+        let fu = (au.cos() * self.b0 - self.b1) / au.sin();
+        let cu = (1.0 / (fu * fu + self.b0 * self.b0).sqrt()).copysign(fu);
+        let cu = cu.clamp(-1.0, 1.0);
+        let xu = (-(cu * self.z0) / (1.0 - cu * cu).max(1e-12).sqrt()).clamp(self.x0, self.x1);
+
+        let d = (xu * xu + self.z0 * self.z0).sqrt();
+        let h0 = self.y0 / (d * d + self.y0 * self.y0).sqrt();
+        let h1 = self.y1 / (d * d + self.y1 * self.y1).sqrt();
+        let hv = h0 + t * (h1 - h0);
+        let hv2 = hv * hv;
+        let yv = if hv2 < 1.0 - 1e-6 {
+            (hv * d / (1.0 - hv2).sqrt()).clamp(self.y0, self.y1)
+        } else {
+            self.y1
+        };
+
+        self.ex * xu + self.ey * yv + self.ez * self.z0
+    }
+}
+
+impl Light for QuadLight {
+    fn sample(&self, point: Vec3) -> Option<LightSample> {
+        let quad = SphericalQuad::new(self, point)?;
+        let s = random_f32(0.0, 1.0);
+        let t = random_f32(0.0, 1.0);
+        if quad.solid_angle < MIN_SOLID_ANGLE {
+            // 遠くの小さな光源では立体角の計算の誤差が大きくなる
+            let to_light = self.corner + self.u * s + self.v * t - point;
+            let direction = unit_vector(to_light);
+            let pdf = self.area_pdf(point, direction);
+            if pdf <= 0.0 {
+                return None;
+            }
+            return Some(LightSample {
+                direction,
+                distance: to_light.length(),
+                radiance: self.radiance,
+                pdf,
+            });
+        }
+        let to_light = quad.sample(s, t);
+        Some(LightSample {
+            direction: unit_vector(to_light),
+            distance: to_light.length(),
+            radiance: self.radiance,
+            pdf: 1.0 / quad.solid_angle,
+        })
+    }
+
+    fn pdf_value(&self, point: Vec3, direction: Vec3) -> f32 {
+        if self.intersect(point, direction).is_none() {
+            return 0.0;
+        }
+        match SphericalQuad::new(self, point) {
+            Some(quad) if quad.solid_angle >= MIN_SOLID_ANGLE => 1.0 / quad.solid_angle,
+            Some(_) => self.area_pdf(point, direction),
+            None => 0.0,
+        }
+    }
+
+    fn hit(&self, ray: &Ray, tmax: f32) -> Option<Vec3> {
+        match self.intersect(ray.origin(), ray.direction()) {
+            Some(t) if t > 0.001 && t < tmax => Some(self.radiance),
+            _ => None,
+        }
+    }
+
+    fn sample_emission(
+        &self,
+        _center: Vec3,
+        _radius: f32,
+        _distance: f32,
+    ) -> Option<EmissionSample> {
+        let origin = self.corner + self.u * random_f32(0.0, 1.0) + self.v * random_f32(0.0, 1.0);
+        Some(EmissionSample::new(
+            origin,
+            unit_vector(CosinePdf::new(self.normal).sample()),
+            self.radiance * (PI * self.area),
+        ))
+    }
+}
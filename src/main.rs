@@ -1,123 +1,20 @@
-use std::f32::INFINITY;
 use std::io;
 use std::io::Write;
+use std::path::Path;
 
-use hitrecord::HitRecord;
-use hittable::Hittable;
-
-mod camera;
-mod color;
-mod hitrecord;
-mod hittable;
-mod item;
-mod material;
-mod ray;
-mod scene;
-mod vec3;
-
-use camera::Camera;
-use color::*;
-use rand::Rng;
-use ray::Ray;
 use rayon::prelude::*;
-use vec3::{color, origin, random_f32, random_unit_vector, reflect, refract, unit_vector, Vec3};
 
-use crate::{material::Material, scene::Scene};
-
-fn ray_color(ray: &Ray, scene: &Vec<Box<dyn Hittable>>, depth: u32) -> Vec3 {
-    // 反射回数が一定よりも多くなったら、その時点で追跡をやめる
-    if depth <= 0 {
-        return color(0.0, 0.0, 0.0);
-    }
-
-    let iter = scene.iter();
-
-    // 物体に衝突する場合
-    let mut closest_record: HitRecord = HitRecord::not_hit();
-
-    // カメラに最も近い物体のHitRecordを探す
-    let mut tmax = INFINITY;
-    for item in iter {
-        match item.hit(ray, 0.001, tmax, ray.time()) {
-            None => {}
-            Some(hit_record) => {
-                if closest_record.t() > hit_record.t() {
-                    closest_record = hit_record;
-                    tmax = closest_record.t();
-                }
-            }
-        }
-    }
-
-    if closest_record.t() == INFINITY {
-        // tが無限大ということは何にも衝突しなかったという意味なので背景の色
-        let unit_direction = unit_vector(ray.direction());
-        let two = 2.0_f32;
-        let sqrt2: f32 = two.sqrt();
-        let t = (unit_direction.y() + 1.0 / sqrt2) / sqrt2;
-        return color(1.0, 1.0, 1.0) * (1.0 - t) + color(0.5, 0.7, 1.0) * t;
-    } else {
-        // 物体に当たった場合
-        match closest_record.material() {
-            // 拡散マテリアル
-            Material::Lambertian => {
-                // 新しい向き先(拡散)
-                let target = closest_record.normal() + random_unit_vector();
-                // 跳ね返ったレイ
-                let new_ray = Ray::new(closest_record.point(), target, ray.time());
-                return ray_color(&new_ray, scene, depth - 1) * closest_record.attenuation();
-            }
-            // 金属マテリアル
-            Material::Metal => {
-                // 新しい向き先(反射)
-                let target = reflect(unit_vector(ray.direction()), closest_record.normal())
-                    + random_unit_vector() * closest_record.metal_fuzz();
-                // 跳ね返ったレイ
-                let new_ray = Ray::new(closest_record.point(), target, ray.time());
-                return ray_color(&new_ray, scene, depth - 1) * closest_record.attenuation();
-            }
-            // 誘電体マテリアル
-            Material::Dielectric => {
-                // 新しい向き先(屈折)
-                let ref_idx = 1.5;
-                let etai_over_etat: f32;
-                if closest_record.front_face() {
-                    etai_over_etat = 1.0 / ref_idx;
-                } else {
-                    etai_over_etat = ref_idx;
-                }
-                let unit_direction = unit_vector(ray.direction());
-
-                let a = -unit_direction.dot(closest_record.normal());
-                let cos_theta: f32;
-                if a < 1.0 {
-                    cos_theta = a;
-                } else {
-                    cos_theta = 1.0;
-                }
-
-                let reflect_prob = Material::shlick(cos_theta, etai_over_etat);
-
-                if random_f32(0.0, 1.0) < reflect_prob {
-                    // 反射
-                    let target = reflect(unit_vector(ray.direction()), closest_record.normal());
-                    // 跳ね返ったレイ
-                    let new_ray = Ray::new(closest_record.point(), target, ray.time());
-                    return ray_color(&new_ray, scene, depth - 1);
-                }
-
-                // 屈折
-                let target = refract(unit_direction, closest_record.normal(), etai_over_etat);
-                let new_ray = Ray::new(closest_record.point(), target, ray.time());
-                return ray_color(&new_ray, scene, depth - 1);
-            }
-            // 未指定
-            Material::None => {
-                return color(1.0, 0.0, 0.0);
-            }
-        }
-    }
-}
+use ray_tracing_in_rust::bdpt::BidirectionalPathTracer;
+use ray_tracing_in_rust::color::*;
+use ray_tracing_in_rust::environment::Environment;
+use ray_tracing_in_rust::environment_map::EnvironmentMap;
+use ray_tracing_in_rust::hittable::Hittable;
+use ray_tracing_in_rust::integrator::*;
+use ray_tracing_in_rust::mlt::MetropolisLightTransport;
+use ray_tracing_in_rust::photon::{caustic_casters, CausticPhotonMaps};
+use ray_tracing_in_rust::scene::Scene;
+use ray_tracing_in_rust::sky::SkySettings;
+use ray_tracing_in_rust::vec3::{origin, random_f32, Vec3};
 
 fn main() {
     // 定数設定
@@ -139,60 +36,166 @@ fn main() {
     // const MAX_DEPTH: u32 = 2;
     const MAX_DEPTH: u32 = 50;
 
+    // この反射回数からロシアンルーレットで追跡を打ち切り始める
+    // const RUSSIAN_ROULETTE_DEPTH: u32 = MAX_DEPTH;
+    const RUSSIAN_ROULETTE_DEPTH: u32 = 5;
+
+    // フォトンマッピングで1回に光源から放つフォトンの数と、半径を縮めながらフォトンマップを作る回数
+    const PHOTON_COUNT: usize = 1_000_000;
+    const PHOTON_PASSES: usize = 8;
+
     const IMAGE_HEIGHT: u32 = ((IMAGE_WIDTH as f32) / ASPECT_RATIO) as u32;
     const HEIGHT: f32 = (IMAGE_HEIGHT - 1) as f32;
     const WIDTH: f32 = (IMAGE_WIDTH - 1) as f32;
     const NUM_OF_PIXELS: u32 = IMAGE_WIDTH * IMAGE_HEIGHT;
 
-    // カメラ
-    let look_from = Vec3::new(12.0, 2.0, 3.0);
-    let look_at = Vec3::new(0.0, 0.0, 0.0);
-    let vup = Vec3::new(0.0, 1.0, 0.0);
-    let dist_to_focus = 10.0;
-    let camera = Camera::new(
-        look_from,
-        look_at,
-        vup,
-        25.0,
-        ASPECT_RATIO,
-        0.05,
-        dist_to_focus,
-        0.0,
-        10.0,
-    );
+    // シーン。コマンドライン引数で名前を指定する
+    // objの場合は続けてOBJファイルのパスを、volの場合は続けて.volファイルのパスを指定する
+    // --integrator <名前>で色の求め方を切り替える
+    // --environment <パス>で背景をHDR画像に置き換える。
+    // --environment-intensity <倍率>と--environment-rotation <角度>で明るさと向きを変えられる
+    // --sky-location <緯度>,<経度>、--sky-date <年>-<月>-<日>、--sky-time <時>:<分>、
+    // --sky-utc-offset <時間>、--sky-turbidity <濁り>、--sky-albedo <反射率>のどれかを指定すると、
+    // 背景をその日時と場所の空にして太陽を光源に加える(skyシーンでは空の設定を変える)
+    let mut args: Vec<String> = std::env::args().collect();
+    let integrator_name = take_option(&mut args, "--integrator").unwrap_or("path".to_string());
+    let environment_path = take_option(&mut args, "--environment");
+    let environment_intensity =
+        take_option(&mut args, "--environment-intensity").map_or(1.0, |x| {
+            x.parse::<f32>()
+                .unwrap_or_else(|_| panic!("{}は数値ではありません", x))
+        });
+    let environment_rotation = take_option(&mut args, "--environment-rotation").map_or(0.0, |x| {
+        x.parse::<f32>()
+            .unwrap_or_else(|_| panic!("{}は数値ではありません", x))
+    });
+    let sky_settings = take_sky_settings(&mut args);
+    if sky_settings.is_some() && environment_path.is_some() {
+        panic!("--environmentと空の設定は同時に指定できません");
+    }
+    let scene_name = args.get(1).map_or("random", |name| name.as_str());
+    let scene = if scene_name == "obj" {
+        let path = args.get(2).expect("OBJファイルのパスを指定してください");
+        Scene::obj_model(Path::new(path), ASPECT_RATIO).unwrap_or_else(|e| panic!("{}", e))
+    } else if scene_name == "vol" {
+        let path = args.get(2).expect(".volファイルのパスを指定してください");
+        Scene::vol_model(Path::new(path), ASPECT_RATIO)
+            .unwrap_or_else(|e| panic!("{}を読み込めません: {}", path, e))
+    } else if let (Some(settings), "sky") = (&sky_settings, scene_name) {
+        Scene::sky(settings, ASPECT_RATIO)
+    } else {
+        let scene = Scene::by_name(scene_name, ASPECT_RATIO)
+            .unwrap_or_else(|| panic!("{}というシーンはありません", scene_name));
+        match &sky_settings {
+            Some(settings) => scene.with_physical_sky(&settings.physical_sky()),
+            None => scene,
+        }
+    };
+    let scene = match environment_path {
+        Some(path) => scene.with_environment(Environment::image(
+            EnvironmentMap::open(&path, environment_intensity, environment_rotation)
+                .unwrap_or_else(|e| panic!("{}を読み込めません: {}", path, e)),
+        )),
+        None => scene,
+    };
+    let camera = scene.camera();
+    println!("BVH {}", scene.world().bvh().stats());
+
+    // 距離を使うデバッグ表示の基準はシーン全体の大きさにする
+    let bbox = scene
+        .world()
+        .bvh()
+        .bounding_box(camera.time0(), camera.time1());
+    let scene_size = bbox.map_or(1.0, |bbox| (bbox.max() - bbox.min()).length());
+    // カメラから最も遠い角までの距離
+    let farthest = bbox.map_or(1.0, |bbox| {
+        let p = camera.look_from();
+        let d = |i: usize| {
+            (bbox.min()[i] - p[i])
+                .abs()
+                .max((bbox.max()[i] - p[i]).abs())
+        };
+        Vec3::new(d(0), d(1), d(2)).length()
+    });
+    let integrator: Box<dyn Integrator> = match integrator_name.as_str() {
+        "path" => Box::new(PathTracer::new(MAX_DEPTH, RUSSIAN_ROULETTE_DEPTH)),
+        "bdpt" => Box::new(BidirectionalPathTracer::new(MAX_DEPTH)),
+        "photon" => {
+            // 半径はコースティクスを作る物体の大きさを基準にする。地面のような大きな物体に左右されないようにする
+            let caster_size = caustic_casters(&scene)
+                .map_or(scene_size, |bbox| (bbox.max() - bbox.min()).length());
+            let caustics = CausticPhotonMaps::new(
+                &scene,
+                PHOTON_COUNT,
+                caster_size * 0.005,
+                PHOTON_PASSES,
+                MAX_DEPTH,
+            );
+            let photon_count: usize = caustics.maps().iter().map(|map| map.len()).sum();
+            println!("コースティクスのフォトン {}個", photon_count);
+            Box::new(PathTracer::new(MAX_DEPTH, RUSSIAN_ROULETTE_DEPTH).with_caustics(caustics))
+        }
+        "mlt" => Box::new(MetropolisLightTransport::new(
+            Box::new(PathTracer::new(MAX_DEPTH, RUSSIAN_ROULETTE_DEPTH)),
+            100_000,
+            1000,
+            0.3,
+            0.01,
+        )),
+        "normal" => Box::new(NormalIntegrator),
+        "depth" => Box::new(DepthIntegrator::new(farthest)),
+        "albedo" => Box::new(AlbedoIntegrator),
+        "uv" => Box::new(UvIntegrator),
+        "ao" => Box::new(AmbientOcclusionIntegrator::new(scene_size * 0.1)),
+        "cost" => Box::new(CostIntegrator::new(50.0)),
+        _ => panic!("{}というintegratorはありません", integrator_name),
+    };
 
     let mut img = image::RgbImage::new(IMAGE_WIDTH, IMAGE_HEIGHT);
 
-    let scene = Scene::random_scene();
+    // 画像上の位置(x, y)を通るレイ
+    // 画角の座標系では左上が(0, 0)なためy軸の向きが逆になっている
+    let camera_ray = |x: f32, y: f32| {
+        // 画角の横座標
+        let u = x / (WIDTH - 1.0);
+        // 画角の縦座標
+        let v = ((HEIGHT - 1.0) - y) / (HEIGHT - 1.0);
+        camera.get_ray(u, v)
+    };
 
-    // 進捗
-    let mut progress: u32 = 0;
+    // 画像全体をまとめて描くintegratorの場合
+    if let Some(colors) = integrator.render(
+        &scene,
+        IMAGE_WIDTH,
+        IMAGE_HEIGHT,
+        SAMPLE_PER_PIXEL,
+        &camera_ray,
+    ) {
+        for (c, pixel) in colors.into_iter().zip(img.pixels_mut()) {
+            write_color(pixel, c, 1);
+        }
+        img.save("result.png").unwrap();
+        return;
+    }
 
     // カメラから見える画角の1ピクセルごとに色を決めていく
-    for (x, y, pixel) in img.enumerate_pixels_mut() {
+    // progressは進捗
+    for (progress, (x, y, pixel)) in (0_u32..).zip(img.enumerate_pixels_mut()) {
         // アンチエイリアシングのために乱数を使って少しずらした方向にレイをたくさん飛ばして色を平均化する
         // 平均を計算するために足しこむための変数
         let mut sum_of_colors = origin();
-        // 乱数
 
         let colors: Vec<Vec3> = (0..SAMPLE_PER_PIXEL)
             .into_par_iter()
             .map(|_| {
-                // 乱数を生成
-                let mut rng = rand::thread_rng();
-                let rand1 = rng.gen::<f32>();
-                let rand2 = rng.gen::<f32>();
-
-                // 画角の横座標
-                let u = ((x as f32) + rand1) / (WIDTH - 1.0);
-                // 画角の縦座標
-                // 画角の座標系では左上が(0, 0)なためy軸の向きが逆になっている
-                let v = ((HEIGHT - 1.0) - ((y as f32) + rand2)) / (HEIGHT - 1.0);
-                let ray = camera.get_ray(u, v);
+                // 乱数で少しずらした位置を通るレイ
+                let ray = camera_ray(
+                    (x as f32) + random_f32(0.0, 1.0),
+                    (y as f32) + random_f32(0.0, 1.0),
+                );
 
                 // レイを飛ばして色を決める
-                let color = ray_color(&ray, &scene, MAX_DEPTH);
-                color
+                integrator.li(ray, &scene)
             })
             .collect();
 
@@ -205,11 +208,79 @@ fn main() {
         write_color(pixel, sum_of_colors, SAMPLE_PER_PIXEL);
 
         // 進捗を表示
-        if progress % (NUM_OF_PIXELS / 100) == 0 {
+        if progress.is_multiple_of(NUM_OF_PIXELS / 100) {
             println!("{:.0}%", 100.0 * (progress as f32) / NUM_OF_PIXELS as f32);
             io::stdout().flush().unwrap();
         }
-        progress += 1;
     }
     img.save("result.png").unwrap();
 }
+
+/// コマンドライン引数から空の設定を取り除いて返す。どれも指定されていなければNone
+fn take_sky_settings(args: &mut Vec<String>) -> Option<SkySettings> {
+    let location = take_option(args, "--sky-location");
+    let date = take_option(args, "--sky-date");
+    let time = take_option(args, "--sky-time");
+    let utc_offset = take_option(args, "--sky-utc-offset");
+    let turbidity = take_option(args, "--sky-turbidity");
+    let albedo = take_option(args, "--sky-albedo");
+    if [&location, &date, &time, &utc_offset, &turbidity, &albedo]
+        .iter()
+        .all(|x| x.is_none())
+    {
+        return None;
+    }
+
+    let mut settings = SkySettings::default();
+    if let Some(location) = location {
+        let [latitude, longitude] = split_numbers(&location, ',');
+        settings = settings.with_location(latitude, longitude);
+    }
+    if let Some(date) = date {
+        let [year, month, day] = split_numbers(&date, '-');
+        settings = settings.with_date(year as i32, month as u32, day as u32);
+    }
+    // 時刻だけを指定した場合は既定の時差のまま、時差だけを指定した場合は既定の時刻のままにする
+    let default_time = settings.time();
+    let hour = time.map_or(default_time.0, |time| {
+        let [hour, minute] = split_numbers(&time, ':');
+        hour + minute / 60.0
+    });
+    let utc_offset = utc_offset.map_or(default_time.1, |x| parse_number(&x));
+    settings = settings.with_time(hour, utc_offset);
+    if let Some(turbidity) = turbidity {
+        settings = settings.with_turbidity(parse_number(&turbidity));
+    }
+    if let Some(albedo) = albedo {
+        let a = parse_number(&albedo);
+        settings = settings.with_ground_albedo(Vec3::new(a, a, a));
+    }
+    Some(settings)
+}
+
+/// separatorで区切られたN個の数値
+fn split_numbers<const N: usize>(value: &str, separator: char) -> [f32; N] {
+    let numbers: Vec<f32> = value.split(separator).map(parse_number).collect();
+    numbers.try_into().unwrap_or_else(|_| {
+        panic!(
+            "{}は{}で区切った{}個の数値ではありません",
+            value, separator, N
+        )
+    })
+}
+
+fn parse_number(x: &str) -> f32 {
+    x.parse::<f32>()
+        .unwrap_or_else(|_| panic!("{}は数値ではありません", x))
+}
+
+/// コマンドライン引数からnameとそれに続く値を取り除き、値を返す
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == name)?;
+    let value = args
+        .get(i + 1)
+        .unwrap_or_else(|| panic!("{}の値を指定してください", name))
+        .clone();
+    args.drain(i..i + 2);
+    Some(value)
+}
@@ -1,15 +1,516 @@
-#[derive(Clone, Copy, Debug)]
-pub enum Material {
-    Lambertian,
-    Metal,
-    Dielectric,
-    None,
-}
-
-impl Material {
-    pub fn shlick(cosine: f32, ref_idx: f32) -> f32 {
-        let r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
-        let r1 = r0 * r0;
-        r1 + (1.0 - r1) * (1.0 - cosine).powi(5)
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use crate::hitrecord::HitRecord;
+use crate::microfacet::{
+    eval_dielectric, eval_reflection, fresnel_conductor, to_local, Ggx, GgxDielectricPdf,
+    GgxReflectionPdf,
+};
+use crate::onb::Onb;
+use crate::pdf::{CosinePdf, Pdf, UniformSpherePdf};
+use crate::ray::Ray;
+use crate::texture::{SolidColor, Texture};
+use crate::vec3::{color, random_f32, random_unit_vector, reflect, refract, unit_vector, Vec3};
+
+/// 散乱の仕方
+pub enum ScatterRecord {
+    /// 鏡面反射や屈折のように特定の向きにしか散乱しない。減衰と散乱したレイ
+    Specular { attenuation: Vec3, ray: Ray },
+    /// pdfに従って向きを選ぶ。寄与はevalの値をpdfの確率密度で割って求める
+    Diffuse { pdf: Box<dyn Pdf> },
+}
+
+/// 物体の表面でレイがどう散乱するか、どれだけ光を放つかを決める
+pub trait Material: Send + Sync {
+    /// 散乱の仕方を返す。吸収された場合はNone
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord>;
+
+    /// 表面から放たれる光。光源でなければ黒
+    fn emitted(&self, _ray_in: &Ray, _hit_record: &HitRecord) -> Vec3 {
+        color(0.0, 0.0, 0.0)
+    }
+
+    /// scatteredの向きへ散乱する割合(BSDFと余弦の積)。ScatterRecord::Diffuseの場合に使う
+    fn eval(&self, _ray_in: &Ray, _hit_record: &HitRecord, _scattered: &Ray) -> Vec3 {
+        color(0.0, 0.0, 0.0)
+    }
+
+    /// 表面の色。デバッグ表示に使う
+    fn albedo(&self, _hit_record: &HitRecord) -> Vec3 {
+        color(0.0, 0.0, 0.0)
+    }
+
+    /// 霧や煙のように媒質の中で散乱する場合はtrue。当たった点は表面ではないので法線を持たない
+    fn is_volume(&self) -> bool {
+        false
+    }
+
+    /// 散乱の仕方が常にScatterRecord::Specularになる場合はtrue。コースティクスを作る物体を探すのに使う
+    fn is_specular(&self) -> bool {
+        false
+    }
+}
+
+/// 拡散マテリアル
+pub struct Lambertian {
+    albedo: Arc<dyn Texture>,
+}
+
+impl Lambertian {
+    pub fn new(albedo: Vec3) -> Lambertian {
+        Lambertian::with_texture(Arc::new(SolidColor::new(albedo)))
+    }
+
+    pub fn with_texture(albedo: Arc<dyn Texture>) -> Lambertian {
+        Lambertian { albedo }
+    }
+}
+
+impl Material for Lambertian {
+    fn scatter(&self, _ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
+        // 余弦に比例した向きへ拡散する
+        Some(ScatterRecord::Diffuse {
+            pdf: Box::new(CosinePdf::new(hit_record.normal())),
+        })
+    }
+
+    fn eval(&self, _ray_in: &Ray, hit_record: &HitRecord, scattered: &Ray) -> Vec3 {
+        let cosine = hit_record.normal().dot(unit_vector(scattered.direction()));
+        self.albedo
+            .value(hit_record.u(), hit_record.v(), hit_record.point())
+            * (cosine.max(0.0) / PI)
+    }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Vec3 {
+        self.albedo
+            .value(hit_record.u(), hit_record.v(), hit_record.point())
+    }
+}
+
+/// 金属マテリアル
+///
+/// 反射の向きをfuzzの大きさだけランダムにずらしてざらついた金属を表す。
+/// エネルギーが保存されないので、物理的に正しい金属にはConductorを使う
+pub struct Metal {
+    albedo: Vec3,
+    fuzz: f32,
+}
+
+impl Metal {
+    pub fn new(albedo: Vec3, fuzz: f32) -> Metal {
+        Metal { albedo, fuzz }
+    }
+}
+
+impl Material for Metal {
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
+        // 新しい向き先(反射)
+        let target = reflect(unit_vector(ray_in.direction()), hit_record.normal())
+            + random_unit_vector() * self.fuzz;
+        // 跳ね返ったレイ
+        let scattered = Ray::new(hit_record.point(), target, ray_in.time());
+        Some(ScatterRecord::Specular {
+            attenuation: self.albedo,
+            ray: scattered,
+        })
+    }
+
+    fn albedo(&self, _hit_record: &HitRecord) -> Vec3 {
+        self.albedo
+    }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+}
+
+/// 誘電体マテリアル
+///
+/// ref_idxは屈折率(水なら1.333、ガラスなら1.5、ダイヤモンドなら2.42程度)。
+/// absorptionは媒質の単位長さあたりの吸収係数で、内部を進んだ距離に応じて色が濃くなる(Beer–Lambertの法則)
+pub struct Dielectric {
+    ref_idx: f32,
+    absorption: Vec3,
+}
+
+impl Dielectric {
+    pub fn new(ref_idx: f32, absorption: Vec3) -> Dielectric {
+        Dielectric {
+            ref_idx,
+            absorption,
+        }
+    }
+
+    pub fn ref_idx(&self) -> f32 {
+        self.ref_idx
+    }
+
+    /// 媒質の中を通ってきた光の減衰
+    ///
+    /// 裏側から当たった場合は媒質の中を通ってきたので、進んだ距離に応じて吸収される
+    pub(crate) fn attenuation(&self, ray_in: &Ray, hit_record: &HitRecord) -> Vec3 {
+        if hit_record.front_face() {
+            return color(1.0, 1.0, 1.0);
+        }
+        let distance = hit_record.t() * ray_in.direction().length();
+        color(
+            (-self.absorption.x() * distance).exp(),
+            (-self.absorption.y() * distance).exp(),
+            (-self.absorption.z() * distance).exp(),
+        )
+    }
+}
+
+impl Material for Dielectric {
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
+        // 新しい向き先(屈折)
+        let etai_over_etat = if hit_record.front_face() {
+            1.0 / self.ref_idx
+        } else {
+            self.ref_idx
+        };
+        let unit_direction = unit_vector(ray_in.direction());
+
+        let a = -unit_direction.dot(hit_record.normal());
+        let cos_theta = if a < 1.0 { a } else { 1.0 };
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+        let reflect_prob = shlick(cos_theta, etai_over_etat);
+
+        // 全反射する場合は屈折できない
+        let target = if etai_over_etat * sin_theta > 1.0 || random_f32(0.0, 1.0) < reflect_prob {
+            // 反射
+            reflect(unit_direction, hit_record.normal())
+        } else {
+            // 屈折
+            refract(unit_direction, hit_record.normal(), etai_over_etat)
+        };
+        let scattered = Ray::new(hit_record.point(), target, ray_in.time());
+
+        Some(ScatterRecord::Specular {
+            attenuation: self.attenuation(ray_in, hit_record),
+            ray: scattered,
+        })
+    }
+
+    fn albedo(&self, _hit_record: &HitRecord) -> Vec3 {
+        color(1.0, 1.0, 1.0)
+    }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+}
+
+/// 微小面モデル(GGX)の導体(金属)マテリアル
+///
+/// 反射率は複素屈折率eta + ikから入射角ごとに求める。roughnessが0に近い場合は完全な鏡面になる
+pub struct Conductor {
+    eta: Vec3,
+    k: Vec3,
+    ggx: Ggx,
+}
+
+impl Conductor {
+    /// eta、kは赤、緑、青の波長(650nm、550nm、450nm程度)での屈折率と消衰係数
+    pub fn new(eta: Vec3, k: Vec3, roughness: f32) -> Conductor {
+        Conductor {
+            eta,
+            k,
+            ggx: Ggx::from_roughness(roughness),
+        }
+    }
+
+    pub fn gold(roughness: f32) -> Conductor {
+        Conductor::new(
+            color(0.143, 0.374, 1.442),
+            color(3.983, 2.385, 1.603),
+            roughness,
+        )
+    }
+
+    pub fn copper(roughness: f32) -> Conductor {
+        Conductor::new(
+            color(0.200, 0.924, 1.102),
+            color(3.912, 2.452, 2.142),
+            roughness,
+        )
+    }
+
+    pub fn aluminium(roughness: f32) -> Conductor {
+        Conductor::new(
+            color(1.657, 0.880, 0.521),
+            color(9.224, 6.270, 4.837),
+            roughness,
+        )
+    }
+
+    pub fn silver(roughness: f32) -> Conductor {
+        Conductor::new(
+            color(0.155, 0.117, 0.138),
+            color(4.828, 3.122, 2.147),
+            roughness,
+        )
+    }
+}
+
+/// 表面から視点へ向かう向きwoの側を向いた法線
+fn facing_normal(hit_record: &HitRecord, wo: Vec3) -> Vec3 {
+    if hit_record.normal().dot(wo) < 0.0 {
+        -hit_record.normal()
+    } else {
+        hit_record.normal()
+    }
+}
+
+impl Material for Conductor {
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
+        let wo = -unit_vector(ray_in.direction());
+        let normal = facing_normal(hit_record, wo);
+        if self.ggx.is_smooth() {
+            let direction = reflect(-wo, normal);
+            return Some(ScatterRecord::Specular {
+                attenuation: fresnel_conductor(wo.dot(normal), self.eta, self.k),
+                ray: Ray::new(hit_record.point(), direction, ray_in.time()),
+            });
+        }
+        Some(ScatterRecord::Diffuse {
+            pdf: Box::new(GgxReflectionPdf::new(normal, wo, self.ggx)),
+        })
+    }
+
+    fn eval(&self, ray_in: &Ray, hit_record: &HitRecord, scattered: &Ray) -> Vec3 {
+        let wo = -unit_vector(ray_in.direction());
+        let uvw = Onb::from_w(facing_normal(hit_record, wo));
+        eval_reflection(
+            &self.ggx,
+            to_local(&uvw, wo),
+            to_local(&uvw, unit_vector(scattered.direction())),
+            |cosine| fresnel_conductor(cosine, self.eta, self.k),
+        )
+    }
+
+    fn albedo(&self, _hit_record: &HitRecord) -> Vec3 {
+        fresnel_conductor(1.0, self.eta, self.k)
+    }
+
+    fn is_specular(&self) -> bool {
+        self.ggx.is_smooth()
+    }
+}
+
+/// 微小面モデル(GGX)の表面がざらついた誘電体(すりガラス)マテリアル
+///
+/// ref_idxとabsorptionはDielectricと同じ。roughnessが0に近い場合はDielectricと同じ完全な鏡面になる
+pub struct RoughDielectric {
+    /// 完全な鏡面の場合に使う。屈折率と吸収係数もこれから読む
+    smooth: Dielectric,
+    ggx: Ggx,
+}
+
+impl RoughDielectric {
+    pub fn new(ref_idx: f32, roughness: f32, absorption: Vec3) -> RoughDielectric {
+        RoughDielectric {
+            smooth: Dielectric::new(ref_idx, absorption),
+            ggx: Ggx::from_roughness(roughness),
+        }
+    }
+
+    /// woの側を向いた法線と、woの側と反対側の屈折率
+    fn orientation(&self, hit_record: &HitRecord, wo: Vec3) -> (Vec3, f32, f32) {
+        let normal = facing_normal(hit_record, wo);
+        // 外側を向いた法線と同じ側から当たっていれば、外から中へ入る
+        let entering = hit_record.front_face() == (normal.dot(hit_record.normal()) > 0.0);
+        let ref_idx = self.smooth.ref_idx();
+        if entering {
+            (normal, 1.0, ref_idx)
+        } else {
+            (normal, ref_idx, 1.0)
+        }
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
+        if self.ggx.is_smooth() {
+            return self.smooth.scatter(ray_in, hit_record);
+        }
+        let wo = -unit_vector(ray_in.direction());
+        let (normal, eta_i, eta_t) = self.orientation(hit_record, wo);
+        Some(ScatterRecord::Diffuse {
+            pdf: Box::new(GgxDielectricPdf::new(normal, wo, self.ggx, eta_i, eta_t)),
+        })
+    }
+
+    fn eval(&self, ray_in: &Ray, hit_record: &HitRecord, scattered: &Ray) -> Vec3 {
+        let wo = -unit_vector(ray_in.direction());
+        let (normal, eta_i, eta_t) = self.orientation(hit_record, wo);
+        let uvw = Onb::from_w(normal);
+        let f = eval_dielectric(
+            &self.ggx,
+            to_local(&uvw, wo),
+            to_local(&uvw, unit_vector(scattered.direction())),
+            eta_i,
+            eta_t,
+        );
+        self.smooth.attenuation(ray_in, hit_record) * f
+    }
+
+    fn albedo(&self, _hit_record: &HitRecord) -> Vec3 {
+        color(1.0, 1.0, 1.0)
+    }
+
+    fn is_specular(&self) -> bool {
+        self.ggx.is_smooth()
+    }
+}
+
+/// 光を放つ拡散マテリアル。レイは散乱させない
+pub struct DiffuseLight {
+    emit: Arc<dyn Texture>,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Vec3) -> DiffuseLight {
+        DiffuseLight::with_texture(Arc::new(SolidColor::new(emit)))
+    }
+
+    pub fn with_texture(emit: Arc<dyn Texture>) -> DiffuseLight {
+        DiffuseLight { emit }
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _ray_in: &Ray, _hit_record: &HitRecord) -> Option<ScatterRecord> {
+        None
+    }
+
+    fn emitted(&self, _ray_in: &Ray, hit_record: &HitRecord) -> Vec3 {
+        self.emit
+            .value(hit_record.u(), hit_record.v(), hit_record.point())
+    }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Vec3 {
+        self.emit
+            .value(hit_record.u(), hit_record.v(), hit_record.point())
+    }
+}
+
+/// 等方性の位相関数。霧や煙の中でレイをすべての方向へ均等に散乱させる
+pub struct Isotropic {
+    albedo: Arc<dyn Texture>,
+}
+
+impl Isotropic {
+    pub fn new(albedo: Vec3) -> Isotropic {
+        Isotropic::with_texture(Arc::new(SolidColor::new(albedo)))
+    }
+
+    pub fn with_texture(albedo: Arc<dyn Texture>) -> Isotropic {
+        Isotropic { albedo }
+    }
+}
+
+impl Material for Isotropic {
+    fn scatter(&self, _ray_in: &Ray, _hit_record: &HitRecord) -> Option<ScatterRecord> {
+        Some(ScatterRecord::Diffuse {
+            pdf: Box::new(UniformSpherePdf),
+        })
+    }
+
+    fn eval(&self, _ray_in: &Ray, hit_record: &HitRecord, _scattered: &Ray) -> Vec3 {
+        self.albedo
+            .value(hit_record.u(), hit_record.v(), hit_record.point())
+            / (4.0 * PI)
+    }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Vec3 {
+        self.albedo
+            .value(hit_record.u(), hit_record.v(), hit_record.point())
+    }
+
+    fn is_volume(&self) -> bool {
+        true
+    }
+}
+
+/// Schlickの近似による反射率
+pub fn shlick(cosine: f32, ref_idx: f32) -> f32 {
+    let r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
+    let r1 = r0 * r0;
+    r1 + (1.0 - r1) * (1.0 - cosine).powi(5)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::sampler::{with_sampler, Sampler};
+
+    struct SeededSampler(StdRng);
+
+    impl Sampler for SeededSampler {
+        fn next(&mut self) -> f32 {
+            self.0.gen()
+        }
+    }
+
+    /// 一様に明るい環境に置いたとき、向きwoへ反射する光の割合(白色炉テスト)
+    fn directional_albedo(material: &dyn Material, wo: Vec3, samples: usize) -> Vec3 {
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        let point = Vec3::new(0.0, 0.0, 0.0);
+        let hit_record = HitRecord::new(point, normal, true, 1.0, 0.0, 0.0, material);
+        let ray_in = Ray::new(wo, -wo, 0.0);
+        let pdf = match material.scatter(&ray_in, &hit_record) {
+            Some(ScatterRecord::Diffuse { pdf }) => pdf,
+            _ => panic!("微小面のマテリアルはpdfで向きを選ぶ"),
+        };
+        let mut sum = color(0.0, 0.0, 0.0);
+        for _ in 0..samples {
+            let scattered = Ray::new(point, pdf.sample(), 0.0);
+            let pdf_value = pdf.value(scattered.direction());
+            if pdf_value > 0.0 {
+                sum = sum + material.eval(&ray_in, &hit_record, &scattered) / pdf_value;
+            }
+        }
+        sum / samples as f32
+    }
+
+    #[test]
+    fn conductor_presets_do_not_create_energy() {
+        let sampler = Rc::new(RefCell::new(SeededSampler(StdRng::seed_from_u64(5))));
+        with_sampler(sampler, || {
+            for roughness in [0.1, 0.5, 1.0] {
+                let presets = [
+                    ("gold", Conductor::gold(roughness)),
+                    ("copper", Conductor::copper(roughness)),
+                    ("aluminium", Conductor::aluminium(roughness)),
+                    ("silver", Conductor::silver(roughness)),
+                ];
+                for (name, material) in presets {
+                    for theta in [0.0_f32, 45.0, 80.0] {
+                        let theta = theta.to_radians();
+                        let wo = Vec3::new(theta.sin(), 0.0, theta.cos());
+                        let albedo = directional_albedo(&material, wo, 20_000);
+                        // 微小面の間で何度も反射する光は捨てるので1より小さくなるが、越えてはいけない
+                        for c in [albedo.x(), albedo.y(), albedo.z()] {
+                            assert!(
+                                c > 0.0 && c <= 1.0,
+                                "{} roughness: {}, theta: {}, albedo: {:?}",
+                                name,
+                                roughness,
+                                theta.to_degrees(),
+                                albedo
+                            );
+                        }
+                    }
+                }
+            }
+        });
     }
 }
@@ -0,0 +1,434 @@
+use std::f32::consts::PI;
+
+use crate::onb::Onb;
+use crate::pdf::Pdf;
+use crate::vec3::{color, random_f32, refract, unit_vector, Vec3};
+
+/// これより粗さが小さい面は、完全な鏡面として扱う
+pub const MIN_ALPHA: f32 = 1e-3;
+
+/// GGX(Trowbridge–Reitz)の微小面分布
+///
+/// 向きはすべて法線をz軸とする局所座標で表す
+#[derive(Clone, Copy, Debug)]
+pub struct Ggx {
+    alpha: f32,
+}
+
+impl Ggx {
+    /// roughnessは見た目の粗さ(0〜1)。分布の幅alphaはその2乗にする
+    pub fn from_roughness(roughness: f32) -> Ggx {
+        let roughness = roughness.clamp(0.0, 1.0);
+        Ggx {
+            alpha: roughness * roughness,
+        }
+    }
+
+    pub fn alpha(&self) -> f32 {
+        self.alpha
+    }
+
+    /// 完全な鏡面として扱うほど滑らか
+    pub fn is_smooth(&self) -> bool {
+        self.alpha < MIN_ALPHA
+    }
+
+    /// 微小面の法線がhである密度
+    pub fn d(&self, h: Vec3) -> f32 {
+        if h.z() <= 0.0 {
+            return 0.0;
+        }
+        let a2 = self.alpha * self.alpha;
+        let t = h.z() * h.z() * (a2 - 1.0) + 1.0;
+        a2 / (PI * t * t)
+    }
+
+    /// Smithの遮蔽関数の補助関数Λ
+    fn lambda(&self, w: Vec3) -> f32 {
+        let cos2 = w.z() * w.z();
+        if cos2 <= 0.0 {
+            return f32::INFINITY;
+        }
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0) / 2.0
+    }
+
+    /// wの向きから見える微小面の割合
+    pub fn g1(&self, w: Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// woとwiの両方から見える微小面の割合(高さに相関のあるSmithの遮蔽関数)
+    pub fn g(&self, wo: Vec3, wi: Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// woから見える微小面の法線の分布(VNDF)でhを選ぶ確率密度
+    pub fn visible_pdf(&self, wo: Vec3, h: Vec3) -> f32 {
+        if wo.z() <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot(h).max(0.0) * self.d(h) / wo.z()
+    }
+
+    /// woから見える微小面の法線を、一様な乱数(u1, u2)から選ぶ(Heitz 2018)
+    pub fn sample_visible_normal(&self, wo: Vec3, u1: f32, u2: f32) -> Vec3 {
+        // 分布を半球に引き伸ばした空間で考える
+        let vh = unit_vector(Vec3::new(self.alpha * wo.x(), self.alpha * wo.y(), wo.z()));
+        let length_squared = vh.x() * vh.x() + vh.y() * vh.y();
+        let t1 = if length_squared > 0.0 {
+            Vec3::new(-vh.y(), vh.x(), 0.0) / length_squared.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross(t1);
+
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+        let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+
+        unit_vector(Vec3::new(
+            self.alpha * nh.x(),
+            self.alpha * nh.y(),
+            nh.z().max(1e-6),
+        ))
+    }
+}
+
+/// 複素屈折率eta + ikの導体の反射率(偏光していない光)。cos_iは入射角の余弦
+pub fn fresnel_conductor(cos_i: f32, eta: Vec3, k: Vec3) -> Vec3 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let channel = |eta: f32, k: f32| {
+        let cos2 = cos_i * cos_i;
+        let sin2 = 1.0 - cos2;
+        let eta2 = eta * eta;
+        let k2 = k * k;
+        let t0 = eta2 - k2 - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+        let t1 = a2_plus_b2 + cos2;
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+        let t2 = 2.0 * cos_i * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        0.5 * (rp + rs)
+    };
+    color(
+        channel(eta.x(), k.x()),
+        channel(eta.y(), k.y()),
+        channel(eta.z(), k.z()),
+    )
+}
+
+/// 屈折率eta_iの媒質から屈折率eta_tの媒質へ入射する光の反射率。全反射する場合は1
+pub fn fresnel_dielectric(cos_i: f32, eta_i: f32, eta_t: f32) -> f32 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin_t = eta_i / eta_t * (1.0 - cos_i * cos_i).max(0.0).sqrt();
+    if sin_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin_t * sin_t).max(0.0).sqrt();
+    let parallel = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    let perpendicular = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+/// ワールド座標のベクトルを基底uvwの局所座標に直す
+pub fn to_local(uvw: &Onb, a: Vec3) -> Vec3 {
+    Vec3::new(a.dot(uvw.u()), a.dot(uvw.v()), a.dot(uvw.w()))
+}
+
+/// 反射の向きwiに対するハーフベクトル
+fn reflection_half_vector(wo: Vec3, wi: Vec3) -> Option<Vec3> {
+    let h = wo + wi;
+    if h.length_squared() <= 0.0 {
+        return None;
+    }
+    Some(unit_vector(h))
+}
+
+/// 屈折の向きwiに対する一般化したハーフベクトル。法線と同じ側を向ける
+fn refraction_half_vector(wo: Vec3, wi: Vec3, eta_i: f32, eta_t: f32) -> Option<Vec3> {
+    let h = wo * eta_i + wi * eta_t;
+    if h.length_squared() <= 0.0 {
+        return None;
+    }
+    let h = unit_vector(h);
+    Some(if h.z() < 0.0 { -h } else { h })
+}
+
+/// GGXの微小面で反射する面のBRDFと余弦の積。fresnelはハーフベクトルとの角度の余弦から反射率を求める
+pub fn eval_reflection(ggx: &Ggx, wo: Vec3, wi: Vec3, fresnel: impl Fn(f32) -> Vec3) -> Vec3 {
+    if wo.z() <= 0.0 || wi.z() <= 0.0 {
+        return color(0.0, 0.0, 0.0);
+    }
+    let h = match reflection_half_vector(wo, wi) {
+        Some(h) => h,
+        None => return color(0.0, 0.0, 0.0),
+    };
+    fresnel(wo.dot(h)) * (ggx.d(h) * ggx.g(wo, wi) / (4.0 * wo.z()))
+}
+
+/// 屈折率eta_iの側から屈折率eta_tの側へ、GGXの微小面で反射または屈折するBSDFと余弦の積
+pub fn eval_dielectric(ggx: &Ggx, wo: Vec3, wi: Vec3, eta_i: f32, eta_t: f32) -> f32 {
+    if wo.z() <= 0.0 || wi.z() == 0.0 {
+        return 0.0;
+    }
+    if wi.z() > 0.0 {
+        let h = match reflection_half_vector(wo, wi) {
+            Some(h) => h,
+            None => return 0.0,
+        };
+        let f = fresnel_dielectric(wo.dot(h), eta_i, eta_t);
+        return f * ggx.d(h) * ggx.g(wo, wi) / (4.0 * wo.z());
+    }
+
+    let h = match refraction_half_vector(wo, wi, eta_i, eta_t) {
+        Some(h) => h,
+        None => return 0.0,
+    };
+    let wo_h = wo.dot(h);
+    let wi_h = wi.dot(h);
+    // 微小面の表から入って裏へ抜ける場合だけ屈折できる
+    if wo_h <= 0.0 || wi_h >= 0.0 {
+        return 0.0;
+    }
+    let f = fresnel_dielectric(wo_h, eta_i, eta_t);
+    let denom = eta_i * wo_h + eta_t * wi_h;
+    (1.0 - f) * ggx.d(h) * ggx.g(wo, wi) * eta_t * eta_t * (wi_h * wo_h).abs()
+        / (wo.z() * denom * denom)
+}
+
+/// GGXの微小面で反射する向きの分布。woから見える微小面の法線を選んで反射させる
+pub struct GgxReflectionPdf {
+    uvw: Onb,
+    wo: Vec3,
+    ggx: Ggx,
+}
+
+impl GgxReflectionPdf {
+    /// normalは表面の法線、woは表面から視点へ向かう向き(ワールド座標)
+    pub fn new(normal: Vec3, wo: Vec3, ggx: Ggx) -> GgxReflectionPdf {
+        let uvw = Onb::from_w(normal);
+        GgxReflectionPdf {
+            uvw,
+            wo: to_local(&uvw, unit_vector(wo)),
+            ggx,
+        }
+    }
+}
+
+impl Pdf for GgxReflectionPdf {
+    fn value(&self, direction: Vec3) -> f32 {
+        let wi = to_local(&self.uvw, unit_vector(direction));
+        if wi.z() <= 0.0 {
+            return 0.0;
+        }
+        match reflection_half_vector(self.wo, wi) {
+            Some(h) => self.ggx.visible_pdf(self.wo, h) / (4.0 * self.wo.dot(h)),
+            None => 0.0,
+        }
+    }
+
+    fn sample(&self) -> Vec3 {
+        let h = self
+            .ggx
+            .sample_visible_normal(self.wo, random_f32(0.0, 1.0), random_f32(0.0, 1.0));
+        let wi = h * (2.0 * self.wo.dot(h)) - self.wo;
+        self.uvw.local(wi)
+    }
+}
+
+/// GGXの微小面で反射または屈折する向きの分布
+///
+/// woから見える微小面の法線を選び、その微小面での反射率の確率で反射、残りの確率で屈折させる
+pub struct GgxDielectricPdf {
+    uvw: Onb,
+    wo: Vec3,
+    ggx: Ggx,
+    eta_i: f32,
+    eta_t: f32,
+}
+
+impl GgxDielectricPdf {
+    /// normalはwoの側を向いた法線。eta_iはwoの側、eta_tは反対側の屈折率
+    pub fn new(normal: Vec3, wo: Vec3, ggx: Ggx, eta_i: f32, eta_t: f32) -> GgxDielectricPdf {
+        let uvw = Onb::from_w(normal);
+        GgxDielectricPdf {
+            uvw,
+            wo: to_local(&uvw, unit_vector(wo)),
+            ggx,
+            eta_i,
+            eta_t,
+        }
+    }
+}
+
+impl GgxDielectricPdf {
+    /// 微小面で反射してwiへ向かう確率密度
+    fn reflection_value(&self, wi: Vec3) -> f32 {
+        let wo = self.wo;
+        match reflection_half_vector(wo, wi) {
+            Some(h) if wo.dot(h) > 0.0 => {
+                let f = fresnel_dielectric(wo.dot(h), self.eta_i, self.eta_t);
+                f * self.ggx.visible_pdf(wo, h) / (4.0 * wo.dot(h))
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// 微小面で屈折してwiへ向かう確率密度
+    fn refraction_value(&self, wi: Vec3) -> f32 {
+        let wo = self.wo;
+        let h = match refraction_half_vector(wo, wi, self.eta_i, self.eta_t) {
+            Some(h) => h,
+            None => return 0.0,
+        };
+        let wo_h = wo.dot(h);
+        let wi_h = wi.dot(h);
+        if wo_h <= 0.0 || wi_h >= 0.0 {
+            return 0.0;
+        }
+        let f = fresnel_dielectric(wo_h, self.eta_i, self.eta_t);
+        let denom = self.eta_i * wo_h + self.eta_t * wi_h;
+        (1.0 - f) * self.ggx.visible_pdf(wo, h) * self.eta_t * self.eta_t * wi_h.abs()
+            / (denom * denom)
+    }
+}
+
+impl Pdf for GgxDielectricPdf {
+    fn value(&self, direction: Vec3) -> f32 {
+        // 微小面で反射した向きが表面の裏へ潜ることもあるので、どちらの側でも両方の密度を足す
+        let wi = to_local(&self.uvw, unit_vector(direction));
+        self.reflection_value(wi) + self.refraction_value(wi)
+    }
+
+    fn sample(&self) -> Vec3 {
+        let wo = self.wo;
+        let h = self
+            .ggx
+            .sample_visible_normal(wo, random_f32(0.0, 1.0), random_f32(0.0, 1.0));
+        let wo_h = wo.dot(h);
+        let f = fresnel_dielectric(wo_h, self.eta_i, self.eta_t);
+        let wi = if random_f32(0.0, 1.0) < f {
+            h * (2.0 * wo_h) - wo
+        } else {
+            refract(-wo, h, self.eta_i / self.eta_t)
+        };
+        self.uvw.local(wi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::sampler::{with_sampler, Sampler};
+
+    struct SeededSampler(StdRng);
+
+    impl Sampler for SeededSampler {
+        fn next(&mut self) -> f32 {
+            self.0.gen()
+        }
+    }
+
+    /// 球面をcosθとφでそれぞれ等分した、立体角の等しい区画の数
+    const BINS: usize = 16;
+
+    fn bin(direction: Vec3) -> usize {
+        let d = unit_vector(direction);
+        let i = (((d.z() + 1.0) / 2.0 * BINS as f32) as usize).min(BINS - 1);
+        let phi = d.y().atan2(d.x()).rem_euclid(2.0 * PI);
+        let j = ((phi / (2.0 * PI) * BINS as f32) as usize).min(BINS - 1);
+        i * BINS + j
+    }
+
+    /// 区画ごとに、sampleで選ばれた割合と、valueを区画で積分した値を比べる
+    ///
+    /// 微小面で反射した向きが表面の裏へ潜るなど、valueが0の向きは寄与が0になるので捨てる。
+    /// 捨てた割合は、valueを球面全体で積分した値の残りと一致する
+    fn assert_sample_matches_value(pdf: &dyn Pdf, samples: usize) {
+        let sampler = Rc::new(RefCell::new(SeededSampler(StdRng::seed_from_u64(3))));
+        let (counts, discarded) = with_sampler(sampler, || {
+            let mut counts = vec![0; BINS * BINS];
+            let mut discarded = 0;
+            for _ in 0..samples {
+                let direction = pdf.sample();
+                if pdf.value(direction) > 0.0 {
+                    counts[bin(direction)] += 1;
+                } else {
+                    discarded += 1;
+                }
+            }
+            (counts, discarded)
+        });
+
+        let sub = 12;
+        let d_omega = 4.0 * PI / (BINS * BINS * sub * sub) as f32;
+        let mut total = 0.0;
+        for (index, count) in counts.iter().enumerate() {
+            let (i, j) = (index / BINS, index % BINS);
+            let mut expected = 0.0;
+            for a in 0..sub {
+                for b in 0..sub {
+                    let z = -1.0 + 2.0 * ((i * sub + a) as f32 + 0.5) / (BINS * sub) as f32;
+                    let phi = 2.0 * PI * ((j * sub + b) as f32 + 0.5) / (BINS * sub) as f32;
+                    let r = (1.0 - z * z).max(0.0).sqrt();
+                    expected += pdf.value(Vec3::new(r * phi.cos(), r * phi.sin(), z)) * d_omega;
+                }
+            }
+            total += expected;
+            let actual = *count as f32 / samples as f32;
+            assert!(
+                (actual - expected).abs() < 0.001 + 0.03 * expected,
+                "bin: ({}, {}), expected: {}, actual: {}",
+                i,
+                j,
+                expected,
+                actual
+            );
+        }
+        let discarded = discarded as f32 / samples as f32;
+        assert!(
+            (discarded - (1.0 - total)).abs() < 0.01,
+            "discarded: {}, integral: {}",
+            discarded,
+            total
+        );
+    }
+
+    fn local(theta_degrees: f32) -> Vec3 {
+        let theta = theta_degrees.to_radians();
+        Vec3::new(theta.sin(), 0.0, theta.cos())
+    }
+
+    #[test]
+    fn reflection_sample_matches_value() {
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        for (roughness, theta) in [(0.4, 20.0), (0.7, 60.0)] {
+            let pdf = GgxReflectionPdf::new(normal, local(theta), Ggx::from_roughness(roughness));
+            assert_sample_matches_value(&pdf, 200_000);
+        }
+    }
+
+    #[test]
+    fn dielectric_sample_matches_value() {
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        // 外から中へ入る場合と、全反射が起こりうる中から外へ出る場合
+        for (eta_i, eta_t, theta) in [(1.0, 1.5, 30.0), (1.5, 1.0, 35.0)] {
+            let pdf =
+                GgxDielectricPdf::new(normal, local(theta), Ggx::from_roughness(0.6), eta_i, eta_t);
+            assert_sample_matches_value(&pdf, 200_000);
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::f32::consts::PI;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+
+use crate::color::luminance;
+use crate::integrator::Integrator;
+use crate::ray::Ray;
+use crate::sampler::{with_sampler, Sampler};
+use crate::scene::Scene;
+use crate::vec3::{color, random_f32, Vec3};
+
+/// 主標本空間のメトロポリス光輸送(PSSMLT)
+///
+/// integratorが1つの経路を作るのに使う乱数の列を少しずつ変化(変異)させ、
+/// 明るい経路の近くを重点的に調べる。鍵穴から差し込む光や間接光だけで照らされた部屋のように、
+/// 普通のパストレーシングでは光の届く経路がなかなか見つからないシーンに向いている。
+/// integratorは乱数をすべてrandom_f32から取り出す必要がある
+pub struct MetropolisLightTransport {
+    integrator: Box<dyn Integrator>,
+    bootstrap_samples: usize,
+    chains: usize,
+    large_step_probability: f32,
+    sigma: f32,
+}
+
+impl MetropolisLightTransport {
+    /// bootstrap_samplesは画像全体の明るさを見積もるための経路の数、chainsは並行して変異させる乱数の列の数。
+    /// large_step_probabilityは乱数の列をすべて新しく選び直す確率で、それ以外は標準偏差sigmaで少しだけずらす
+    pub fn new(
+        integrator: Box<dyn Integrator>,
+        bootstrap_samples: usize,
+        chains: usize,
+        large_step_probability: f32,
+        sigma: f32,
+    ) -> MetropolisLightTransport {
+        MetropolisLightTransport {
+            integrator,
+            bootstrap_samples,
+            chains,
+            large_step_probability,
+            sigma,
+        }
+    }
+
+    /// samplerの乱数で画像上の位置を選び、その位置から経路を作る
+    fn evaluate(
+        &self,
+        scene: &Scene,
+        width: u32,
+        height: u32,
+        camera_ray: &(dyn Fn(f32, f32) -> Ray + Sync),
+        sampler: &Rc<RefCell<MltSampler>>,
+    ) -> Sample {
+        with_sampler(sampler.clone(), || {
+            let x = random_f32(0.0, width as f32);
+            let y = random_f32(0.0, height as f32);
+            let radiance = self.integrator.li(camera_ray(x, y), scene);
+            // NaNや無限大になった経路は捨てる
+            let radiance =
+                if radiance.x().is_finite() && radiance.y().is_finite() && radiance.z().is_finite()
+                {
+                    radiance
+                } else {
+                    color(0.0, 0.0, 0.0)
+                };
+            let pixel = (y as usize).min(height as usize - 1) * width as usize
+                + (x as usize).min(width as usize - 1);
+            Sample {
+                pixel,
+                radiance,
+                luminance: luminance(radiance),
+            }
+        })
+    }
+}
+
+/// 画像上の1点から作った経路の寄与
+struct Sample {
+    pixel: usize,
+    radiance: Vec3,
+    luminance: f32,
+}
+
+impl Integrator for MetropolisLightTransport {
+    /// 1本のレイだけでは変異させられないので、元のintegratorで求める
+    fn li(&self, ray: Ray, scene: &Scene) -> Vec3 {
+        self.integrator.li(ray, scene)
+    }
+
+    fn render(
+        &self,
+        scene: &Scene,
+        width: u32,
+        height: u32,
+        samples_per_pixel: u32,
+        camera_ray: &(dyn Fn(f32, f32) -> Ray + Sync),
+    ) -> Option<Vec<Vec3>> {
+        let pixel_count = (width * height) as usize;
+        let new_sampler = |seed: u64| {
+            Rc::new(RefCell::new(MltSampler::new(
+                seed,
+                self.sigma,
+                self.large_step_probability,
+            )))
+        };
+
+        // ブートストラップ。ランダムな経路の明るさの平均から画像全体の明るさを見積もる
+        let weights: Vec<f32> = (0..self.bootstrap_samples as u64)
+            .into_par_iter()
+            .map(|seed| {
+                self.evaluate(scene, width, height, camera_ray, &new_sampler(seed))
+                    .luminance
+            })
+            .collect();
+        let total_weight: f32 = weights.iter().sum();
+        if total_weight <= 0.0 {
+            return Some(vec![color(0.0, 0.0, 0.0); pixel_count]);
+        }
+        let brightness = total_weight / self.bootstrap_samples as f32;
+
+        // 明るさに比例した確率でブートストラップの経路を選ぶための累積分布
+        let cdf: Vec<f32> = weights
+            .iter()
+            .scan(0.0, |sum, weight| {
+                *sum += weight / total_weight;
+                Some(*sum)
+            })
+            .collect();
+
+        let total_mutations = samples_per_pixel as usize * pixel_count;
+        let chains = self.chains.clamp(1, total_mutations.max(1));
+        let mutations_per_chain = total_mutations / chains;
+
+        let film = (0..chains)
+            .into_par_iter()
+            .fold(
+                || vec![color(0.0, 0.0, 0.0); pixel_count],
+                |mut film, chain| {
+                    let mut rng = StdRng::seed_from_u64(chain_seed(chain));
+
+                    // ブートストラップの経路を乱数の列ごと再現して出発点にする
+                    let u: f32 = rng.gen();
+                    let seed = cdf.partition_point(|&c| c < u).min(cdf.len() - 1) as u64;
+                    let sampler = new_sampler(seed);
+                    let mut current = self.evaluate(scene, width, height, camera_ray, &sampler);
+
+                    for _ in 0..mutations_per_chain {
+                        sampler.borrow_mut().start_iteration();
+                        let proposed = self.evaluate(scene, width, height, camera_ray, &sampler);
+                        let accept = if current.luminance > 0.0 {
+                            (proposed.luminance / current.luminance).min(1.0)
+                        } else {
+                            1.0
+                        };
+
+                        // 採択される確率で重みを分けて、両方の経路を画像に足す
+                        if accept > 0.0 && proposed.luminance > 0.0 {
+                            film[proposed.pixel] = film[proposed.pixel]
+                                + proposed.radiance * (accept / proposed.luminance);
+                        }
+                        if current.luminance > 0.0 {
+                            film[current.pixel] = film[current.pixel]
+                                + current.radiance * ((1.0 - accept) / current.luminance);
+                        }
+
+                        if rng.gen::<f32>() < accept {
+                            current = proposed;
+                            sampler.borrow_mut().accept();
+                        } else {
+                            sampler.borrow_mut().reject();
+                        }
+                    }
+                    film
+                },
+            )
+            .reduce(
+                || vec![color(0.0, 0.0, 0.0); pixel_count],
+                |a, b| a.iter().zip(&b).map(|(a, b)| *a + *b).collect(),
+            );
+
+        let scale = brightness * pixel_count as f32 / (chains * mutations_per_chain) as f32;
+        Some(film.into_iter().map(|c| c * scale).collect())
+    }
+}
+
+/// 連鎖の採択判定に使う乱数のシード。ブートストラップの経路のシード(0から順に振る)とは別の列から取る
+fn chain_seed(chain: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (chain, 0xC4A1_u32).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// 乱数の列の1つの値
+#[derive(Clone, Copy, Default)]
+struct PrimarySample {
+    value: f32,
+    /// 最後に変異させた繰り返しの番号
+    last_modification: u64,
+    /// 変異が棄却されたときに戻す値
+    value_backup: f32,
+    modify_backup: u64,
+}
+
+/// 変異させながら乱数の列を返すSampler
+///
+/// 値は取り出されるときに、前回取り出されてから行われたはずの変異をまとめて適用する
+struct MltSampler {
+    rng: StdRng,
+    sigma: f32,
+    large_step_probability: f32,
+    samples: Vec<PrimarySample>,
+    current_iteration: u64,
+    large_step: bool,
+    last_large_step_iteration: u64,
+    index: usize,
+}
+
+impl MltSampler {
+    /// seedが同じなら最初の繰り返しでは同じ乱数の列を返す
+    fn new(seed: u64, sigma: f32, large_step_probability: f32) -> MltSampler {
+        MltSampler {
+            rng: StdRng::seed_from_u64(seed),
+            sigma,
+            large_step_probability,
+            samples: Vec::new(),
+            current_iteration: 0,
+            large_step: true,
+            last_large_step_iteration: 0,
+            index: 0,
+        }
+    }
+
+    /// 次の変異を始める
+    fn start_iteration(&mut self) {
+        self.current_iteration += 1;
+        self.large_step = self.rng.gen::<f32>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step_iteration = self.current_iteration;
+        }
+    }
+
+    /// 今回の変異で変えた値を元に戻す
+    fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.last_modification == self.current_iteration {
+                sample.value = sample.value_backup;
+                sample.last_modification = sample.modify_backup;
+            }
+        }
+        self.current_iteration -= 1;
+    }
+
+    fn ensure_ready(&mut self, index: usize) {
+        if index >= self.samples.len() {
+            self.samples.resize(index + 1, PrimarySample::default());
+        }
+        let sample = &mut self.samples[index];
+
+        // 最後に採択された大きな変異より前の値は、その時点で選び直されている
+        if sample.last_modification < self.last_large_step_iteration {
+            sample.value = self.rng.gen();
+            sample.last_modification = self.last_large_step_iteration;
+        }
+
+        sample.value_backup = sample.value;
+        sample.modify_backup = sample.last_modification;
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // n回分の小さな変異は、標準偏差をsqrt(n)倍した1回の変異と同じ
+            let n = (self.current_iteration - sample.last_modification) as f32;
+            let sigma = self.sigma * n.sqrt();
+            // Box–Muller法で正規分布に従う値を作る
+            let u1: f32 = 1.0 - self.rng.gen::<f32>();
+            let u2: f32 = self.rng.gen();
+            let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+            sample.value += normal * sigma;
+            sample.value -= sample.value.floor();
+            if sample.value >= 1.0 {
+                sample.value = 0.0;
+            }
+        }
+        sample.last_modification = self.current_iteration;
+    }
+}
+
+impl Sampler for MltSampler {
+    fn next(&mut self) -> f32 {
+        let index = self.index;
+        self.index += 1;
+        self.ensure_ready(index);
+        self.samples[index].value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(sampler: &MltSampler) -> Vec<(f32, u64)> {
+        sampler
+            .samples
+            .iter()
+            .map(|sample| (sample.value, sample.last_modification))
+            .collect()
+    }
+
+    fn take(sampler: &mut MltSampler, count: usize) -> Vec<f32> {
+        (0..count).map(|_| sampler.next()).collect()
+    }
+
+    #[test]
+    fn same_seed_replays_the_bootstrap_sequence() {
+        let mut bootstrap = MltSampler::new(7, 0.01, 0.3);
+        let mut replay = MltSampler::new(7, 0.01, 0.3);
+        let expected = take(&mut bootstrap, 32);
+        assert_eq!(take(&mut replay, 32), expected);
+        assert_ne!(take(&mut MltSampler::new(8, 0.01, 0.3), 32), expected);
+    }
+
+    #[test]
+    fn reject_restores_mutated_values() {
+        // 小さな変異だけの場合と、大きな変異だけの場合
+        for large_step_probability in [0.0, 1.0] {
+            let mut sampler = MltSampler::new(3, 0.01, large_step_probability);
+            take(&mut sampler, 16);
+            sampler.start_iteration();
+            take(&mut sampler, 16);
+            sampler.accept();
+            let before = values(&sampler);
+
+            // 一部の値だけ変異させて棄却すると、すべての値と繰り返しの番号が元に戻る
+            sampler.start_iteration();
+            let mutated = take(&mut sampler, 10);
+            assert_ne!(
+                mutated,
+                before[..10]
+                    .iter()
+                    .map(|(value, _)| *value)
+                    .collect::<Vec<_>>()
+            );
+            sampler.reject();
+            assert_eq!(values(&sampler), before);
+            assert_eq!(sampler.current_iteration, 1);
+
+            // 採択した場合は変異した値が残る
+            sampler.start_iteration();
+            let accepted = take(&mut sampler, 16);
+            sampler.accept();
+            let after: Vec<f32> = values(&sampler).iter().map(|(value, _)| *value).collect();
+            assert_eq!(after, accepted);
+        }
+    }
+}
@@ -0,0 +1,615 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::item::{MeshData, TriangleMesh};
+use crate::material::{Dielectric, DiffuseLight, Lambertian, Material, Metal};
+use crate::texture::ImageTexture;
+use crate::vec3::{color, Vec3};
+
+/// OBJ/MTLファイルの読み込みに失敗した理由
+#[derive(Debug)]
+pub enum ObjError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// map_Kdで指定された画像を読み込めなかった
+    Texture {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    /// 書式の誤り。lineは1から数えた行番号
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            ObjError::Texture { path, source } => write!(f, "{}: {}", path.display(), source),
+            ObjError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ObjError::Io { source, .. } => Some(source),
+            ObjError::Texture { source, .. } => Some(source),
+            ObjError::Parse { .. } => None,
+        }
+    }
+}
+
+/// MTLファイルに書かれたマテリアルの値
+#[derive(Clone, Debug)]
+pub struct MtlMaterial {
+    pub name: String,
+    /// 拡散反射色
+    pub kd: Vec3,
+    /// 鏡面反射色
+    pub ks: Vec3,
+    /// 鏡面反射の指数
+    pub ns: f32,
+    /// 屈折率
+    pub ni: f32,
+    /// 不透明度
+    pub d: f32,
+    /// 放射色
+    pub ke: Vec3,
+    /// 照明モデル
+    pub illum: u32,
+    /// 拡散反射色のテクスチャ画像
+    pub map_kd: Option<PathBuf>,
+}
+
+impl MtlMaterial {
+    fn new(name: &str) -> MtlMaterial {
+        MtlMaterial {
+            name: String::from(name),
+            kd: color(0.8, 0.8, 0.8),
+            ks: color(0.0, 0.0, 0.0),
+            ns: 0.0,
+            ni: 1.0,
+            d: 1.0,
+            ke: color(0.0, 0.0, 0.0),
+            illum: 2,
+            map_kd: None,
+        }
+    }
+
+    /// レンダラーのマテリアルに変換する
+    ///
+    /// 放射色があれば光源、透明なら誘電体、鏡面反射が主なら金属、それ以外は拡散マテリアルとする。
+    /// 拡散マテリアルはmap_Kdがあれば画像テクスチャを使う
+    pub fn to_material(&self) -> Result<Arc<dyn Material>, ObjError> {
+        let is_black = |c: Vec3| c.x() <= 0.0 && c.y() <= 0.0 && c.z() <= 0.0;
+        Ok(if !is_black(self.ke) {
+            Arc::new(DiffuseLight::new(self.ke))
+        } else if self.d < 1.0 {
+            let ni = if self.ni > 1.0 { self.ni } else { 1.5 };
+            Arc::new(Dielectric::new(ni, color(0.0, 0.0, 0.0)))
+        } else if self.illum == 3 || (is_black(self.kd) && !is_black(self.ks)) {
+            // Phongの指数から粗さを近似する
+            let fuzz = (2.0 / (self.ns + 2.0)).sqrt();
+            Arc::new(Metal::new(self.ks, fuzz))
+        } else if let Some(path) = &self.map_kd {
+            let texture = ImageTexture::open(path).map_err(|source| ObjError::Texture {
+                path: path.clone(),
+                source,
+            })?;
+            Arc::new(Lambertian::with_texture(Arc::new(texture)))
+        } else {
+            Arc::new(Lambertian::new(self.kd))
+        })
+    }
+}
+
+/// OBJファイルを読み込み、グループとマテリアルの組ごとに三角形メッシュを作る
+///
+/// mtllibで指定されたMTLファイルはOBJファイルと同じディレクトリから探す
+pub fn load_obj(path: &Path) -> Result<Vec<TriangleMesh>, ObjError> {
+    parse_obj(&read_to_string(path)?, path)
+}
+
+/// OBJファイルの内容textを読む。pathはエラーの表示とmtllibのファイルを探すのに使う
+fn parse_obj(text: &str, path: &Path) -> Result<Vec<TriangleMesh>, ObjError> {
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    let default_material: Arc<dyn Material> = Arc::new(Lambertian::new(color(0.73, 0.73, 0.73)));
+
+    let mut positions: Vec<Vec3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<[f32; 2]> = Vec::new();
+    let mut materials: HashMap<String, Arc<dyn Material>> = HashMap::new();
+
+    let mut builders: Vec<MeshBuilder> = Vec::new();
+    let mut group = String::new();
+    let mut material_name: Option<String> = None;
+    let mut current: Option<usize> = None;
+
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: String| ObjError::Parse {
+            path: path.to_path_buf(),
+            line: number + 1,
+            message,
+        };
+        let mut tokens = strip_comment(line).split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+
+        match keyword {
+            "v" => positions.push(parse_vec3(&args).map_err(error)?),
+            "vn" => normals.push(parse_vec3(&args).map_err(error)?),
+            "vt" => {
+                if args.is_empty() {
+                    return Err(error(String::from("vtにはUV座標が必要です")));
+                }
+                let u = parse_f32(args[0]).map_err(error)?;
+                let v = match args.get(1) {
+                    Some(v) => parse_f32(v).map_err(error)?,
+                    None => 0.0,
+                };
+                uvs.push([u, v]);
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(error(format!(
+                        "面には3つ以上の頂点が必要です({}個)",
+                        args.len()
+                    )));
+                }
+                let corners = args
+                    .iter()
+                    .map(|arg| {
+                        parse_corner(arg, positions.len(), uvs.len(), normals.len()).map_err(error)
+                    })
+                    .collect::<Result<Vec<Corner>, ObjError>>()?;
+
+                let index = match current {
+                    Some(index) => index,
+                    None => {
+                        let material = match &material_name {
+                            Some(name) => materials.get(name).cloned().ok_or_else(|| {
+                                error(format!("マテリアル{}が定義されていません", name))
+                            })?,
+                            None => default_material.clone(),
+                        };
+                        builders.push(MeshBuilder::new(material));
+                        current = Some(builders.len() - 1);
+                        builders.len() - 1
+                    }
+                };
+                builders[index].add_polygon(&corners, &positions, &normals, &uvs);
+            }
+            "g" | "o" => {
+                let name = args.join(" ");
+                if name != group {
+                    group = name;
+                    current = None;
+                }
+            }
+            "usemtl" => {
+                let name = args.join(" ");
+                if name.is_empty() {
+                    return Err(error(String::from("usemtlにはマテリアル名が必要です")));
+                }
+                if material_name.as_deref() != Some(name.as_str()) {
+                    material_name = Some(name);
+                    current = None;
+                }
+            }
+            "mtllib" => {
+                if args.is_empty() {
+                    return Err(error(String::from("mtllibにはファイル名が必要です")));
+                }
+                for file in &args {
+                    for mtl in load_mtl(&base_dir.join(file))? {
+                        materials.insert(mtl.name.clone(), mtl.to_material()?);
+                    }
+                }
+            }
+            // スムージンググループなど、描画に使わない指定は読み飛ばす
+            _ => {}
+        }
+    }
+
+    Ok(builders
+        .into_iter()
+        .filter(|builder| !builder.indices.is_empty())
+        .map(|builder| TriangleMesh::new(builder.build()))
+        .collect())
+}
+
+/// MTLファイルを読み込む
+pub fn load_mtl(path: &Path) -> Result<Vec<MtlMaterial>, ObjError> {
+    parse_mtl(&read_to_string(path)?, path)
+}
+
+/// MTLファイルの内容textを読む。pathはエラーの表示とテクスチャ画像を探すのに使う
+fn parse_mtl(text: &str, path: &Path) -> Result<Vec<MtlMaterial>, ObjError> {
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    let mut result: Vec<MtlMaterial> = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: String| ObjError::Parse {
+            path: path.to_path_buf(),
+            line: number + 1,
+            message,
+        };
+        let mut tokens = strip_comment(line).split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+
+        if keyword == "newmtl" {
+            if args.is_empty() {
+                return Err(error(String::from("newmtlにはマテリアル名が必要です")));
+            }
+            result.push(MtlMaterial::new(&args.join(" ")));
+            continue;
+        }
+
+        let material = match result.last_mut() {
+            Some(material) => material,
+            None => return Err(error(format!("newmtlより前に{}があります", keyword))),
+        };
+        match keyword {
+            "Kd" => material.kd = parse_vec3(&args).map_err(error)?,
+            "Ks" => material.ks = parse_vec3(&args).map_err(error)?,
+            "Ke" => material.ke = parse_vec3(&args).map_err(error)?,
+            "Ns" => material.ns = parse_single(&args).map_err(error)?,
+            "Ni" => material.ni = parse_single(&args).map_err(error)?,
+            "d" => material.d = parse_single(&args).map_err(error)?,
+            // Trは透明度なので不透明度に直す
+            "Tr" => material.d = 1.0 - parse_single(&args).map_err(error)?,
+            "illum" => {
+                material.illum = parse_single(&args).map_err(error)? as u32;
+            }
+            "map_Kd" => {
+                // オプションは読み飛ばし、最後の引数をファイル名とする
+                let file = args
+                    .last()
+                    .ok_or_else(|| error(String::from("map_Kdにはファイル名が必要です")))?;
+                material.map_kd = Some(base_dir.join(file));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(result)
+}
+
+fn read_to_string(path: &Path) -> Result<String, ObjError> {
+    fs::read_to_string(path).map_err(|source| ObjError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find('#') {
+        Some(index) => &line[..index],
+        None => line,
+    }
+}
+
+fn parse_f32(s: &str) -> Result<f32, String> {
+    s.parse::<f32>()
+        .map_err(|_| format!("{}は数値ではありません", s))
+}
+
+fn parse_single(args: &[&str]) -> Result<f32, String> {
+    match args.first() {
+        Some(s) => parse_f32(s),
+        None => Err(String::from("値がありません")),
+    }
+}
+
+fn parse_vec3(args: &[&str]) -> Result<Vec3, String> {
+    if args.len() < 3 {
+        return Err(format!("3つの数値が必要です({}個)", args.len()));
+    }
+    Ok(Vec3::new(
+        parse_f32(args[0])?,
+        parse_f32(args[1])?,
+        parse_f32(args[2])?,
+    ))
+}
+
+/// 面の頂点。それぞれ0から数えた頂点座標、UV座標、法線のインデックス
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Corner {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+/// "v"、"v/vt"、"v//vn"、"v/vt/vn"の形式の頂点を読む。負のインデックスはそれまでに定義された要素の末尾から数える
+fn parse_corner(
+    s: &str,
+    position_count: usize,
+    uv_count: usize,
+    normal_count: usize,
+) -> Result<Corner, String> {
+    let mut parts = s.split('/');
+    let position = resolve_index(parts.next().unwrap_or(""), position_count, "頂点")?;
+    let uv = match parts.next() {
+        Some("") | None => None,
+        Some(part) => Some(resolve_index(part, uv_count, "UV座標")?),
+    };
+    let normal = match parts.next() {
+        Some("") | None => None,
+        Some(part) => Some(resolve_index(part, normal_count, "法線")?),
+    };
+    if parts.next().is_some() {
+        return Err(format!("{}は頂点の書式ではありません", s));
+    }
+    Ok(Corner {
+        position,
+        uv,
+        normal,
+    })
+}
+
+fn resolve_index(s: &str, count: usize, kind: &str) -> Result<usize, String> {
+    let index = s
+        .parse::<i64>()
+        .map_err(|_| format!("{}は{}のインデックスではありません", s, kind))?;
+    let resolved = if index > 0 {
+        index - 1
+    } else {
+        count as i64 + index
+    };
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!(
+            "{}のインデックス{}が範囲外です({}個)",
+            kind, index, count
+        ));
+    }
+    Ok(resolved as usize)
+}
+
+/// 1つのメッシュに含まれる頂点を集める。同じ組み合わせの頂点は使いまわす
+struct MeshBuilder {
+    material: Arc<dyn Material>,
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<[f32; 2]>,
+    indices: Vec<[u32; 3]>,
+    vertex_map: HashMap<Corner, u32>,
+    has_normals: bool,
+    has_uvs: bool,
+}
+
+impl MeshBuilder {
+    fn new(material: Arc<dyn Material>) -> MeshBuilder {
+        MeshBuilder {
+            material,
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            indices: Vec::new(),
+            vertex_map: HashMap::new(),
+            has_normals: true,
+            has_uvs: true,
+        }
+    }
+
+    fn vertex(
+        &mut self,
+        corner: Corner,
+        positions: &[Vec3],
+        normals: &[Vec3],
+        uvs: &[[f32; 2]],
+    ) -> u32 {
+        if let Some(index) = self.vertex_map.get(&corner) {
+            return *index;
+        }
+        let index = self.positions.len() as u32;
+        self.positions.push(positions[corner.position]);
+        match corner.normal {
+            Some(n) => self.normals.push(normals[n]),
+            None => {
+                self.has_normals = false;
+                self.normals.push(Vec3::new(0.0, 0.0, 0.0));
+            }
+        }
+        match corner.uv {
+            Some(uv) => self.uvs.push(uvs[uv]),
+            None => {
+                self.has_uvs = false;
+                self.uvs.push([0.0, 0.0]);
+            }
+        }
+        self.vertex_map.insert(corner, index);
+        index
+    }
+
+    /// 多角形は最初の頂点を中心に扇形に三角形へ分割する
+    fn add_polygon(
+        &mut self,
+        corners: &[Corner],
+        positions: &[Vec3],
+        normals: &[Vec3],
+        uvs: &[[f32; 2]],
+    ) {
+        let first = self.vertex(corners[0], positions, normals, uvs);
+        for i in 1..corners.len() - 1 {
+            let b = self.vertex(corners[i], positions, normals, uvs);
+            let c = self.vertex(corners[i + 1], positions, normals, uvs);
+            self.indices.push([first, b, c]);
+        }
+    }
+
+    /// 法線やUV座標は、すべての頂点が持っている場合だけ使う
+    fn build(self) -> MeshData {
+        let normals = if self.has_normals {
+            Some(self.normals)
+        } else {
+            None
+        };
+        let uvs = if self.has_uvs { Some(self.uvs) } else { None };
+        MeshData::new(self.positions, self.indices, normals, uvs, self.material)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Vec<TriangleMesh>, ObjError> {
+        parse_obj(text, Path::new("test.obj"))
+    }
+
+    fn parse_error_line(result: Result<Vec<TriangleMesh>, ObjError>) -> usize {
+        match result {
+            Err(ObjError::Parse { line, .. }) => line,
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn polygons_are_split_into_fans() {
+        let meshes = parse(
+            "v 0 0 0\n\
+             v 1 0 0\n\
+             v 1 1 0\n\
+             v 0 1 0\n\
+             v 0 2 0\n\
+             f 1 2 3\n\
+             f 1 2 3 4 5\n",
+        )
+        .unwrap();
+        assert_eq!(meshes.len(), 1);
+        let mesh = meshes[0].mesh();
+        assert_eq!(mesh.indices().len(), 4);
+        assert_eq!(mesh.positions().len(), 5);
+        // 扇形の分割は最初の頂点を共有する
+        for triangle in &mesh.indices()[1..] {
+            assert_eq!(triangle[0], mesh.indices()[1][0]);
+        }
+    }
+
+    #[test]
+    fn negative_indices_count_from_the_end() {
+        let meshes = parse(
+            "v 0 0 0\n\
+             v 1 0 0\n\
+             v 0 1 0\n\
+             vt 0 0\n\
+             vt 1 0\n\
+             vt 0 1\n\
+             vn 0 0 1\n\
+             f -3/-3/-1 -2/-2/-1 -1/-1/-1\n",
+        )
+        .unwrap();
+        let mesh = meshes[0].mesh();
+        assert_eq!(mesh.indices(), &[[0, 1, 2]]);
+        assert_eq!(mesh.positions()[1].x(), 1.0);
+        assert_eq!(mesh.uvs().unwrap()[2], [0.0, 1.0]);
+        assert_eq!(mesh.normals().unwrap()[0].z(), 1.0);
+    }
+
+    #[test]
+    fn normals_are_dropped_unless_every_corner_has_one() {
+        let meshes = parse(
+            "v 0 0 0\n\
+             v 1 0 0\n\
+             v 0 1 0\n\
+             v 1 1 0\n\
+             vn 0 0 1\n\
+             f 1//1 2//1 3//1\n\
+             f 2 4 3\n",
+        )
+        .unwrap();
+        let mesh = meshes[0].mesh();
+        assert_eq!(mesh.indices().len(), 2);
+        assert!(mesh.normals().is_none());
+        assert!(mesh.uvs().is_none());
+
+        let meshes = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+        assert!(meshes[0].mesh().normals().is_none());
+    }
+
+    #[test]
+    fn groups_and_materials_split_meshes() {
+        let meshes = parse(
+            "v 0 0 0\n\
+             v 1 0 0\n\
+             v 0 1 0\n\
+             g a\n\
+             f 1 2 3\n\
+             g b\n\
+             f 1 2 3\n\
+             f 1 3 2\n",
+        )
+        .unwrap();
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[1].mesh().indices().len(), 2);
+    }
+
+    #[test]
+    fn errors_report_the_line_number() {
+        // コメントと空行も行として数える
+        let text = "# comment\n\nv 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n";
+        assert_eq!(parse_error_line(parse(text)), 6);
+        assert_eq!(parse_error_line(parse("v 0 0\n")), 1);
+        assert_eq!(parse_error_line(parse("v 0 0 0\nf 1 1\n")), 2);
+        assert_eq!(parse_error_line(parse("v 0 0 0\nv 1 0 x\n")), 2);
+        assert_eq!(
+            parse_error_line(parse("v 0 0 0\nusemtl missing\nf 1 1 1\n")),
+            3
+        );
+        assert_eq!(parse_error_line(parse("v 0 0 0\nf 0 1 1\n")), 2);
+        assert_eq!(parse_error_line(parse("v 0 0 0\nf -2 1 1\n")), 2);
+    }
+
+    #[test]
+    fn mtl_values_are_read() {
+        let materials = parse_mtl(
+            "newmtl glass\n\
+             Kd 0.1 0.2 0.3\n\
+             Ni 1.45\n\
+             Tr 0.75\n\
+             newmtl lamp\n\
+             Ke 4 4 4\n\
+             illum 1\n",
+            Path::new("test.mtl"),
+        )
+        .unwrap();
+        assert_eq!(materials.len(), 2);
+        assert_eq!(materials[0].name, "glass");
+        assert_eq!(materials[0].kd.y(), 0.2);
+        assert_eq!(materials[0].ni, 1.45);
+        assert_eq!(materials[0].d, 0.25);
+        assert_eq!(materials[1].ke.x(), 4.0);
+        assert_eq!(materials[1].illum, 1);
+    }
+
+    #[test]
+    fn mtl_errors_report_the_line_number() {
+        let result = parse_mtl("newmtl a\nKd 1 1\n", Path::new("test.mtl"));
+        assert!(matches!(result, Err(ObjError::Parse { line: 2, .. })));
+        let result = parse_mtl("\nKd 1 1 1\n", Path::new("test.mtl"));
+        assert!(matches!(result, Err(ObjError::Parse { line: 2, .. })));
+    }
+}
@@ -0,0 +1,42 @@
+use crate::vec3::{unit_vector, Vec3};
+
+/// 正規直交基底(Orthonormal Basis)。wを軸とする局所座標系
+#[derive(Clone, Copy, Debug)]
+pub struct Onb {
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Onb {
+    /// nをw軸とする基底を作る
+    pub fn from_w(n: Vec3) -> Onb {
+        let w = unit_vector(n);
+        // wと平行でない軸を選んで残りの2軸を作る
+        let a = if w.x().abs() > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let v = unit_vector(w.cross(a));
+        let u = w.cross(v);
+        Onb { u, v, w }
+    }
+
+    pub fn u(&self) -> Vec3 {
+        self.u
+    }
+
+    pub fn v(&self) -> Vec3 {
+        self.v
+    }
+
+    pub fn w(&self) -> Vec3 {
+        self.w
+    }
+
+    /// 局所座標(a, b, c)をワールド座標のベクトルに直す
+    pub fn local(&self, a: Vec3) -> Vec3 {
+        self.u * a.x() + self.v * a.y() + self.w * a.z()
+    }
+}
@@ -0,0 +1,143 @@
+use std::f32::consts::PI;
+
+use crate::hittable::Hittable;
+use crate::onb::Onb;
+use crate::vec3::{
+    random_cosine_direction, random_f32, random_to_sphere, random_unit_vector, unit_vector, Vec3,
+};
+
+/// 向きの確率密度関数(立体角あたり)
+pub trait Pdf {
+    /// directionの向きの確率密度
+    fn value(&self, direction: Vec3) -> f32;
+
+    /// この分布に従うランダムな向き
+    fn sample(&self) -> Vec3;
+}
+
+/// 法線を天頂とする半球上で、余弦に比例する分布
+pub struct CosinePdf {
+    uvw: Onb,
+}
+
+impl CosinePdf {
+    pub fn new(normal: Vec3) -> CosinePdf {
+        CosinePdf {
+            uvw: Onb::from_w(normal),
+        }
+    }
+}
+
+impl Pdf for CosinePdf {
+    fn value(&self, direction: Vec3) -> f32 {
+        let cosine = unit_vector(direction).dot(self.uvw.w());
+        cosine.max(0.0) / PI
+    }
+
+    fn sample(&self) -> Vec3 {
+        self.uvw.local(random_cosine_direction())
+    }
+}
+
+/// 全方向に一様な分布
+pub struct UniformSpherePdf;
+
+impl Pdf for UniformSpherePdf {
+    fn value(&self, _direction: Vec3) -> f32 {
+        1.0 / (4.0 * PI)
+    }
+
+    fn sample(&self) -> Vec3 {
+        random_unit_vector()
+    }
+}
+
+/// originから見て球が見える範囲(円錐)の立体角の中で一様な分布
+pub struct SphereSolidAnglePdf {
+    uvw: Onb,
+    distance_squared: f32,
+    radius: f32,
+    cos_theta_max: f32,
+}
+
+impl SphereSolidAnglePdf {
+    pub fn new(origin: Vec3, center: Vec3, radius: f32) -> SphereSolidAnglePdf {
+        let direction = center - origin;
+        let distance_squared = direction.length_squared();
+        SphereSolidAnglePdf {
+            uvw: Onb::from_w(direction),
+            distance_squared,
+            radius,
+            cos_theta_max: (1.0 - radius * radius / distance_squared).max(0.0).sqrt(),
+        }
+    }
+}
+
+impl Pdf for SphereSolidAnglePdf {
+    fn value(&self, direction: Vec3) -> f32 {
+        // 円錐の外側の向きは選ばれない
+        if unit_vector(direction).dot(self.uvw.w()) < self.cos_theta_max {
+            return 0.0;
+        }
+        1.0 / (2.0 * PI * (1.0 - self.cos_theta_max))
+    }
+
+    fn sample(&self) -> Vec3 {
+        self.uvw
+            .local(random_to_sphere(self.radius, self.distance_squared))
+    }
+}
+
+/// originから物体(光源)の表面上の点を狙う分布
+pub struct HittablePdf<'a> {
+    objects: &'a dyn Hittable,
+    origin: Vec3,
+    time: f32,
+}
+
+impl<'a> HittablePdf<'a> {
+    pub fn new(objects: &'a dyn Hittable, origin: Vec3, time: f32) -> HittablePdf<'a> {
+        HittablePdf {
+            objects,
+            origin,
+            time,
+        }
+    }
+}
+
+impl Pdf for HittablePdf<'_> {
+    fn value(&self, direction: Vec3) -> f32 {
+        self.objects.pdf_value(self.origin, direction, self.time)
+    }
+
+    fn sample(&self) -> Vec3 {
+        self.objects.random(self.origin, self.time)
+    }
+}
+
+/// 2つの分布を重みweight : 1 - weightで混ぜた分布
+pub struct MixturePdf<'a> {
+    p0: &'a dyn Pdf,
+    p1: &'a dyn Pdf,
+    weight: f32,
+}
+
+impl<'a> MixturePdf<'a> {
+    pub fn new(p0: &'a dyn Pdf, p1: &'a dyn Pdf, weight: f32) -> MixturePdf<'a> {
+        MixturePdf { p0, p1, weight }
+    }
+}
+
+impl Pdf for MixturePdf<'_> {
+    fn value(&self, direction: Vec3) -> f32 {
+        self.weight * self.p0.value(direction) + (1.0 - self.weight) * self.p1.value(direction)
+    }
+
+    fn sample(&self) -> Vec3 {
+        if random_f32(0.0, 1.0) < self.weight {
+            self.p0.sample()
+        } else {
+            self.p1.sample()
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::vec3::{unit_vector, Vec3};
+
+const POINT_COUNT: usize = 256;
+
+/// パーリンノイズ。格子点にランダムな勾配ベクトルを置き、エルミート補間で三線形補間する
+///
+/// 同じシードからは同じ模様が作られる
+#[derive(Clone)]
+pub struct Perlin {
+    gradients: Vec<Vec3>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Perlin {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let gradients = (0..POINT_COUNT)
+            .map(|_| {
+                unit_vector(Vec3::new(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                ))
+            })
+            .collect();
+        let perm_x = generate_perm(&mut rng);
+        let perm_y = generate_perm(&mut rng);
+        let perm_z = generate_perm(&mut rng);
+        Perlin {
+            gradients,
+            perm_x,
+            perm_y,
+            perm_z,
+        }
+    }
+
+    /// 位置pにおけるノイズ。おおよそ-1〜1の値になる
+    pub fn noise(&self, p: Vec3) -> f32 {
+        let u = p.x() - p.x().floor();
+        let v = p.y() - p.y().floor();
+        let w = p.z() - p.z().floor();
+        let i = p.x().floor() as i64;
+        let j = p.y().floor() as i64;
+        let k = p.z().floor() as i64;
+
+        let mut c = [[[Vec3::new(0.0, 0.0, 0.0); 2]; 2]; 2];
+        for (di, plane) in c.iter_mut().enumerate() {
+            for (dj, row) in plane.iter_mut().enumerate() {
+                for (dk, gradient) in row.iter_mut().enumerate() {
+                    let index = self.perm_x[((i + di as i64) & 255) as usize]
+                        ^ self.perm_y[((j + dj as i64) & 255) as usize]
+                        ^ self.perm_z[((k + dk as i64) & 255) as usize];
+                    *gradient = self.gradients[index];
+                }
+            }
+        }
+
+        trilinear_interp(&c, u, v, w)
+    }
+
+    /// 周波数を倍にしながらdepth回ノイズを重ねた乱流。0以上の値になる
+    pub fn turbulence(&self, p: Vec3, depth: u32) -> f32 {
+        let mut accum = 0.0;
+        let mut temp_p = p;
+        let mut weight = 1.0;
+        for _ in 0..depth {
+            accum += weight * self.noise(temp_p);
+            weight *= 0.5;
+            temp_p = temp_p * 2.0;
+        }
+        accum.abs()
+    }
+}
+
+fn generate_perm(rng: &mut StdRng) -> Vec<usize> {
+    let mut p: Vec<usize> = (0..POINT_COUNT).collect();
+    for i in (1..POINT_COUNT).rev() {
+        let target = rng.gen_range(0..=i);
+        p.swap(i, target);
+    }
+    p
+}
+
+fn trilinear_interp(c: &[[[Vec3; 2]; 2]; 2], u: f32, v: f32, w: f32) -> f32 {
+    // エルミート補間で格子の境界をなめらかにする
+    let uu = u * u * (3.0 - 2.0 * u);
+    let vv = v * v * (3.0 - 2.0 * v);
+    let ww = w * w * (3.0 - 2.0 * w);
+
+    let mut accum = 0.0;
+    for (i, plane) in c.iter().enumerate() {
+        for (j, row) in plane.iter().enumerate() {
+            for (k, gradient) in row.iter().enumerate() {
+                let (fi, fj, fk) = (i as f32, j as f32, k as f32);
+                let weight = Vec3::new(u - fi, v - fj, w - fk);
+                accum += (fi * uu + (1.0 - fi) * (1.0 - uu))
+                    * (fj * vv + (1.0 - fj) * (1.0 - vv))
+                    * (fk * ww + (1.0 - fk) * (1.0 - ww))
+                    * gradient.dot(weight);
+            }
+        }
+    }
+    accum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points() -> Vec<Vec3> {
+        (0..64)
+            .map(|i| {
+                let i = i as f32;
+                Vec3::new(0.37 * i - 5.0, 1.3 * (0.5 * i).sin(), 0.11 * i * i - 3.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_noise() {
+        let a = Perlin::new(42);
+        let b = Perlin::new(42);
+        for p in points() {
+            assert_eq!(a.noise(p), b.noise(p));
+            assert_eq!(a.turbulence(p, 7), b.turbulence(p, 7));
+        }
+    }
+
+    #[test]
+    fn different_seeds_give_different_noise() {
+        let a = Perlin::new(1);
+        let b = Perlin::new(2);
+        let differs = points()
+            .iter()
+            .filter(|&&p| (a.noise(p) - b.noise(p)).abs() > 1e-3)
+            .count();
+        assert!(differs > 48, "違う値になった点: {}", differs);
+    }
+}
@@ -0,0 +1,354 @@
+use std::f32::consts::PI;
+
+use rayon::join;
+use rayon::prelude::*;
+
+use crate::aabb::Aabb;
+use crate::hitrecord::HitRecord;
+use crate::hittable::Hittable;
+use crate::light::Light;
+use crate::material::ScatterRecord;
+use crate::pdf::{CosinePdf, Pdf};
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::vec3::{color, origin, random_f32, unit_vector, Vec3};
+
+/// これより多くのフォトンを含む部分木は別スレッドで構築する
+const PARALLEL_THRESHOLD: usize = 4096;
+/// 漸進的フォトンマッピングで半径を縮める割合
+const ALPHA: f32 = 2.0 / 3.0;
+
+/// 物体の表面に届いた光の粒
+#[derive(Clone, Copy)]
+pub struct Photon {
+    position: Vec3,
+    /// フォトンが進んできた向き(単位ベクトル)
+    direction: Vec3,
+    /// フォトンが運ぶ光の量(放射束)
+    power: Vec3,
+}
+
+/// フォトンをkd木に並べたもの
+///
+/// 各区間の中央のフォトンが節になり、その左右に座標axes[i]で分けた部分木が並ぶ
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+    radius: f32,
+}
+
+impl PhotonMap {
+    /// 密度を推定するときはradius以内のフォトンを集める
+    pub fn new(mut photons: Vec<Photon>, radius: f32) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        PhotonMap {
+            photons,
+            axes,
+            radius,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    /// pointからradius以内にあるフォトンを順に渡す
+    fn for_each_in_radius(&self, point: Vec3, mut f: impl FnMut(&Photon)) {
+        let radius_squared = self.radius * self.radius;
+        let mut stack = vec![(0, self.photons.len())];
+        while let Some((lo, hi)) = stack.pop() {
+            if lo >= hi {
+                continue;
+            }
+            let mid = (lo + hi) / 2;
+            let photon = &self.photons[mid];
+            if (photon.position - point).length_squared() <= radius_squared {
+                f(photon);
+            }
+
+            // pointがある側は必ず調べ、反対側は分割面までの距離が半径以内の場合だけ調べる
+            let axis = self.axes[mid] as usize;
+            let d = point[axis] - photon.position[axis];
+            let (near, far) = if d <= 0.0 {
+                ((lo, mid), (mid + 1, hi))
+            } else {
+                ((mid + 1, hi), (lo, mid))
+            };
+            if d * d <= radius_squared {
+                stack.push(far);
+            }
+            stack.push(near);
+        }
+    }
+
+    /// hit_recordの点からray_inの逆向きに反射される光を、周りのフォトンの密度から推定する
+    pub fn estimate(&self, ray_in: &Ray, hit_record: &HitRecord) -> Vec3 {
+        let material = hit_record.material();
+        let mut sum = color(0.0, 0.0, 0.0);
+        self.for_each_in_radius(hit_record.point(), |photon| {
+            let to_light = -photon.direction;
+            let cosine = hit_record.normal().dot(to_light);
+            if cosine <= 0.0 {
+                // 裏側から届いたフォトン
+                return;
+            }
+            // evalは余弦を含むので割って取り除く
+            let f = material.eval(
+                ray_in,
+                hit_record,
+                &Ray::new(hit_record.point(), to_light, ray_in.time()),
+            ) / cosine;
+            sum = sum + f * photon.power;
+        });
+        sum / (PI * self.radius * self.radius)
+    }
+}
+
+/// 区間の中央値で分けながら再帰的にkd木を作る。分ける座標は広がりが最も大きい軸
+fn build(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.len() <= 1 {
+        return;
+    }
+
+    let (min, max) = photons.iter().fold(
+        (photons[0].position, photons[0].position),
+        |(min, max), photon| {
+            let p = photon.position;
+            (
+                Vec3::new(min.x().min(p.x()), min.y().min(p.y()), min.z().min(p.z())),
+                Vec3::new(max.x().max(p.x()), max.y().max(p.y()), max.z().max(p.z())),
+            )
+        },
+    );
+    let extent = max - min;
+    let axis = if extent.x() > extent.y() && extent.x() > extent.z() {
+        0
+    } else if extent.y() > extent.z() {
+        1
+    } else {
+        2
+    };
+
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| {
+        a.position[axis]
+            .partial_cmp(&b.position[axis])
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    axes[mid] = axis as u8;
+
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    let right = &mut right[1..];
+    let right_axes = &mut right_axes[1..];
+    if left.len() + right.len() > PARALLEL_THRESHOLD {
+        join(|| build(left, left_axes), || build(right, right_axes));
+    } else {
+        build(left, left_axes);
+        build(right, right_axes);
+    }
+}
+
+/// 集光模様(コースティクス)のためのフォトンマップ
+///
+/// 光る物体、物体とは別の光源、環境から放ったフォトンのうち、鏡面反射や屈折を1回以上してから拡散面に届いたものだけを記録する。
+/// passes個のフォトンマップを、半径を少しずつ縮めながら作っておき(確率的漸進的フォトンマッピング)、
+/// 推定のたびにどれか1つを選んで使う。passesを増やすほどぼけが少なくなる
+pub struct CausticPhotonMaps {
+    maps: Vec<PhotonMap>,
+}
+
+impl CausticPhotonMaps {
+    /// 1つのフォトンマップにつき光源からphoton_count個のフォトンを放つ。radiusは最初のフォトンマップの半径
+    pub fn new(
+        scene: &Scene,
+        photon_count: usize,
+        radius: f32,
+        passes: usize,
+        max_depth: u32,
+    ) -> CausticPhotonMaps {
+        let mut maps = Vec::with_capacity(passes);
+        let mut radius_squared = radius * radius;
+        for i in 1..=passes {
+            let photons = trace_caustic_photons(scene, photon_count, max_depth);
+            maps.push(PhotonMap::new(photons, radius_squared.sqrt()));
+            radius_squared *= (i as f32 + ALPHA) / (i as f32 + 1.0);
+        }
+        CausticPhotonMaps { maps }
+    }
+
+    pub fn maps(&self) -> &[PhotonMap] {
+        &self.maps
+    }
+
+    /// ランダムに選んだフォトンマップで推定する
+    pub fn estimate(&self, ray_in: &Ray, hit_record: &HitRecord) -> Vec3 {
+        if self.maps.is_empty() {
+            return color(0.0, 0.0, 0.0);
+        }
+        let index =
+            ((random_f32(0.0, 1.0) * self.maps.len() as f32) as usize).min(self.maps.len() - 1);
+        self.maps[index].estimate(ray_in, hit_record)
+    }
+}
+
+/// 光源からcount個のフォトンを放ち、鏡面反射や屈折を経て拡散面に届いたものを返す
+///
+/// 光る物体、物体とは別の光源、環境のうち1つを同じ確率で選んでフォトンを放つ
+fn trace_caustic_photons(scene: &Scene, count: usize, max_depth: u32) -> Vec<Photon> {
+    let camera = scene.camera();
+    let emissive_area = scene.lights().area(camera.time0());
+    let light_sources: Vec<&dyn Light> = scene
+        .light_sources()
+        .iter()
+        .map(|light| light.as_ref())
+        .chain(scene.environment_light())
+        .collect();
+    let source_count = light_sources.len() + usize::from(emissive_area > 0.0);
+    if source_count == 0 || count == 0 {
+        return Vec::new();
+    }
+    let (center, radius, distance) = infinite_light_target(scene);
+
+    (0..count)
+        .into_par_iter()
+        .filter_map(|_| {
+            let time = random_f32(camera.time0(), camera.time1());
+            let index =
+                ((random_f32(0.0, 1.0) * source_count as f32) as usize).min(source_count - 1);
+            let (ray, power) = match light_sources.get(index) {
+                Some(light) => {
+                    let sample = light.sample_emission(center, radius, distance)?;
+                    (
+                        Ray::new(sample.origin(), sample.direction(), time),
+                        sample.power(),
+                    )
+                }
+                None => emit_from_emissive_objects(scene, emissive_area, time)?,
+            };
+            let power = power * (source_count as f32 / count as f32);
+            if power.x() <= 0.0 && power.y() <= 0.0 && power.z() <= 0.0 {
+                return None;
+            }
+            trace_photon(scene, ray, power, max_depth)
+        })
+        .collect()
+}
+
+/// 光る物体の上の点と光を出す向きを選び、そのレイと放つ光の量を確率密度で割ったものを返す
+fn emit_from_emissive_objects(scene: &Scene, area: f32, time: f32) -> Option<(Ray, Vec3)> {
+    let record = scene.lights().sample_surface(time)?;
+
+    // 両面のどちらかを選んで、余弦に比例した向きに光を出す
+    let normal = record.normal();
+    let side = if random_f32(0.0, 1.0) < 0.5 {
+        normal
+    } else {
+        -normal
+    };
+    let direction = CosinePdf::new(side).sample();
+    let cosine = normal.dot(unit_vector(direction)).abs();
+    let pdf_dir = cosine / (2.0 * PI);
+    if pdf_dir <= 0.0 {
+        return None;
+    }
+    let le = record.material().emitted(
+        &Ray::new(record.point() + direction, -direction, time),
+        &record,
+    );
+    Some((
+        Ray::new(record.point(), direction, time),
+        le * (cosine * area / pdf_dir),
+    ))
+}
+
+/// コースティクスを作る可能性のある物体をまとめて囲むボックス。そのような物体がなければNone
+///
+/// コースティクスのフォトンは最初に鏡面反射や屈折をする物体に当たる必要があるので、
+/// そうでないと分かっている物体(表面のマテリアルが鏡面でないもの)を除いて囲む。
+/// 境界ボックスを持たない物体は含めない
+pub fn caustic_casters(scene: &Scene) -> Option<Aabb> {
+    let camera = scene.camera();
+    scene
+        .world()
+        .bvh()
+        .primitives()
+        .iter()
+        .filter(|primitive| {
+            primitive
+                .sample_surface(camera.time0())
+                .is_none_or(|record| record.material().is_specular())
+        })
+        .filter_map(|primitive| primitive.bounding_box(camera.time0(), camera.time1()))
+        .reduce(Aabb::surrounding_box)
+}
+
+/// 無限遠の光源がフォトンを放つ先の球の中心と半径、および円板を置く中心からの距離
+///
+/// 球はコースティクスを作る物体を囲み、円板はシーン全体の外に置くので、他の物体にさえぎられる分も正しく扱える
+fn infinite_light_target(scene: &Scene) -> (Vec3, f32, f32) {
+    let camera = scene.camera();
+    let bounding_sphere = |bbox: Aabb| {
+        (
+            (bbox.min() + bbox.max()) * 0.5,
+            (bbox.max() - bbox.min()).length() * 0.5,
+        )
+    };
+    let scene_bounds = scene
+        .world()
+        .bvh()
+        .bounding_box(camera.time0(), camera.time1());
+    let (scene_bounds, casters) = match (scene_bounds, caustic_casters(scene)) {
+        (Some(scene_bounds), Some(casters)) => (scene_bounds, casters),
+        _ => return (origin(), 0.0, 0.0),
+    };
+    let (center, radius) = bounding_sphere(casters);
+    let (scene_center, scene_radius) = bounding_sphere(scene_bounds);
+    (
+        center,
+        radius,
+        (center - scene_center).length() + scene_radius + radius,
+    )
+}
+
+/// 1つのフォトンを追跡する。鏡面反射か屈折を1回以上してから拡散面に当たった場合だけ記録する
+fn trace_photon(scene: &Scene, ray: Ray, power: Vec3, max_depth: u32) -> Option<Photon> {
+    let mut ray = ray;
+    let mut power = power;
+    let mut specular_bounces = 0;
+
+    for _ in 0..max_depth {
+        let record = scene.world().hit(&ray, 0.001, f32::INFINITY, ray.time())?;
+        let material = record.material();
+        match material.scatter(&ray, &record)? {
+            ScatterRecord::Specular {
+                attenuation,
+                ray: scattered,
+            } => {
+                power = power * attenuation;
+                ray = scattered;
+                specular_bounces += 1;
+            }
+            ScatterRecord::Diffuse { .. } => {
+                if specular_bounces == 0 || material.is_volume() {
+                    return None;
+                }
+                return Some(Photon {
+                    position: record.point(),
+                    direction: unit_vector(ray.direction()),
+                    power,
+                });
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,42 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rand::Rng;
+
+/// 0〜1の一様乱数の出どころ
+pub trait Sampler {
+    /// [0, 1)の一様乱数
+    fn next(&mut self) -> f32;
+}
+
+thread_local! {
+    /// このスレッドで乱数の代わりに使うSampler
+    static CURRENT: RefCell<Option<Rc<RefCell<dyn Sampler>>>> = const { RefCell::new(None) };
+}
+
+/// fを実行する間、このスレッドのrandom_f32はsamplerから値を取り出す
+///
+/// 同じ値を返すsamplerで実行し直せば、同じ経路を再現できる
+pub fn with_sampler<R>(sampler: Rc<RefCell<dyn Sampler>>, f: impl FnOnce() -> R) -> R {
+    /// fがパニックした場合も元のSamplerに戻す
+    struct Restore(Option<Rc<RefCell<dyn Sampler>>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            CURRENT.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+
+    let _restore = Restore(CURRENT.with(|current| current.replace(Some(sampler))));
+    f()
+}
+
+/// [0, 1)の一様乱数。with_samplerで差し替えられていなければthread_rngから取り出す
+pub fn next_f32() -> f32 {
+    let sampler = CURRENT.with(|current| current.borrow().clone());
+    match sampler {
+        Some(sampler) => sampler.borrow_mut().next(),
+        None => rand::thread_rng().gen::<f32>(),
+    }
+}
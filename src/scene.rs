@@ -1,29 +1,206 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
 use crate::{
+    aabb::Aabb,
+    camera::Camera,
+    environment::Environment,
+    flat_bvh::FlatBvh,
+    hitrecord::HitRecord,
     hittable::Hittable,
-    item::Sphere,
-    material::Material,
-    vec3::{color, random_color, random_color_range, random_f32, Vec3},
+    item::{Cuboid, MeshData, Plane, Sphere, TriangleMesh, XyRect, XzRect, YzRect},
+    light::{DirectionalLight, Light, PointLight, QuadLight, SphereLight, SpotLight},
+    material::{Conductor, Dielectric, DiffuseLight, Lambertian, Material, Metal, RoughDielectric},
+    obj::{load_obj, ObjError},
+    ray::Ray,
+    sky::{PhysicalSky, SkySettings},
+    texture::{
+        CheckerTexture, ImageTexture, MarbleTexture, NoiseTexture, Texture, TurbulenceTexture,
+        WoodTexture,
+    },
+    transform::{Matrix, Transform},
+    vec3::{color, random_color, random_color_range, random_f32, unit_vector, Vec3},
+    volume::{ConstantMedium, DensityGrid, HeterogeneousMedium},
 };
 
-pub struct Scene {}
+/// シーン中のすべての物体。境界ボックスを持つ物体はBVHにまとめ、無限に広がる物体は別に調べる
+pub struct World {
+    bvh: FlatBvh<Box<dyn Hittable>>,
+    unbounded: Vec<Box<dyn Hittable>>,
+}
+
+impl World {
+    pub fn new(objects: Vec<Box<dyn Hittable>>, time0: f32, time1: f32) -> World {
+        let (bounded, unbounded): (Vec<_>, Vec<_>) = objects
+            .into_iter()
+            .partition(|object| object.bounding_box(time0, time1).is_some());
+        World {
+            bvh: FlatBvh::new(bounded, time0, time1),
+            unbounded,
+        }
+    }
+
+    pub fn bvh(&self) -> &FlatBvh<Box<dyn Hittable>> {
+        &self.bvh
+    }
+}
+
+impl Hittable for World {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord<'_>> {
+        let hit_bvh = self.bvh.hit(ray, tmin, tmax, time);
+        let tmax = hit_bvh.as_ref().map_or(tmax, |record| record.t());
+        self.unbounded.hit(ray, tmin, tmax, time).or(hit_bvh)
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        if self.unbounded.is_empty() {
+            self.bvh.bounding_box(time0, time1)
+        } else {
+            None
+        }
+    }
+}
+
+/// カメラ、物体、光源、環境をまとめたもの
+///
+/// lightsは直接サンプリングする光る物体。光る物体はworldにも含まれている必要がある。
+/// light_sourcesは物体とは別に置く光源で、worldには含めない
+pub struct Scene {
+    camera: Camera,
+    world: World,
+    lights: Vec<Box<dyn Hittable>>,
+    light_sources: Vec<Box<dyn Light>>,
+    environment: Environment,
+}
 
 impl Scene {
-    pub fn random_scene() -> Vec<Box<dyn Hittable>> {
+    /// 境界ボックスはカメラのシャッターが開いている時間で求める
+    pub fn new(camera: Camera, objects: Vec<Box<dyn Hittable>>, environment: Environment) -> Scene {
+        let world = World::new(objects, camera.time0(), camera.time1());
+        Scene {
+            camera,
+            world,
+            lights: Vec::new(),
+            light_sources: Vec::new(),
+            environment,
+        }
+    }
+
+    /// 直接サンプリングする光源を指定する
+    ///
+    /// 光源の表面から点を選ぶので、どれも面積を持つ必要がある(軸ごとに拡大率の違うTransformは使えない)
+    pub fn with_lights(mut self, lights: Vec<Arc<dyn Hittable>>) -> Scene {
+        let time = self.camera.time0();
+        assert!(
+            lights.iter().all(|light| light.area(time) > 0.0),
+            "光源には表面積を求められる物体を指定してください"
+        );
+        self.lights = lights
+            .into_iter()
+            .map(|light| Box::new(light) as Box<dyn Hittable>)
+            .collect();
+        self
+    }
+
+    /// 物体とは別の光源を置く
+    pub fn with_light_sources(mut self, light_sources: Vec<Box<dyn Light>>) -> Scene {
+        self.light_sources = light_sources;
+        self
+    }
+
+    /// 環境を置き換える
+    pub fn with_environment(mut self, environment: Environment) -> Scene {
+        self.environment = environment;
+        self
+    }
+
+    /// 背景を空のモデルに置き換え、太陽を光源に加える
+    pub fn with_physical_sky(mut self, sky: &PhysicalSky) -> Scene {
+        self.environment = Environment::image(sky.environment_map(512, 256));
+        self.light_sources.push(Box::new(sky.sun()));
+        self
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    pub fn lights(&self) -> &Vec<Box<dyn Hittable>> {
+        &self.lights
+    }
+
+    pub fn light_sources(&self) -> &Vec<Box<dyn Light>> {
+        &self.light_sources
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    /// シーンを照らす環境の光。光源と同じように直接サンプリングする
+    pub fn environment_light(&self) -> Option<&dyn Light> {
+        if self.environment.lighting() && !self.environment.is_black() {
+            Some(&self.environment)
+        } else {
+            None
+        }
+    }
+
+    /// 名前からシーンを作る。存在しない名前の場合はNone
+    pub fn by_name(name: &str, aspect_ratio: f32) -> Option<Scene> {
+        match name {
+            "random" => Some(Scene::random_scene(aspect_ratio)),
+            "simple_light" => Some(Scene::simple_light(aspect_ratio)),
+            "cornell_box" => Some(Scene::cornell_box(aspect_ratio)),
+            "cornell_smoke" => Some(Scene::cornell_smoke(aspect_ratio)),
+            "mesh" => Some(Scene::mesh(aspect_ratio)),
+            "textures" => Some(Scene::textures(aspect_ratio)),
+            "perlin" => Some(Scene::perlin(aspect_ratio)),
+            "volumes" => Some(Scene::volumes(aspect_ratio)),
+            "caustics" => Some(Scene::caustics(aspect_ratio)),
+            "light_types" => Some(Scene::light_types(aspect_ratio)),
+            "sky" => Some(Scene::sky(&SkySettings::default(), aspect_ratio)),
+            "microfacet" => Some(Scene::microfacet(aspect_ratio)),
+            _ => None,
+        }
+    }
+
+    pub fn random_scene(aspect_ratio: f32) -> Scene {
+        // カメラ
+        let camera = Camera::new(
+            Vec3::new(12.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            25.0,
+            aspect_ratio,
+            0.05,
+            10.0,
+            0.0,
+            10.0,
+        );
+
         // 物体を配置
         let mut scene: Vec<Box<dyn Hittable>> = Vec::new();
 
         // 地面
+        let ground: Arc<dyn Material> = Arc::new(Lambertian::new(color(0.5, 0.5, 0.5)));
         scene.push(Box::new(Sphere::new(
             Vec3::new(0.0, -1000.0, 0.0),
             Vec3::new(0.0, -1000.0, 0.0),
             1000.0,
-            Material::Lambertian,
-            color(0.5, 0.5, 0.5),
-            0.0,
+            ground,
             0.0,
             1.0,
         )));
 
+        // ガラスはすべての球で同じマテリアルを共有する
+        let glass: Arc<dyn Material> = Arc::new(Dielectric::new(1.5, color(0.0, 0.0, 0.0)));
+
         for a in -11..11 {
             for b in -11..11 {
                 let choose_mat = random_f32(0.0, 1.0);
@@ -41,9 +218,7 @@ impl Scene {
                             center,
                             center,
                             0.2,
-                            Material::Lambertian,
-                            albedo,
-                            0.0,
+                            Arc::new(Lambertian::new(albedo)),
                             0.0,
                             1.0,
                         )));
@@ -54,9 +229,7 @@ impl Scene {
                             center,
                             center + Vec3::new(0.0, random_f32(0.0, 0.5), 0.0),
                             0.2,
-                            Material::Lambertian,
-                            albedo,
-                            0.0,
+                            Arc::new(Lambertian::new(albedo)),
                             0.0,
                             10.0,
                         )));
@@ -67,9 +240,7 @@ impl Scene {
                             center,
                             center,
                             0.2,
-                            Material::Metal,
-                            albedo,
-                            random_f32(0.0, 0.5),
+                            Arc::new(Metal::new(albedo, random_f32(0.0, 0.5))),
                             0.0,
                             1.0,
                         )));
@@ -79,9 +250,7 @@ impl Scene {
                             center,
                             center,
                             0.2,
-                            Material::Dielectric,
-                            color(1.0, 1.0, 1.0),
-                            0.0,
+                            glass.clone(),
                             0.0,
                             1.0,
                         )));
@@ -94,9 +263,7 @@ impl Scene {
             Vec3::new(0.0, 1.0, 0.0),
             Vec3::new(0.0, 1.0, 0.0),
             1.0,
-            Material::Dielectric,
-            color(1.0, 1.0, 1.0),
-            0.0,
+            glass,
             0.0,
             1.0,
         )));
@@ -104,9 +271,7 @@ impl Scene {
             Vec3::new(-4.0, 1.0, 0.0),
             Vec3::new(-4.0, 1.0, 0.0),
             1.0,
-            Material::Lambertian,
-            color(0.4, 0.2, 0.1),
-            0.0,
+            Arc::new(Lambertian::new(color(0.4, 0.2, 0.1))),
             0.0,
             1.0,
         )));
@@ -114,13 +279,760 @@ impl Scene {
             Vec3::new(4.0, 1.0, 0.0),
             Vec3::new(4.0, 1.0, 0.0),
             1.0,
-            Material::Metal,
-            color(0.7, 0.6, 0.5),
+            Arc::new(Metal::new(color(0.7, 0.6, 0.5), 0.0)),
+            0.0,
+            1.0,
+        )));
+
+        Scene::new(camera, scene, Environment::sky())
+    }
+
+    /// 背景を暗くして、光る球と長方形の光源だけで照らしたシーン
+    pub fn simple_light(aspect_ratio: f32) -> Scene {
+        let camera = Camera::new(
+            Vec3::new(26.0, 3.0, 6.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            20.0,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let light: Arc<dyn Material> = Arc::new(DiffuseLight::new(color(4.0, 4.0, 4.0)));
+        let sphere_light: Arc<dyn Hittable> = Arc::new(Sphere::new(
+            Vec3::new(0.0, 7.0, 0.0),
+            Vec3::new(0.0, 7.0, 0.0),
+            2.0,
+            light.clone(),
+            0.0,
+            1.0,
+        ));
+        let rect_light: Arc<dyn Hittable> = Arc::new(XyRect::new(3.0, 5.0, 1.0, 3.0, -2.0, light));
+        let scene: Vec<Box<dyn Hittable>> = vec![
+            // 地面
+            Box::new(Plane::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Arc::new(Lambertian::new(color(0.5, 0.5, 0.5))),
+            )),
+            Box::new(Sphere::new(
+                Vec3::new(0.0, 2.0, 0.0),
+                Vec3::new(0.0, 2.0, 0.0),
+                2.0,
+                Arc::new(Lambertian::new(color(0.4, 0.2, 0.1))),
+                0.0,
+                1.0,
+            )),
+            // 光源
+            Box::new(sphere_light.clone()),
+            Box::new(rect_light.clone()),
+        ];
+
+        Scene::new(camera, scene, Environment::black()).with_lights(vec![sphere_light, rect_light])
+    }
+
+    /// コーネルボックス。天井の光源だけで照らす
+    pub fn cornell_box(aspect_ratio: f32) -> Scene {
+        let camera = Camera::new(
+            Vec3::new(278.0, 278.0, -800.0),
+            Vec3::new(278.0, 278.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            40.0,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let red: Arc<dyn Material> = Arc::new(Lambertian::new(color(0.65, 0.05, 0.05)));
+        let white: Arc<dyn Material> = Arc::new(Lambertian::new(color(0.73, 0.73, 0.73)));
+        let green: Arc<dyn Material> = Arc::new(Lambertian::new(color(0.12, 0.45, 0.15)));
+        let light: Arc<dyn Material> = Arc::new(DiffuseLight::new(color(15.0, 15.0, 15.0)));
+
+        let ceiling_light: Arc<dyn Hittable> =
+            Arc::new(XzRect::new(213.0, 343.0, 227.0, 332.0, 554.0, light));
+
+        let scene: Vec<Box<dyn Hittable>> = vec![
+            // 壁
+            Box::new(YzRect::new(0.0, 555.0, 0.0, 555.0, 555.0, green)),
+            Box::new(YzRect::new(0.0, 555.0, 0.0, 555.0, 0.0, red)),
+            // 天井の光源
+            Box::new(ceiling_light.clone()),
+            // 床、天井、奥の壁
+            Box::new(XzRect::new(0.0, 555.0, 0.0, 555.0, 0.0, white.clone())),
+            Box::new(XzRect::new(0.0, 555.0, 0.0, 555.0, 555.0, white.clone())),
+            Box::new(XyRect::new(0.0, 555.0, 0.0, 555.0, 555.0, white.clone())),
+            // 箱。原点に置いた直方体を回転してから移動する
+            Box::new(Transform::new(
+                Arc::new(Cuboid::new(
+                    Vec3::new(0.0, 0.0, 0.0),
+                    Vec3::new(165.0, 330.0, 165.0),
+                    white.clone(),
+                )),
+                Matrix::translation(Vec3::new(265.0, 0.0, 295.0)) * Matrix::rotation_y(15.0),
+            )),
+            Box::new(Transform::new(
+                Arc::new(Cuboid::new(
+                    Vec3::new(0.0, 0.0, 0.0),
+                    Vec3::new(165.0, 165.0, 165.0),
+                    white,
+                )),
+                Matrix::translation(Vec3::new(130.0, 0.0, 65.0)) * Matrix::rotation_y(-18.0),
+            )),
+        ];
+
+        Scene::new(camera, scene, Environment::black()).with_lights(vec![ceiling_light])
+    }
+
+    /// コーネルボックスの箱を黒と白の煙に置き換えたもの
+    pub fn cornell_smoke(aspect_ratio: f32) -> Scene {
+        let camera = Camera::new(
+            Vec3::new(278.0, 278.0, -800.0),
+            Vec3::new(278.0, 278.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            40.0,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let red: Arc<dyn Material> = Arc::new(Lambertian::new(color(0.65, 0.05, 0.05)));
+        let white: Arc<dyn Material> = Arc::new(Lambertian::new(color(0.73, 0.73, 0.73)));
+        let green: Arc<dyn Material> = Arc::new(Lambertian::new(color(0.12, 0.45, 0.15)));
+        let light: Arc<dyn Material> = Arc::new(DiffuseLight::new(color(7.0, 7.0, 7.0)));
+
+        let box1: Arc<dyn Hittable> = Arc::new(Transform::new(
+            Arc::new(Cuboid::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(165.0, 330.0, 165.0),
+                white.clone(),
+            )),
+            Matrix::translation(Vec3::new(265.0, 0.0, 295.0)) * Matrix::rotation_y(15.0),
+        ));
+        let box2: Arc<dyn Hittable> = Arc::new(Transform::new(
+            Arc::new(Cuboid::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(165.0, 165.0, 165.0),
+                white.clone(),
+            )),
+            Matrix::translation(Vec3::new(130.0, 0.0, 65.0)) * Matrix::rotation_y(-18.0),
+        ));
+
+        let ceiling_light: Arc<dyn Hittable> =
+            Arc::new(XzRect::new(113.0, 443.0, 127.0, 432.0, 554.0, light));
+
+        let scene: Vec<Box<dyn Hittable>> = vec![
+            // 壁
+            Box::new(YzRect::new(0.0, 555.0, 0.0, 555.0, 555.0, green)),
+            Box::new(YzRect::new(0.0, 555.0, 0.0, 555.0, 0.0, red)),
+            // 天井の光源
+            Box::new(ceiling_light.clone()),
+            // 床、天井、奥の壁
+            Box::new(XzRect::new(0.0, 555.0, 0.0, 555.0, 0.0, white.clone())),
+            Box::new(XzRect::new(0.0, 555.0, 0.0, 555.0, 555.0, white.clone())),
+            Box::new(XyRect::new(0.0, 555.0, 0.0, 555.0, 555.0, white)),
+            // 煙
+            Box::new(ConstantMedium::new(box1, 0.01, color(0.0, 0.0, 0.0))),
+            Box::new(ConstantMedium::new(box2, 0.01, color(1.0, 1.0, 1.0))),
+        ];
+
+        Scene::new(camera, scene, Environment::black()).with_lights(vec![ceiling_light])
+    }
+
+    /// 三角形メッシュで作った球。左は頂点法線でなめらかに、右は面の法線のまま描画する
+    pub fn mesh(aspect_ratio: f32) -> Scene {
+        let camera = Camera::new(
+            Vec3::new(0.0, 2.0, 10.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            30.0,
+            aspect_ratio,
             0.0,
+            10.0,
             0.0,
             1.0,
+        );
+
+        // 約10万ポリゴンの球。1つのメッシュを変換して3か所に置く
+        let smooth_sphere: Arc<dyn Hittable> = Arc::new(TriangleMesh::new(uv_sphere_mesh(
+            Vec3::new(0.0, 0.0, 0.0),
+            1.0,
+            256,
+            200,
+            true,
+            Arc::new(Metal::new(color(0.8, 0.6, 0.2), 0.0)),
         )));
 
-        scene
+        let scene: Vec<Box<dyn Hittable>> = vec![
+            // 地面
+            Box::new(Plane::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Arc::new(Lambertian::new(color(0.5, 0.5, 0.5))),
+            )),
+            Box::new(Transform::new(
+                smooth_sphere.clone(),
+                Matrix::translation(Vec3::new(-1.2, 1.0, 0.0)),
+            )),
+            // 縦につぶして傾けた楕円体
+            Box::new(Transform::new(
+                smooth_sphere.clone(),
+                Matrix::translation(Vec3::new(-3.0, 0.5, -2.0))
+                    * Matrix::rotation_z(30.0)
+                    * Matrix::scaling(Vec3::new(1.0, 0.5, 1.0)),
+            )),
+            Box::new(Transform::new(
+                smooth_sphere,
+                Matrix::translation(Vec3::new(3.0, 0.6, -2.0))
+                    * Matrix::scaling(Vec3::new(0.6, 0.6, 0.6)),
+            )),
+            // 粗い球
+            Box::new(TriangleMesh::new(uv_sphere_mesh(
+                Vec3::new(1.2, 1.0, 0.0),
+                1.0,
+                16,
+                8,
+                false,
+                Arc::new(Lambertian::new(color(0.1, 0.2, 0.5))),
+            ))),
+        ];
+
+        Scene::new(camera, scene, Environment::sky())
+    }
+
+    /// 市松模様の地面と、画像を貼った球
+    ///
+    /// 画像はカレントディレクトリのweek1.jpgを使う
+    pub fn textures(aspect_ratio: f32) -> Scene {
+        let camera = Camera::new(
+            Vec3::new(0.0, 2.0, 10.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            30.0,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let image = ImageTexture::open(Path::new("week1.jpg"))
+            .unwrap_or_else(|e| panic!("week1.jpgを読み込めません: {}", e));
+
+        let scene: Vec<Box<dyn Hittable>> = vec![
+            // 地面
+            Box::new(Plane::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Arc::new(Lambertian::with_texture(Arc::new(
+                    CheckerTexture::from_colors(1.0, color(0.2, 0.3, 0.1), color(0.9, 0.9, 0.9)),
+                ))),
+            )),
+            Box::new(Sphere::new(
+                Vec3::new(-1.2, 1.0, 0.0),
+                Vec3::new(-1.2, 1.0, 0.0),
+                1.0,
+                Arc::new(Lambertian::with_texture(Arc::new(image))),
+                0.0,
+                1.0,
+            )),
+            Box::new(Sphere::new(
+                Vec3::new(1.2, 1.0, 0.0),
+                Vec3::new(1.2, 1.0, 0.0),
+                1.0,
+                Arc::new(Lambertian::with_texture(Arc::new(
+                    CheckerTexture::from_colors(0.25, color(0.8, 0.1, 0.1), color(0.9, 0.9, 0.9)),
+                ))),
+                0.0,
+                1.0,
+            )),
+        ];
+
+        Scene::new(camera, scene, Environment::sky())
+    }
+
+    /// パーリンノイズによるテクスチャ。左から順にノイズ、乱流、大理石、木目
+    pub fn perlin(aspect_ratio: f32) -> Scene {
+        let camera = Camera::new(
+            Vec3::new(0.0, 3.0, 14.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            30.0,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let textured_sphere = |x: f32, texture: Arc<dyn Texture>| -> Box<dyn Hittable> {
+            Box::new(Sphere::new(
+                Vec3::new(x, 1.0, 0.0),
+                Vec3::new(x, 1.0, 0.0),
+                1.0,
+                Arc::new(Lambertian::with_texture(texture)),
+                0.0,
+                1.0,
+            ))
+        };
+
+        let scene: Vec<Box<dyn Hittable>> = vec![
+            // 地面
+            Box::new(Plane::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Arc::new(Lambertian::with_texture(Arc::new(MarbleTexture::new(
+                    1, 1.0, 7,
+                )))),
+            )),
+            textured_sphere(-3.3, Arc::new(NoiseTexture::new(2, 4.0))),
+            textured_sphere(-1.1, Arc::new(TurbulenceTexture::new(3, 4.0, 7))),
+            textured_sphere(1.1, Arc::new(MarbleTexture::new(4, 4.0, 7))),
+            textured_sphere(
+                3.3,
+                Arc::new(WoodTexture::new(
+                    5,
+                    8.0,
+                    4,
+                    color(0.8, 0.55, 0.3),
+                    color(0.4, 0.2, 0.08),
+                )),
+            ),
+        ];
+
+        Scene::new(camera, scene, Environment::sky())
+    }
+
+    /// 密度の格子から作った雲(左)と、温度の格子で光る炎(右)
+    pub fn volumes(aspect_ratio: f32) -> Scene {
+        let camera = Camera::new(
+            Vec3::new(0.0, 2.0, 12.0),
+            Vec3::new(0.0, 1.5, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            30.0,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let cloud = HeterogeneousMedium::new(
+            Aabb::new(Vec3::new(-4.0, 0.5, -1.5), Vec3::new(-0.5, 3.5, 1.5)),
+            DensityGrid::from_noise(64, 1, 4.0, 5),
+            15.0,
+            color(0.9, 0.9, 0.9),
+        );
+
+        // 炎は中心ほど熱く、上に行くほど冷える
+        let temperature = DensityGrid::from_fn(64, 64, 64, |p| {
+            let centered = (p - Vec3::new(0.5, 0.35, 0.5)) * 2.0;
+            (1.0 - centered.length()).max(0.0) * (1.2 - p.y())
+        });
+        let fire = HeterogeneousMedium::new(
+            Aabb::new(Vec3::new(0.5, 0.0, -1.5), Vec3::new(3.5, 3.0, 1.5)),
+            DensityGrid::from_noise(64, 2, 6.0, 5),
+            6.0,
+            color(0.3, 0.3, 0.3),
+        )
+        .with_emission(temperature, 40.0);
+
+        let scene: Vec<Box<dyn Hittable>> = vec![
+            // 地面
+            Box::new(Plane::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Arc::new(Lambertian::new(color(0.5, 0.5, 0.5))),
+            )),
+            Box::new(cloud),
+            Box::new(fire),
+        ];
+
+        Scene::new(camera, scene, Environment::sky())
+    }
+
+    /// 拡散面の床にガラス球を置き、小さな光源で照らしたシーン。床に集光模様(コースティクス)ができる
+    pub fn caustics(aspect_ratio: f32) -> Scene {
+        let camera = Camera::new(
+            Vec3::new(0.0, 5.0, 12.0),
+            Vec3::new(0.0, 0.8, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            30.0,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let light: Arc<dyn Hittable> = Arc::new(Sphere::new(
+            Vec3::new(-3.0, 7.0, -3.0),
+            Vec3::new(-3.0, 7.0, -3.0),
+            0.5,
+            Arc::new(DiffuseLight::new(color(100.0, 100.0, 100.0))),
+            0.0,
+            1.0,
+        ));
+        let glass = |center: Vec3, radius: f32, absorption: Vec3| -> Box<dyn Hittable> {
+            Box::new(Sphere::new(
+                center,
+                center,
+                radius,
+                Arc::new(Dielectric::new(1.5, absorption)),
+                0.0,
+                1.0,
+            ))
+        };
+
+        let scene: Vec<Box<dyn Hittable>> = vec![
+            // 地面
+            Box::new(Plane::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Arc::new(Lambertian::new(color(0.7, 0.7, 0.7))),
+            )),
+            glass(Vec3::new(0.0, 1.0, 0.0), 1.0, color(0.0, 0.0, 0.0)),
+            glass(Vec3::new(-2.5, 0.7, 1.0), 0.7, color(0.1, 0.6, 1.2)),
+            glass(Vec3::new(2.3, 0.8, 0.8), 0.8, color(1.0, 0.2, 0.8)),
+            Box::new(Sphere::new(
+                Vec3::new(1.5, 0.5, -2.0),
+                Vec3::new(1.5, 0.5, -2.0),
+                0.5,
+                Arc::new(Metal::new(color(0.9, 0.8, 0.6), 0.0)),
+                0.0,
+                1.0,
+            )),
+            Box::new(light.clone()),
+        ];
+
+        Scene::new(camera, scene, Environment::black()).with_lights(vec![light])
+    }
+
+    /// 物体とは別の光源(点光源、スポットライト、平行光源、球と長方形の光源)と光る物体で照らしたシーン
+    pub fn light_types(aspect_ratio: f32) -> Scene {
+        let camera = Camera::new(
+            Vec3::new(0.0, 4.0, 14.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            30.0,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let emissive: Arc<dyn Hittable> = Arc::new(Sphere::new(
+            Vec3::new(3.5, 0.4, 1.5),
+            Vec3::new(3.5, 0.4, 1.5),
+            0.4,
+            Arc::new(DiffuseLight::new(color(4.0, 1.0, 0.5))),
+            0.0,
+            1.0,
+        ));
+        let sphere =
+            |center: Vec3, radius: f32, material: Arc<dyn Material>| -> Box<dyn Hittable> {
+                Box::new(Sphere::new(center, center, radius, material, 0.0, 1.0))
+            };
+
+        let scene: Vec<Box<dyn Hittable>> = vec![
+            // 地面と奥の壁
+            Box::new(Plane::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Arc::new(Lambertian::new(color(0.7, 0.7, 0.7))),
+            )),
+            Box::new(XyRect::new(
+                -10.0,
+                10.0,
+                0.0,
+                10.0,
+                -4.0,
+                Arc::new(Lambertian::new(color(0.6, 0.6, 0.6))),
+            )),
+            sphere(
+                Vec3::new(-2.5, 1.0, 0.0),
+                1.0,
+                Arc::new(Lambertian::new(color(0.8, 0.3, 0.3))),
+            ),
+            sphere(
+                Vec3::new(0.0, 1.0, 0.0),
+                1.0,
+                Arc::new(Metal::new(color(0.8, 0.8, 0.8), 0.1)),
+            ),
+            sphere(
+                Vec3::new(2.5, 1.0, 0.0),
+                1.0,
+                Arc::new(Lambertian::new(color(0.3, 0.5, 0.8))),
+            ),
+            Box::new(emissive.clone()),
+        ];
+
+        let light_sources: Vec<Box<dyn Light>> = vec![
+            // 夕日
+            Box::new(DirectionalLight::new(
+                Vec3::new(-1.0, 0.6, 0.8),
+                color(0.6, 0.45, 0.3),
+                2.0,
+            )),
+            Box::new(PointLight::new(
+                Vec3::new(-4.0, 3.0, 3.0),
+                color(6.0, 6.0, 8.0),
+            )),
+            // 奥の壁を照らすスポットライト
+            Box::new(SpotLight::new(
+                Vec3::new(0.0, 6.0, 2.0),
+                Vec3::new(0.0, -0.5, -1.0),
+                color(40.0, 35.0, 25.0),
+                20.0,
+                12.0,
+            )),
+            Box::new(SphereLight::new(
+                Vec3::new(-4.0, 0.5, 2.0),
+                0.5,
+                color(1.0, 3.0, 1.0),
+            )),
+            // 天井の長方形の光源(下向き)
+            Box::new(QuadLight::new(
+                Vec3::new(1.0, 5.0, -1.0),
+                Vec3::new(2.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 2.0),
+                color(3.0, 3.0, 3.0),
+            )),
+        ];
+
+        Scene::new(camera, scene, Environment::black())
+            .with_lights(vec![emissive])
+            .with_light_sources(light_sources)
+    }
+
+    /// 空と太陽に照らされた建物。既定の設定では夏至の東京の午後になる
+    pub fn sky(settings: &SkySettings, aspect_ratio: f32) -> Scene {
+        let camera = Camera::new(
+            Vec3::new(-14.0, 3.0, 14.0),
+            Vec3::new(0.0, 3.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            40.0,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let concrete: Arc<dyn Material> = Arc::new(Lambertian::new(color(0.6, 0.58, 0.55)));
+        let building = |p0: Vec3, p1: Vec3| -> Box<dyn Hittable> {
+            Box::new(Cuboid::new(p0, p1, concrete.clone()))
+        };
+        let scene: Vec<Box<dyn Hittable>> = vec![
+            // 地面
+            Box::new(Plane::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Arc::new(Lambertian::new(color(0.3, 0.3, 0.3))),
+            )),
+            building(Vec3::new(-4.0, 0.0, -4.0), Vec3::new(0.0, 8.0, 0.0)),
+            building(Vec3::new(1.0, 0.0, -6.0), Vec3::new(4.0, 5.0, -2.0)),
+            building(Vec3::new(-3.0, 0.0, 2.0), Vec3::new(-1.0, 3.0, 5.0)),
+            Box::new(Sphere::new(
+                Vec3::new(3.0, 1.0, 3.0),
+                Vec3::new(3.0, 1.0, 3.0),
+                1.0,
+                Arc::new(Metal::new(color(0.9, 0.9, 0.9), 0.0)),
+                0.0,
+                1.0,
+            )),
+        ];
+
+        Scene::new(camera, scene, Environment::black()).with_physical_sky(&settings.physical_sky())
+    }
+
+    /// 金、銅、アルミニウムとすりガラスの球を、手前から奥へ粗くしながら並べたシーン
+    pub fn microfacet(aspect_ratio: f32) -> Scene {
+        let camera = Camera::new(
+            Vec3::new(0.0, 4.0, 9.0),
+            Vec3::new(0.0, 0.6, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            35.0,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let mut scene: Vec<Box<dyn Hittable>> = vec![Box::new(Plane::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Arc::new(Lambertian::with_texture(Arc::new(
+                CheckerTexture::from_colors(2.0, color(0.2, 0.2, 0.2), color(0.8, 0.8, 0.8)),
+            ))),
+        ))];
+
+        let roughness = [0.0, 0.2, 0.4, 0.7];
+        for (i, r) in roughness.iter().enumerate() {
+            let materials: [Arc<dyn Material>; 4] = [
+                Arc::new(Conductor::gold(*r)),
+                Arc::new(Conductor::copper(*r)),
+                Arc::new(Conductor::aluminium(*r)),
+                Arc::new(RoughDielectric::new(1.5, *r, color(0.0, 0.0, 0.0))),
+            ];
+            for (j, material) in materials.into_iter().enumerate() {
+                let center = Vec3::new(-3.3 + 2.2 * j as f32, 0.5, 1.5 - 1.6 * i as f32);
+                scene.push(Box::new(Sphere::new(
+                    center, center, 0.5, material, 0.0, 1.0,
+                )));
+            }
+        }
+
+        Scene::new(camera, scene, Environment::sky()).with_light_sources(vec![Box::new(
+            DirectionalLight::new(Vec3::new(-1.0, 2.0, 1.0), color(2.0, 2.0, 2.0), 2.0),
+        )])
+    }
+
+    /// OBJファイルのモデルを地面に置いたシーン。カメラはモデル全体が写るように置く
+    pub fn obj_model(path: &Path, aspect_ratio: f32) -> Result<Scene, ObjError> {
+        let meshes = load_obj(path)?;
+        let bbox = meshes
+            .iter()
+            .filter_map(|mesh| mesh.bounding_box(0.0, 1.0))
+            .reduce(Aabb::surrounding_box)
+            .unwrap_or(Aabb::new(
+                Vec3::new(-1.0, -1.0, -1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
+        let center = (bbox.min() + bbox.max()) * 0.5;
+        let radius = (bbox.max() - bbox.min()).length() * 0.5;
+
+        let vfov = 30.0_f32;
+        let distance = radius / (vfov.to_radians() * 0.5).sin();
+        let camera = Camera::new(
+            center + unit_vector(Vec3::new(0.0, 0.3, 1.0)) * distance,
+            center,
+            Vec3::new(0.0, 1.0, 0.0),
+            vfov,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let mut scene: Vec<Box<dyn Hittable>> = vec![Box::new(Plane::new(
+            bbox.min(),
+            Vec3::new(0.0, 1.0, 0.0),
+            Arc::new(Lambertian::new(color(0.5, 0.5, 0.5))),
+        ))];
+        for mesh in meshes {
+            scene.push(Box::new(mesh));
+        }
+
+        Ok(Scene::new(camera, scene, Environment::sky()))
+    }
+
+    /// Mitsubaの.volファイルの密度の格子を煙として地面に置いたシーン
+    ///
+    /// 格子は最も長い辺が3になるように置く。密度は最大値が10になるように揃える
+    pub fn vol_model(path: &Path, aspect_ratio: f32) -> io::Result<Scene> {
+        let density = DensityGrid::load_vol(path)?;
+        let (nx, ny, nz) = density.resolution();
+        let longest = nx.max(ny).max(nz) as f32;
+        let half = Vec3::new(nx as f32, ny as f32, nz as f32) * (1.5 / longest);
+        let bounds = Aabb::new(
+            Vec3::new(-half.x(), 0.0, -half.z()),
+            Vec3::new(half.x(), 2.0 * half.y(), half.z()),
+        );
+        let density_scale = if density.max() > 0.0 {
+            10.0 / density.max()
+        } else {
+            0.0
+        };
+
+        let camera = Camera::new(
+            Vec3::new(0.0, 2.0, 12.0),
+            Vec3::new(0.0, half.y(), 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            30.0,
+            aspect_ratio,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+
+        let scene: Vec<Box<dyn Hittable>> = vec![
+            // 地面
+            Box::new(Plane::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Arc::new(Lambertian::new(color(0.5, 0.5, 0.5))),
+            )),
+            Box::new(HeterogeneousMedium::new(
+                bounds,
+                density,
+                density_scale,
+                color(0.9, 0.9, 0.9),
+            )),
+        ];
+
+        Ok(Scene::new(camera, scene, Environment::sky()))
+    }
+}
+
+/// 経線と緯線で分割した球の三角形メッシュ。smoothがtrueの場合は頂点法線とUV座標を付ける
+fn uv_sphere_mesh(
+    center: Vec3,
+    radius: f32,
+    segments: u32,
+    rings: u32,
+    smooth: bool,
+    material: Arc<dyn Material>,
+) -> MeshData {
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    for j in 0..=rings {
+        let v = j as f32 / rings as f32;
+        let theta = v * std::f32::consts::PI;
+        for i in 0..=segments {
+            let u = i as f32 / segments as f32;
+            let phi = u * 2.0 * std::f32::consts::PI;
+            let n = Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                -theta.sin() * phi.sin(),
+            );
+            positions.push(center + n * radius);
+            normals.push(n);
+            uvs.push([u, 1.0 - v]);
+        }
+    }
+
+    let mut indices = Vec::new();
+    let stride = segments + 1;
+    for j in 0..rings {
+        for i in 0..segments {
+            let a = j * stride + i;
+            let b = a + stride;
+            // 極では三角形が潰れるので1枚だけにする
+            if j != 0 {
+                indices.push([a, b, a + 1]);
+            }
+            if j != rings - 1 {
+                indices.push([a + 1, b, b + 1]);
+            }
+        }
+    }
+
+    if smooth {
+        MeshData::new(positions, indices, Some(normals), Some(uvs), material)
+    } else {
+        MeshData::new(positions, indices, None, None, material)
     }
 }
@@ -0,0 +1,457 @@
+use std::f32::consts::PI;
+
+use crate::environment_map::EnvironmentMap;
+use crate::light::DirectionalLight;
+use crate::vec3::{color, unit_vector, Vec3};
+
+/// 太陽の視直径(度)
+const SUN_ANGULAR_DIAMETER: f32 = 0.53;
+/// 大気の外での太陽の輝度(kcd/m²)
+const SUN_LUMINANCE: f32 = 2.0e6;
+
+/// Preethamの昼間の空のモデル(A Practical Analytic Model for Daylight, 1999)
+///
+/// 大気の濁り(turbidity)と太陽の向きから、空の各方向の明るさと色を求める。
+/// 地平線より下は、空と太陽に照らされた反射率ground_albedoの拡散面の地面とする。
+/// 太陽が地平線より下にある薄明や夜は扱えない
+pub struct PhysicalSky {
+    sun_direction: Vec3,
+    turbidity: f32,
+    ground_albedo: Vec3,
+    intensity: f32,
+    /// 天頂のxyY
+    zenith: [f32; 3],
+    /// x、y、YそれぞれのPerezの係数A〜E
+    perez: [[f32; 5]; 3],
+    /// 太陽のある向きのPerezの関数の値。天頂の値で割って正規化するのに使う
+    perez_sun: [f32; 3],
+    ground: Vec3,
+}
+
+impl PhysicalSky {
+    /// sun_directionは太陽がある向き(+yが天頂)。turbidityは大気の濁り(2で快晴、10で霞)。
+    /// intensityは輝度(kcd/m²)に掛けて放射輝度にする倍率
+    pub fn new(
+        sun_direction: Vec3,
+        turbidity: f32,
+        ground_albedo: Vec3,
+        intensity: f32,
+    ) -> PhysicalSky {
+        let sun_direction = unit_vector(sun_direction);
+        // モデルの範囲外になるので、太陽の天頂角は地平線の少し上までにする
+        let theta_s = sun_direction
+            .y()
+            .clamp(0.0, 1.0)
+            .acos()
+            .min(PI / 2.0 - 0.01);
+        let t = turbidity;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let polynomial = |c: [[f32; 4]; 3]| {
+            let row = |r: [f32; 4]| {
+                r[0] * theta_s.powi(3) + r[1] * theta_s.powi(2) + r[2] * theta_s + r[3]
+            };
+            t * t * row(c[0]) + t * row(c[1]) + row(c[2])
+        };
+        let zenith_x = polynomial([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = polynomial([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let perez = [
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+        ];
+        let perez_sun = [
+            perez_function(perez[0], 0.0, theta_s),
+            perez_function(perez[1], 0.0, theta_s),
+            perez_function(perez[2], 0.0, theta_s),
+        ];
+
+        let mut sky = PhysicalSky {
+            sun_direction,
+            turbidity,
+            ground_albedo,
+            intensity,
+            zenith: [zenith_x, zenith_y, zenith_luminance],
+            perez,
+            perez_sun,
+            ground: color(0.0, 0.0, 0.0),
+        };
+        sky.ground = sky.ground_radiance();
+        sky
+    }
+
+    /// 太陽がある向き
+    pub fn sun_direction(&self) -> Vec3 {
+        self.sun_direction
+    }
+
+    /// directionの向きから届く空の放射輝度(太陽は含まない)
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        let d = unit_vector(direction);
+        if d.y() <= 0.0 {
+            return self.ground;
+        }
+        self.sky_radiance(d)
+    }
+
+    fn sky_radiance(&self, d: Vec3) -> Vec3 {
+        let theta = d.y().clamp(0.0, 1.0).acos();
+        let gamma = d.dot(self.sun_direction).clamp(-1.0, 1.0).acos();
+        let value = |i: usize| {
+            self.zenith[i] * perez_function(self.perez[i], theta, gamma) / self.perez_sun[i]
+        };
+        xyy_to_rgb(value(0), value(1), value(2)) * self.intensity
+    }
+
+    /// 空と太陽に照らされた地面の放射輝度
+    fn ground_radiance(&self) -> Vec3 {
+        // 水平な面が空から受ける放射照度を数値積分で求める
+        const N_THETA: usize = 32;
+        const N_PHI: usize = 64;
+        let d_theta = PI / 2.0 / N_THETA as f32;
+        let d_phi = 2.0 * PI / N_PHI as f32;
+        let mut irradiance = color(0.0, 0.0, 0.0);
+        for i in 0..N_THETA {
+            let theta = (i as f32 + 0.5) * d_theta;
+            for j in 0..N_PHI {
+                let phi = (j as f32 + 0.5) * d_phi;
+                let d = Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                irradiance = irradiance
+                    + self.sky_radiance(d) * (theta.cos() * theta.sin() * d_theta * d_phi);
+            }
+        }
+        irradiance = irradiance + self.sun_irradiance() * self.sun_direction.y().max(0.0);
+        self.ground_albedo * irradiance / PI
+    }
+
+    /// 大気を通ってきた太陽の放射輝度
+    fn sun_radiance(&self) -> Vec3 {
+        let theta_s = self.sun_direction.y().clamp(-1.0, 1.0).acos();
+        let theta_degrees = theta_s.to_degrees();
+        if theta_degrees >= 90.0 {
+            return color(0.0, 0.0, 0.0);
+        }
+        // 大気を通る道のりの長さ(天頂方向を1とする)
+        let air_mass = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_degrees).powf(-1.253));
+        // 大気中の粒子(エアロゾル)による散乱の強さ
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        // 赤、緑、青を代表する波長(μm)での透過率
+        let transmittance = |lambda: f32| {
+            let rayleigh = (-0.008735 * lambda.powf(-4.08) * air_mass).exp();
+            let aerosol = (-beta * lambda.powf(-1.3) * air_mass).exp();
+            rayleigh * aerosol
+        };
+        let t = color(
+            transmittance(0.680),
+            transmittance(0.550),
+            transmittance(0.440),
+        );
+        t * (SUN_LUMINANCE * self.intensity)
+    }
+
+    /// 光に垂直な面が太陽から受ける放射照度
+    fn sun_irradiance(&self) -> Vec3 {
+        let half_sin = (SUN_ANGULAR_DIAMETER.to_radians() / 4.0).sin();
+        let solid_angle = 2.0 * PI * 2.0 * half_sin * half_sin;
+        self.sun_radiance() * solid_angle
+    }
+
+    /// 太陽の円盤
+    pub fn sun(&self) -> DirectionalLight {
+        DirectionalLight::new(
+            self.sun_direction,
+            self.sun_irradiance(),
+            SUN_ANGULAR_DIAMETER,
+        )
+    }
+
+    /// 空を横width個、縦height個のピクセルの環境マップにする。空はなめらかなので画像にしても差が小さい
+    pub fn environment_map(&self, width: usize, height: usize) -> EnvironmentMap {
+        EnvironmentMap::from_fn(width, height, |d| self.radiance(d), 1.0)
+    }
+}
+
+/// 日時と場所から太陽の向きを求めて作る空の設定。既定は夏至の東京の午後3時、快晴に近い空
+#[derive(Clone, Copy, Debug)]
+pub struct SkySettings {
+    latitude: f32,
+    longitude: f32,
+    year: i32,
+    month: u32,
+    day: u32,
+    hour: f32,
+    utc_offset: f32,
+    turbidity: f32,
+    ground_albedo: Vec3,
+    intensity: f32,
+}
+
+impl Default for SkySettings {
+    fn default() -> SkySettings {
+        SkySettings {
+            latitude: 35.68,
+            longitude: 139.77,
+            year: 2024,
+            month: 6,
+            day: 21,
+            hour: 15.0,
+            utc_offset: 9.0,
+            turbidity: 3.0,
+            ground_albedo: color(0.3, 0.3, 0.3),
+            intensity: 0.03,
+        }
+    }
+}
+
+impl SkySettings {
+    /// 緯度と経度(度、北緯と東経が正)
+    pub fn with_location(mut self, latitude: f32, longitude: f32) -> SkySettings {
+        self.latitude = latitude;
+        self.longitude = longitude;
+        self
+    }
+
+    pub fn with_date(mut self, year: i32, month: u32, day: u32) -> SkySettings {
+        self.year = year;
+        self.month = month;
+        self.day = day;
+        self
+    }
+
+    /// 現地時刻(時)と協定世界時との差(時間)
+    pub fn time(&self) -> (f32, f32) {
+        (self.hour, self.utc_offset)
+    }
+
+    /// 現地時刻(時、小数で分以下も表す)と、協定世界時との差(時間)
+    pub fn with_time(mut self, hour: f32, utc_offset: f32) -> SkySettings {
+        self.hour = hour;
+        self.utc_offset = utc_offset;
+        self
+    }
+
+    /// 大気の濁り(2で快晴、10で霞)
+    pub fn with_turbidity(mut self, turbidity: f32) -> SkySettings {
+        self.turbidity = turbidity;
+        self
+    }
+
+    pub fn with_ground_albedo(mut self, ground_albedo: Vec3) -> SkySettings {
+        self.ground_albedo = ground_albedo;
+        self
+    }
+
+    /// 輝度(kcd/m²)に掛けて放射輝度にする倍率
+    pub fn with_intensity(mut self, intensity: f32) -> SkySettings {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn sun_direction(&self) -> Vec3 {
+        sun_direction(
+            self.latitude,
+            self.longitude,
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.utc_offset,
+        )
+    }
+
+    pub fn physical_sky(&self) -> PhysicalSky {
+        PhysicalSky::new(
+            self.sun_direction(),
+            self.turbidity,
+            self.ground_albedo,
+            self.intensity,
+        )
+    }
+}
+
+/// Perezの天空輝度分布の関数。thetaは天頂角、gammaは太陽との角度
+fn perez_function(c: [f32; 5], theta: f32, gamma: f32) -> f32 {
+    let cos_gamma = gamma.cos();
+    (1.0 + c[0] * (c[1] / theta.cos().max(0.01)).exp())
+        * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+/// 色度座標(x, y)と輝度Yを線形sRGBに直す
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Vec3 {
+    if y <= 0.0 {
+        return color(0.0, 0.0, 0.0);
+    }
+    let cx = x / y * luminance;
+    let cz = (1.0 - x - y) / y * luminance;
+    let cy = luminance;
+    color(
+        (3.2406 * cx - 1.5372 * cy - 0.4986 * cz).max(0.0),
+        (-0.9689 * cx + 1.8758 * cy + 0.0415 * cz).max(0.0),
+        (0.0557 * cx - 0.2040 * cy + 1.0570 * cz).max(0.0),
+    )
+}
+
+/// 緯度latitude、経度longitude(度、北緯と東経が正)の地点で、
+/// 現地時刻year年month月day日hour時(小数で分以下も表す)に太陽がある向き
+///
+/// utc_offsetは現地時刻と協定世界時の差(時間)。-zが北、+xが東、+yが天頂になる。
+/// NOAAの近似式を使うので誤差は0.5度程度
+pub fn sun_direction(
+    latitude: f32,
+    longitude: f32,
+    year: i32,
+    month: u32,
+    day: u32,
+    hour: f32,
+    utc_offset: f32,
+) -> Vec3 {
+    const DAYS_BEFORE_MONTH: [u32; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let month_index = (month.clamp(1, 12) - 1) as usize;
+    let day_of_year = DAYS_BEFORE_MONTH[month_index] + day + u32::from(leap && month > 2);
+    let utc_hour = hour - utc_offset;
+
+    // 1年を一周とする角度
+    let days_in_year = if leap { 366.0 } else { 365.0 };
+    let g = 2.0 * PI / days_in_year * (day_of_year as f32 - 1.0 + (utc_hour - 12.0) / 24.0);
+    // 均時差(分)と赤緯
+    let equation_of_time = 229.18
+        * (0.000075 + 0.001868 * g.cos()
+            - 0.032077 * g.sin()
+            - 0.014615 * (2.0 * g).cos()
+            - 0.040849 * (2.0 * g).sin());
+    let declination = 0.006918 - 0.399912 * g.cos() + 0.070257 * g.sin()
+        - 0.006758 * (2.0 * g).cos()
+        + 0.000907 * (2.0 * g).sin()
+        - 0.002697 * (3.0 * g).cos()
+        + 0.00148 * (3.0 * g).sin();
+
+    // 真太陽時(分)と時角
+    let solar_time = utc_hour * 60.0 + equation_of_time + 4.0 * longitude;
+    let hour_angle = (solar_time / 4.0 - 180.0).to_radians();
+
+    let lat = latitude.to_radians();
+    let sin_elevation =
+        lat.sin() * declination.sin() + lat.cos() * declination.cos() * hour_angle.cos();
+    let elevation = sin_elevation.clamp(-1.0, 1.0).asin();
+    // 北から東回りの方位角
+    let azimuth = hour_angle
+        .sin()
+        .atan2(hour_angle.cos() * lat.sin() - declination.tan() * lat.cos())
+        + PI;
+
+    Vec3::new(
+        azimuth.sin() * elevation.cos(),
+        elevation.sin(),
+        -azimuth.cos() * elevation.cos(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::luminance;
+
+    /// 太陽の高度(度)
+    fn elevation(d: Vec3) -> f32 {
+        d.y().asin().to_degrees()
+    }
+
+    #[test]
+    fn solar_noon_elevation_matches_latitude() {
+        // 経度0の正午はほぼ南中する。春分には高度が90°-緯度、夏至には赤緯の23.44°だけ高い
+        let equinox = sun_direction(35.0, 0.0, 2024, 3, 20, 12.0, 0.0);
+        assert!((elevation(equinox) - 55.0).abs() < 1.0);
+        // 北半球では南(+z)にある
+        assert!(equinox.z() > 0.0);
+
+        let solstice = sun_direction(35.0, 0.0, 2024, 6, 21, 12.0, 0.0);
+        assert!((elevation(solstice) - (55.0 + 23.44)).abs() < 1.0);
+        // 南半球では冬至なので低く、北(-z)にある
+        let southern = sun_direction(-35.0, 0.0, 2024, 6, 21, 12.0, 0.0);
+        assert!((elevation(southern) - (55.0 - 23.44)).abs() < 1.0);
+        assert!(southern.z() < 0.0);
+
+        // 時差と経度が合っていれば同じ結果になる
+        let tokyo = sun_direction(35.0, 135.0, 2024, 3, 20, 12.0, 9.0);
+        assert!((tokyo - equinox).length() < 0.01);
+    }
+
+    #[test]
+    fn sun_rises_in_the_east_and_sets_in_the_west() {
+        let morning = sun_direction(35.68, 139.77, 2024, 6, 21, 8.0, 9.0);
+        let afternoon = sun_direction(35.68, 139.77, 2024, 6, 21, 16.0, 9.0);
+        assert!(morning.x() > 0.0 && morning.y() > 0.0);
+        assert!(afternoon.x() < 0.0 && afternoon.y() > 0.0);
+        let midnight = sun_direction(35.68, 139.77, 2024, 6, 21, 0.0, 9.0);
+        assert!(midnight.y() < 0.0);
+    }
+
+    #[test]
+    fn clear_sky_is_blue_at_the_zenith_and_bright_at_the_horizon() {
+        let sun = Vec3::new(0.0, 1.0, 1.0);
+        let sky = PhysicalSky::new(sun, 3.0, color(0.3, 0.3, 0.3), 0.03);
+        let zenith = sky.radiance(Vec3::new(0.0, 1.0, 0.0));
+        // 太陽と反対側の地平線のすぐ上
+        let horizon = sky.radiance(Vec3::new(0.0, 0.01, -1.0));
+        // 濁りの小さい空は地平線に近いほど明るく、天頂ほど青い
+        assert!(luminance(horizon) > luminance(zenith));
+        let blueness = |c: Vec3| c.b() / c.r();
+        assert!(blueness(zenith) > 2.0);
+        assert!(blueness(zenith) > blueness(horizon));
+
+        // 霞んだ空は天頂も白っぽい
+        let hazy = PhysicalSky::new(sun, 8.0, color(0.3, 0.3, 0.3), 0.03);
+        let hazy_zenith = hazy.radiance(Vec3::new(0.0, 1.0, 0.0));
+        assert!(blueness(hazy_zenith) < blueness(zenith));
+    }
+
+    #[test]
+    fn ground_is_lit_by_the_sky_and_the_sun() {
+        let sun = Vec3::new(1.0, 1.0, 0.0);
+        let sky = PhysicalSky::new(sun, 3.0, color(0.3, 0.3, 0.3), 0.03);
+        let down = sky.radiance(Vec3::new(0.0, -1.0, 0.0));
+        // 地平線より下はどの向きでも同じ拡散面の地面になる
+        assert!((down - sky.radiance(Vec3::new(1.0, -0.1, 0.5))).length() == 0.0);
+        assert!(down.x() > 0.0 && down.y() > 0.0 && down.z() > 0.0);
+
+        // 地面の明るさは反射率に比例し、反射率0なら黒い
+        let brighter = PhysicalSky::new(sun, 3.0, color(0.6, 0.6, 0.6), 0.03);
+        let doubled = brighter.radiance(Vec3::new(0.0, -1.0, 0.0));
+        assert!((doubled - down * 2.0).length() < 1e-4 * down.length());
+        let black = PhysicalSky::new(sun, 3.0, color(0.0, 0.0, 0.0), 0.03);
+        assert!(black.radiance(Vec3::new(0.0, -1.0, 0.0)).length() == 0.0);
+    }
+}
@@ -0,0 +1,286 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use image::{ImageResult, RgbImage};
+
+use crate::color::srgb_to_linear;
+use crate::perlin::Perlin;
+use crate::vec3::{color, Vec3};
+
+/// 表面の位置に応じた色
+pub trait Texture: Send + Sync {
+    /// テクスチャ座標(u, v)、位置pにおける色
+    fn value(&self, u: f32, v: f32, p: Vec3) -> Vec3;
+}
+
+/// 単色
+pub struct SolidColor {
+    color: Vec3,
+}
+
+impl SolidColor {
+    pub fn new(color: Vec3) -> SolidColor {
+        SolidColor { color }
+    }
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _u: f32, _v: f32, _p: Vec3) -> Vec3 {
+        self.color
+    }
+}
+
+/// 空間を一辺scaleの立方体に区切り、2つのテクスチャを交互に並べた市松模様
+pub struct CheckerTexture {
+    inv_scale: f32,
+    even: Arc<dyn Texture>,
+    odd: Arc<dyn Texture>,
+}
+
+impl CheckerTexture {
+    pub fn new(scale: f32, even: Arc<dyn Texture>, odd: Arc<dyn Texture>) -> CheckerTexture {
+        CheckerTexture {
+            inv_scale: 1.0 / scale,
+            even,
+            odd,
+        }
+    }
+
+    pub fn from_colors(scale: f32, even: Vec3, odd: Vec3) -> CheckerTexture {
+        CheckerTexture::new(
+            scale,
+            Arc::new(SolidColor::new(even)),
+            Arc::new(SolidColor::new(odd)),
+        )
+    }
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, u: f32, v: f32, p: Vec3) -> Vec3 {
+        let x = (self.inv_scale * p.x()).floor() as i64;
+        let y = (self.inv_scale * p.y()).floor() as i64;
+        let z = (self.inv_scale * p.z()).floor() as i64;
+        if (x + y + z) % 2 == 0 {
+            self.even.value(u, v, p)
+        } else {
+            self.odd.value(u, v, p)
+        }
+    }
+}
+
+/// 画像ファイル(PNG、JPEGなど)を貼り付けるテクスチャ
+///
+/// 8ビットの画像はsRGBで符号化されているので、線形な値に直して使う
+pub struct ImageTexture {
+    image: RgbImage,
+    /// 8ビットの値から線形な値への変換表
+    to_linear: [f32; 256],
+}
+
+impl ImageTexture {
+    pub fn new(image: RgbImage) -> ImageTexture {
+        let mut to_linear = [0.0; 256];
+        for (i, value) in to_linear.iter_mut().enumerate() {
+            *value = srgb_to_linear(i as f32 / 255.0);
+        }
+        ImageTexture { image, to_linear }
+    }
+
+    pub fn open(path: &Path) -> ImageResult<ImageTexture> {
+        Ok(ImageTexture::new(image::open(path)?.to_rgb8()))
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f32, v: f32, _p: Vec3) -> Vec3 {
+        let (width, height) = self.image.dimensions();
+        if width == 0 || height == 0 {
+            // 画像が空の場合は目立つ色にする
+            return color(0.0, 1.0, 1.0);
+        }
+
+        // 画像の座標系では左上が原点なのでvを反転する
+        let u = u.clamp(0.0, 1.0);
+        let v = 1.0 - v.clamp(0.0, 1.0);
+        let i = ((u * width as f32) as u32).min(width - 1);
+        let j = ((v * height as f32) as u32).min(height - 1);
+
+        let pixel = self.image.get_pixel(i, j);
+        color(
+            self.to_linear[pixel[0] as usize],
+            self.to_linear[pixel[1] as usize],
+            self.to_linear[pixel[2] as usize],
+        )
+    }
+}
+
+/// パーリンノイズをそのまま明暗にしたテクスチャ
+pub struct NoiseTexture {
+    noise: Perlin,
+    frequency: f32,
+}
+
+impl NoiseTexture {
+    pub fn new(seed: u64, frequency: f32) -> NoiseTexture {
+        NoiseTexture {
+            noise: Perlin::new(seed),
+            frequency,
+        }
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, _u: f32, _v: f32, p: Vec3) -> Vec3 {
+        color(1.0, 1.0, 1.0) * 0.5 * (1.0 + self.noise.noise(p * self.frequency))
+    }
+}
+
+/// octaves回ノイズを重ねた乱流のテクスチャ。石のような模様になる
+pub struct TurbulenceTexture {
+    noise: Perlin,
+    frequency: f32,
+    octaves: u32,
+}
+
+impl TurbulenceTexture {
+    pub fn new(seed: u64, frequency: f32, octaves: u32) -> TurbulenceTexture {
+        TurbulenceTexture {
+            noise: Perlin::new(seed),
+            frequency,
+            octaves,
+        }
+    }
+}
+
+impl Texture for TurbulenceTexture {
+    fn value(&self, _u: f32, _v: f32, p: Vec3) -> Vec3 {
+        let turbulence = self.noise.turbulence(p * self.frequency, self.octaves);
+        color(1.0, 1.0, 1.0) * turbulence.min(1.0)
+    }
+}
+
+/// 大理石。z方向の縞模様を乱流でゆがませる
+///
+/// frequencyは縞の細かさ、noise_scaleは縞をゆがませる乱流の細かさ
+pub struct MarbleTexture {
+    noise: Perlin,
+    frequency: f32,
+    noise_scale: f32,
+    octaves: u32,
+}
+
+impl MarbleTexture {
+    pub fn new(seed: u64, frequency: f32, octaves: u32) -> MarbleTexture {
+        MarbleTexture {
+            noise: Perlin::new(seed),
+            frequency,
+            noise_scale: 1.0,
+            octaves,
+        }
+    }
+
+    /// 乱流の細かさ。既定は1
+    pub fn with_noise_scale(mut self, noise_scale: f32) -> MarbleTexture {
+        self.noise_scale = noise_scale;
+        self
+    }
+}
+
+impl Texture for MarbleTexture {
+    fn value(&self, _u: f32, _v: f32, p: Vec3) -> Vec3 {
+        let turbulence = self.noise.turbulence(p * self.noise_scale, self.octaves);
+        color(1.0, 1.0, 1.0) * 0.5 * (1.0 + (self.frequency * p.z() + 10.0 * turbulence).sin())
+    }
+}
+
+/// 木目。y軸を中心とする年輪を乱流でゆがませ、lightとdarkの2色で塗る
+///
+/// frequencyは年輪の細かさ、noise_scaleは年輪をゆがませる乱流の細かさ
+pub struct WoodTexture {
+    noise: Perlin,
+    frequency: f32,
+    noise_scale: f32,
+    octaves: u32,
+    light: Vec3,
+    dark: Vec3,
+}
+
+impl WoodTexture {
+    pub fn new(seed: u64, frequency: f32, octaves: u32, light: Vec3, dark: Vec3) -> WoodTexture {
+        WoodTexture {
+            noise: Perlin::new(seed),
+            frequency,
+            noise_scale: 1.0,
+            octaves,
+            light,
+            dark,
+        }
+    }
+
+    /// 乱流の細かさ。既定は1
+    pub fn with_noise_scale(mut self, noise_scale: f32) -> WoodTexture {
+        self.noise_scale = noise_scale;
+        self
+    }
+}
+
+impl Texture for WoodTexture {
+    fn value(&self, _u: f32, _v: f32, p: Vec3) -> Vec3 {
+        let turbulence = self.noise.turbulence(p * self.noise_scale, self.octaves);
+        let radius = (p.x() * p.x() + p.z() * p.z()).sqrt();
+        let rings = self.frequency * radius + 2.0 * turbulence;
+        // 年輪の内側ほど明るく、外側に向かって暗くなる
+        let t = rings - rings.floor();
+        self.light * (1.0 - t) + self.dark * t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    #[test]
+    fn image_texture_decodes_srgb() {
+        let mut image = RgbImage::new(2, 1);
+        image.put_pixel(0, 0, Rgb([128, 0, 255]));
+        image.put_pixel(1, 0, Rgb([10, 10, 10]));
+        let texture = ImageTexture::new(image);
+        let p = Vec3::new(0.0, 0.0, 0.0);
+
+        let c = texture.value(0.25, 0.5, p);
+        assert!((c.x() - 0.216).abs() < 1e-3, "中間の灰色: {}", c.x());
+        assert_eq!(c.y(), 0.0);
+        assert!((c.z() - 1.0).abs() < 1e-6);
+        // 暗い部分は線形に近い
+        let dark = texture.value(0.75, 0.5, p);
+        assert!((dark.x() - 10.0 / 255.0 / 12.92).abs() < 1e-6);
+    }
+
+    #[test]
+    fn noise_scale_changes_the_pattern() {
+        let p = Vec3::new(0.3, 1.7, -2.2);
+        let marble = MarbleTexture::new(1, 4.0, 7);
+        let same = MarbleTexture::new(1, 4.0, 7).with_noise_scale(1.0);
+        assert_eq!(
+            (marble.value(0.0, 0.0, p) - same.value(0.0, 0.0, p)).length(),
+            0.0
+        );
+        let fine = MarbleTexture::new(1, 4.0, 7).with_noise_scale(8.0);
+        // 縞の位置が変わらない点はないので、どこかで違う値になる
+        assert!((0..16).any(|i| {
+            let q = p + Vec3::new(0.1 * i as f32, 0.0, 0.0);
+            (marble.value(0.0, 0.0, q) - fine.value(0.0, 0.0, q)).length() > 1e-3
+        }));
+
+        let light = color(0.8, 0.6, 0.4);
+        let dark = color(0.4, 0.2, 0.1);
+        let wood = WoodTexture::new(1, 4.0, 7, light, dark);
+        let fine = WoodTexture::new(1, 4.0, 7, light, dark).with_noise_scale(8.0);
+        assert!((0..16).any(|i| {
+            let q = p + Vec3::new(0.1 * i as f32, 0.0, 0.0);
+            (wood.value(0.0, 0.0, q) - fine.value(0.0, 0.0, q)).length() > 1e-3
+        }));
+    }
+}
@@ -0,0 +1,463 @@
+use std::ops::Mul;
+use std::sync::Arc;
+
+use crate::aabb::Aabb;
+use crate::hitrecord::HitRecord;
+use crate::hittable::Hittable;
+use crate::ray::Ray;
+use crate::vec3::{unit_vector, Vec3};
+
+/// アフィン変換の行列。3x3の線形変換と平行移動からなる
+#[derive(Clone, Copy, Debug)]
+pub struct Matrix {
+    m: [[f32; 4]; 3],
+}
+
+impl Matrix {
+    pub fn identity() -> Matrix {
+        Matrix {
+            m: [
+                [1.0, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+            ],
+        }
+    }
+
+    pub fn translation(offset: Vec3) -> Matrix {
+        Matrix {
+            m: [
+                [1.0, 0.0, 0.0, offset.x()],
+                [0.0, 1.0, 0.0, offset.y()],
+                [0.0, 0.0, 1.0, offset.z()],
+            ],
+        }
+    }
+
+    pub fn scaling(scale: Vec3) -> Matrix {
+        Matrix {
+            m: [
+                [scale.x(), 0.0, 0.0, 0.0],
+                [0.0, scale.y(), 0.0, 0.0],
+                [0.0, 0.0, scale.z(), 0.0],
+            ],
+        }
+    }
+
+    /// x軸まわりの回転(度数法)
+    pub fn rotation_x(degrees: f32) -> Matrix {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Matrix {
+            m: [
+                [1.0, 0.0, 0.0, 0.0],
+                [0.0, cos, -sin, 0.0],
+                [0.0, sin, cos, 0.0],
+            ],
+        }
+    }
+
+    /// y軸まわりの回転(度数法)
+    pub fn rotation_y(degrees: f32) -> Matrix {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Matrix {
+            m: [
+                [cos, 0.0, sin, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [-sin, 0.0, cos, 0.0],
+            ],
+        }
+    }
+
+    /// z軸まわりの回転(度数法)
+    pub fn rotation_z(degrees: f32) -> Matrix {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Matrix {
+            m: [
+                [cos, -sin, 0.0, 0.0],
+                [sin, cos, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+            ],
+        }
+    }
+
+    /// 点を変換する(平行移動を含む)
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        self.transform_vector(p) + Vec3::new(self.m[0][3], self.m[1][3], self.m[2][3])
+    }
+
+    /// 方向を変換する(平行移動を含まない)
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * v.x() + m[0][1] * v.y() + m[0][2] * v.z(),
+            m[1][0] * v.x() + m[1][1] * v.y() + m[1][2] * v.z(),
+            m[2][0] * v.x() + m[2][1] * v.y() + m[2][2] * v.z(),
+        )
+    }
+
+    /// 線形変換部分の転置行列で方向を変換する。逆行列に対して使うと法線の変換になる
+    pub fn transform_transposed(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * v.x() + m[1][0] * v.y() + m[2][0] * v.z(),
+            m[0][1] * v.x() + m[1][1] * v.y() + m[2][1] * v.z(),
+            m[0][2] * v.x() + m[1][2] * v.y() + m[2][2] * v.z(),
+        )
+    }
+
+    /// 線形変換部分が回転と一様な拡大縮小だけからなる場合、その拡大率
+    ///
+    /// 各軸の変換先が互いに直交して同じ長さならそのような変換になる。形は変わらず、角度も保たれる
+    pub fn uniform_scale(&self) -> Option<f32> {
+        let m = &self.m;
+        let column = |j: usize| Vec3::new(m[0][j], m[1][j], m[2][j]);
+        let (c0, c1, c2) = (column(0), column(1), column(2));
+        let s2 = c0.length_squared();
+        let tolerance = 1e-4 * s2;
+        let similar = (c1.length_squared() - s2).abs() < tolerance
+            && (c2.length_squared() - s2).abs() < tolerance
+            && c0.dot(c1).abs() < tolerance
+            && c1.dot(c2).abs() < tolerance
+            && c2.dot(c0).abs() < tolerance;
+        if similar && s2 > 0.0 {
+            Some(s2.sqrt())
+        } else {
+            None
+        }
+    }
+
+    /// 逆行列。逆行列がない(潰れてしまう変換の)場合はNone
+    pub fn inverse(&self) -> Option<Matrix> {
+        let m = &self.m;
+        // 余因子
+        let c00 = m[1][1] * m[2][2] - m[1][2] * m[2][1];
+        let c01 = m[1][2] * m[2][0] - m[1][0] * m[2][2];
+        let c02 = m[1][0] * m[2][1] - m[1][1] * m[2][0];
+        let det = m[0][0] * c00 + m[0][1] * c01 + m[0][2] * c02;
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let a = [
+            [
+                c00 * inv_det,
+                (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det,
+                (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det,
+            ],
+            [
+                c01 * inv_det,
+                (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det,
+                (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det,
+            ],
+            [
+                c02 * inv_det,
+                (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det,
+                (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det,
+            ],
+        ];
+        let mut result = Matrix {
+            m: [
+                [a[0][0], a[0][1], a[0][2], 0.0],
+                [a[1][0], a[1][1], a[1][2], 0.0],
+                [a[2][0], a[2][1], a[2][2], 0.0],
+            ],
+        };
+        // 平行移動は逆向きに線形変換してから打ち消す
+        let t = -result.transform_vector(Vec3::new(m[0][3], m[1][3], m[2][3]));
+        result.m[0][3] = t.x();
+        result.m[1][3] = t.y();
+        result.m[2][3] = t.z();
+        Some(result)
+    }
+}
+
+/// 行列の積。(a * b)はbを先に適用してからaを適用する変換になる
+impl Mul for Matrix {
+    type Output = Matrix;
+
+    fn mul(self, other: Matrix) -> Matrix {
+        let mut m = [[0.0_f32; 4]; 3];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..3).map(|k| self.m[i][k] * other.m[k][j]).sum();
+                if j == 3 {
+                    *value += self.m[i][3];
+                }
+            }
+        }
+        Matrix { m }
+    }
+}
+
+/// 任意の物体を移動、回転、拡大縮小して配置する。物体はArcで共有できるので、同じメッシュを何か所にも置ける
+///
+/// 光源として表面の点を選べるのは、回転、平行移動、一様な拡大縮小だけの変換の場合に限る。
+/// 軸ごとに拡大率が違うと面の向きによって面積の伸び方が変わり、一様に点を選べないので、表面積を0とする
+pub struct Transform {
+    object: Arc<dyn Hittable>,
+    matrix: Matrix,
+    inverse: Matrix,
+    /// 一様な拡大縮小の場合の拡大率
+    scale: Option<f32>,
+}
+
+impl Transform {
+    pub fn new(object: Arc<dyn Hittable>, matrix: Matrix) -> Transform {
+        let inverse = matrix.inverse().expect("逆行列を持たない変換は使えません");
+        Transform {
+            object,
+            matrix,
+            inverse,
+            scale: matrix.uniform_scale(),
+        }
+    }
+}
+
+impl Hittable for Transform {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord<'_>> {
+        // レイを物体の座標系に変換して調べる。方向は正規化しないのでtはそのまま使える
+        let local_ray = Ray::new(
+            self.inverse.transform_point(ray.origin()),
+            self.inverse.transform_vector(ray.direction()),
+            ray.time(),
+        );
+        let mut hit_record = self.object.hit(&local_ray, tmin, tmax, time)?;
+
+        // 法線は逆行列の転置で変換する。レイの向きとの内積の符号は変わらないので表裏はそのまま
+        hit_record.set_point(self.matrix.transform_point(hit_record.point()));
+        hit_record.set_normal(unit_vector(
+            self.inverse.transform_transposed(hit_record.normal()),
+        ));
+        Some(hit_record)
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        // 元のボックスの8つの頂点を変換して囲む
+        let local = self.object.bounding_box(time0, time1)?;
+        let mut result: Option<Aabb> = None;
+        for i in 0..8 {
+            let corner = Vec3::new(
+                if i & 1 == 0 {
+                    local.min().x()
+                } else {
+                    local.max().x()
+                },
+                if i & 2 == 0 {
+                    local.min().y()
+                } else {
+                    local.max().y()
+                },
+                if i & 4 == 0 {
+                    local.min().z()
+                } else {
+                    local.max().z()
+                },
+            );
+            let p = self.matrix.transform_point(corner);
+            let point_box = Aabb::new(p, p);
+            result = Some(match result {
+                None => point_box,
+                Some(b) => Aabb::surrounding_box(b, point_box),
+            });
+        }
+        result
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        // 角度を保つ変換なので、物体の座標系での立体角あたりの確率密度がそのまま使える
+        if self.scale.is_none() {
+            return 0.0;
+        }
+        self.object.pdf_value(
+            self.inverse.transform_point(origin),
+            self.inverse.transform_vector(direction),
+            time,
+        )
+    }
+
+    fn random(&self, origin: Vec3, time: f32) -> Vec3 {
+        self.matrix.transform_vector(
+            self.object
+                .random(self.inverse.transform_point(origin), time),
+        )
+    }
+
+    fn sample_surface(&self, time: f32) -> Option<HitRecord<'_>> {
+        self.scale?;
+        let mut hit_record = self.object.sample_surface(time)?;
+        hit_record.set_point(self.matrix.transform_point(hit_record.point()));
+        hit_record.set_normal(unit_vector(
+            self.inverse.transform_transposed(hit_record.normal()),
+        ));
+        Some(hit_record)
+    }
+
+    fn area(&self, time: f32) -> f32 {
+        match self.scale {
+            Some(scale) => self.object.area(time) * scale * scale,
+            None => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::{Sphere, XzRect};
+    use crate::material::{Lambertian, Material};
+    use crate::vec3::color;
+
+    fn assert_near(actual: Vec3, expected: Vec3) {
+        assert!(
+            (actual - expected).length() < 1e-4,
+            "expected: {:?}, actual: {:?}",
+            expected,
+            actual
+        );
+    }
+
+    fn material() -> Arc<dyn Material> {
+        Arc::new(Lambertian::new(color(0.5, 0.5, 0.5)))
+    }
+
+    /// 回転と拡大縮小と平行移動を組み合わせた、軸ごとに拡大率の違う変換
+    fn general() -> Matrix {
+        Matrix::translation(Vec3::new(1.0, -2.0, 3.0))
+            * Matrix::rotation_x(25.0)
+            * Matrix::rotation_y(-40.0)
+            * Matrix::scaling(Vec3::new(2.0, 0.5, 3.0))
+    }
+
+    #[test]
+    fn transforms_points_vectors_and_normals() {
+        let m = Matrix::translation(Vec3::new(1.0, 2.0, 3.0))
+            * Matrix::rotation_z(90.0)
+            * Matrix::scaling(Vec3::new(2.0, 3.0, 4.0));
+        // x軸の向きは2倍されてからy軸の向きへ回る
+        assert_near(
+            m.transform_vector(Vec3::new(1.0, 0.0, 0.0)),
+            Vec3::new(0.0, 2.0, 0.0),
+        );
+        assert_near(
+            m.transform_vector(Vec3::new(0.0, 1.0, 0.0)),
+            Vec3::new(-3.0, 0.0, 0.0),
+        );
+        assert_near(
+            m.transform_point(Vec3::new(1.0, 1.0, 1.0)),
+            Vec3::new(-2.0, 4.0, 7.0),
+        );
+        // 転置は行と列を入れ替えた積になる
+        assert_near(
+            m.transform_transposed(Vec3::new(0.0, 1.0, 0.0)),
+            Vec3::new(2.0, 0.0, 0.0),
+        );
+        // 逆行列の転置で変換した法線は、変換後の接線と直交する
+        let inverse = m.inverse().unwrap();
+        let tangent = Vec3::new(1.0, -1.0, 0.0);
+        let normal = Vec3::new(1.0, 1.0, 0.0);
+        let n = inverse.transform_transposed(normal);
+        assert!(n.dot(m.transform_vector(tangent)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let m = general();
+        let inverse = m.inverse().unwrap();
+        for p in [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 2.0, 3.0),
+            Vec3::new(-4.0, 0.5, 7.0),
+        ] {
+            assert_near(inverse.transform_point(m.transform_point(p)), p);
+            assert_near((m * inverse).transform_point(p), p);
+            assert_near((inverse * m).transform_vector(p), p);
+        }
+        assert!(Matrix::scaling(Vec3::new(1.0, 0.0, 1.0))
+            .inverse()
+            .is_none());
+    }
+
+    #[test]
+    fn detects_uniform_scale() {
+        assert_eq!(Matrix::identity().uniform_scale(), Some(1.0));
+        let similar = Matrix::translation(Vec3::new(5.0, 0.0, 0.0))
+            * Matrix::rotation_y(30.0)
+            * Matrix::scaling(Vec3::new(2.0, 2.0, 2.0));
+        assert!((similar.uniform_scale().unwrap() - 2.0).abs() < 1e-5);
+        assert!(general().uniform_scale().is_none());
+    }
+
+    #[test]
+    fn hits_rotated_and_scaled_sphere() {
+        // 半径1の球を軸ごとに違う拡大率で伸ばし、回転させてから移動した楕円体
+        let matrix = general();
+        let ellipsoid = Transform::new(
+            Arc::new(Sphere::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 0.0),
+                1.0,
+                material(),
+                0.0,
+                1.0,
+            )),
+            matrix,
+        );
+        let inverse = matrix.inverse().unwrap();
+        let origin = Vec3::new(10.0, 5.0, -20.0);
+        for target in [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            Vec3::new(0.6, 0.0, 0.8),
+        ] {
+            // 球の表面上の点を変換した位置を狙う
+            let point = matrix.transform_point(target);
+            let ray = Ray::new(origin, point - origin, 0.0);
+            let hit_record = ellipsoid.hit(&ray, 0.001, f32::INFINITY, 0.0).unwrap();
+            let local = inverse.transform_point(hit_record.point());
+            assert!((local.length() - 1.0).abs() < 1e-4, "local: {:?}", local);
+            assert_near(ray.at(hit_record.t()), hit_record.point());
+
+            // 法線は表面に垂直で、単位長さ
+            let normal = hit_record.normal();
+            assert!((normal.length() - 1.0).abs() < 1e-5);
+            let tangent = matrix.transform_vector(local.cross(Vec3::new(0.3, 0.5, 0.7)));
+            assert!(normal.dot(tangent).abs() < 1e-4);
+        }
+        // 外れるレイ
+        let miss = Ray::new(origin, Vec3::new(-1.0, 0.0, 0.0), 0.0);
+        assert!(ellipsoid.hit(&miss, 0.001, f32::INFINITY, 0.0).is_none());
+    }
+
+    #[test]
+    fn uniformly_scaled_light_matches_the_same_rectangle() {
+        let scaled = Transform::new(
+            Arc::new(XzRect::new(0.0, 1.0, 0.0, 1.0, 0.0, material())),
+            Matrix::translation(Vec3::new(1.0, 4.0, 2.0))
+                * Matrix::scaling(Vec3::new(3.0, 3.0, 3.0)),
+        );
+        let rect = XzRect::new(1.0, 4.0, 2.0, 5.0, 4.0, material());
+        assert!((scaled.area(0.0) - rect.area(0.0)).abs() < 1e-4);
+        let origin = Vec3::new(2.0, 0.0, 3.0);
+        for direction in [Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.3, 1.0, 0.2)] {
+            let expected = rect.pdf_value(origin, direction, 0.0);
+            let actual = scaled.pdf_value(origin, direction, 0.0);
+            assert!(
+                (actual - expected).abs() < expected * 1e-3,
+                "expected: {}, actual: {}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn non_uniformly_scaled_object_has_no_area() {
+        let stretched = Transform::new(
+            Arc::new(XzRect::new(0.0, 1.0, 0.0, 1.0, 0.0, material())),
+            general(),
+        );
+        assert_eq!(stretched.area(0.0), 0.0);
+        assert!(stretched.sample_surface(0.0).is_none());
+    }
+}
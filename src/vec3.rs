@@ -1,6 +1,6 @@
 use std::{f32::consts::PI, ops::*};
 
-use rand::Rng;
+use crate::sampler::next_f32;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Vec3 {
@@ -59,6 +59,14 @@ impl Vec3 {
     }
 }
 
+impl Index<usize> for Vec3 {
+    type Output = f32;
+
+    fn index(&self, i: usize) -> &f32 {
+        &self.e[i]
+    }
+}
+
 impl Neg for Vec3 {
     type Output = Vec3;
 
@@ -147,8 +155,7 @@ pub fn origin() -> Vec3 {
 /// ランダムな小数
 pub fn random_f32(min: f32, max: f32) -> f32 {
     let scale = max - min;
-    let mut rng = rand::thread_rng();
-    scale * rng.gen::<f32>() + min
+    scale * next_f32() + min
 }
 
 /// 単位球の中心から球面上を向くランダムなベクトル
@@ -183,6 +190,26 @@ pub fn random_color_range(min: f32, max: f32) -> Vec3 {
     )
 }
 
+/// 単位半球上に余弦に比例する確率で分布するランダムなベクトル(z軸が天頂)
+pub fn random_cosine_direction() -> Vec3 {
+    let r1 = random_f32(0.0, 1.0);
+    let r2 = random_f32(0.0, 1.0);
+    let phi = 2.0 * PI * r1;
+    let z = (1.0 - r2).sqrt();
+    Vec3::new(phi.cos() * r2.sqrt(), phi.sin() * r2.sqrt(), z)
+}
+
+/// 距離distance_squaredの位置にある半径radiusの球が見える範囲の中で一様に分布するランダムなベクトル(z軸が球の中心の方向)
+pub fn random_to_sphere(radius: f32, distance_squared: f32) -> Vec3 {
+    let r1 = random_f32(0.0, 1.0);
+    let r2 = random_f32(0.0, 1.0);
+    let cos_theta_max = (1.0 - radius * radius / distance_squared).max(0.0).sqrt();
+    let z = 1.0 + r2 * (cos_theta_max - 1.0);
+    let phi = 2.0 * PI * r1;
+    let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+    Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z)
+}
+
 /// 金属マテリアルの反射
 pub fn reflect(v: Vec3, n: Vec3) -> Vec3 {
     v - n * v.dot(n) * 2.0
@@ -0,0 +1,588 @@
+use std::f32::consts::PI;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+use crate::aabb::Aabb;
+use crate::hitrecord::HitRecord;
+use crate::hittable::Hittable;
+use crate::material::{Isotropic, Material, ScatterRecord};
+use crate::pdf::UniformSpherePdf;
+use crate::perlin::Perlin;
+use crate::ray::Ray;
+use crate::texture::Texture;
+use crate::vec3::{color, random_f32, Vec3};
+
+/// 密度が一定の霧や煙。閉じた物体boundaryの内側でレイが確率的に散乱する
+pub struct ConstantMedium {
+    boundary: Arc<dyn Hittable>,
+    neg_inv_density: f32,
+    phase_function: Arc<dyn Material>,
+}
+
+impl ConstantMedium {
+    pub fn new(boundary: Arc<dyn Hittable>, density: f32, albedo: Vec3) -> ConstantMedium {
+        ConstantMedium {
+            boundary,
+            neg_inv_density: -1.0 / density,
+            phase_function: Arc::new(Isotropic::new(albedo)),
+        }
+    }
+
+    pub fn with_texture(
+        boundary: Arc<dyn Hittable>,
+        density: f32,
+        albedo: Arc<dyn Texture>,
+    ) -> ConstantMedium {
+        ConstantMedium {
+            boundary,
+            neg_inv_density: -1.0 / density,
+            phase_function: Arc::new(Isotropic::with_texture(albedo)),
+        }
+    }
+}
+
+impl Hittable for ConstantMedium {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, time: f32) -> Option<HitRecord<'_>> {
+        // レイが境界に入る位置と出る位置を求める
+        let record1 = self
+            .boundary
+            .hit(ray, -f32::INFINITY, f32::INFINITY, time)?;
+        let record2 = self
+            .boundary
+            .hit(ray, record1.t() + 0.0001, f32::INFINITY, time)?;
+
+        let t1 = record1.t().max(tmin).max(0.0);
+        let t2 = record2.t().min(tmax);
+        if t1 >= t2 {
+            return None;
+        }
+
+        // 媒質の中を進む距離が指数分布に従う距離より長ければ散乱する
+        let ray_length = ray.direction().length();
+        let distance_inside_boundary = (t2 - t1) * ray_length;
+        let hit_distance = self.neg_inv_density * random_f32(0.0, 1.0).ln();
+        if hit_distance > distance_inside_boundary {
+            return None;
+        }
+
+        let t = t1 + hit_distance / ray_length;
+        // 法線と表裏は散乱の向きに影響しないので適当な値にする
+        Some(HitRecord::new(
+            ray.at(t),
+            Vec3::new(1.0, 0.0, 0.0),
+            true,
+            t,
+            0.0,
+            0.0,
+            self.phase_function.as_ref(),
+        ))
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        self.boundary.bounding_box(time0, time1)
+    }
+}
+
+/// 格子状に並んだ密度などの値。各軸0〜1の座標で三線形補間して参照する
+///
+/// 値はx方向が最も速く変わる順(x、y、zの順)に並ぶ
+pub struct DensityGrid {
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    data: Vec<f32>,
+    max: f32,
+}
+
+impl DensityGrid {
+    pub fn new(nx: usize, ny: usize, nz: usize, data: Vec<f32>) -> DensityGrid {
+        assert!(nx > 0 && ny > 0 && nz > 0, "格子の大きさが0です");
+        assert_eq!(data.len(), nx * ny * nz, "値の数が格子の大きさと違います");
+        let max = data.iter().cloned().fold(0.0, f32::max);
+        DensityGrid {
+            nx,
+            ny,
+            nz,
+            data,
+            max,
+        }
+    }
+
+    /// 各格子点の座標(各軸0〜1)から値を求めて格子を作る
+    pub fn from_fn<F>(nx: usize, ny: usize, nz: usize, f: F) -> DensityGrid
+    where
+        F: Fn(Vec3) -> f32 + Sync,
+    {
+        let data = (0..nx * ny * nz)
+            .into_par_iter()
+            .map(|index| {
+                let x = index % nx;
+                let y = (index / nx) % ny;
+                let z = index / (nx * ny);
+                f(Vec3::new(
+                    (x as f32 + 0.5) / nx as f32,
+                    (y as f32 + 0.5) / ny as f32,
+                    (z as f32 + 0.5) / nz as f32,
+                ))
+            })
+            .collect();
+        DensityGrid::new(nx, ny, nz, data)
+    }
+
+    /// パーリンノイズの乱流で作った雲のような密度。中心から離れるほど薄くなり、境界では0になる
+    pub fn from_noise(resolution: usize, seed: u64, frequency: f32, octaves: u32) -> DensityGrid {
+        let noise = Perlin::new(seed);
+        DensityGrid::from_fn(resolution, resolution, resolution, |p| {
+            let centered = (p - Vec3::new(0.5, 0.5, 0.5)) * 2.0;
+            let falloff = (1.0 - centered.length()).max(0.0);
+            let turbulence = noise.turbulence(p * frequency, octaves);
+            (falloff * 2.0 - 0.5 + turbulence).max(0.0) * falloff
+        })
+    }
+
+    /// リトルエンディアンのf32をnx*ny*nz個並べただけのファイルを読み込む
+    pub fn load_raw(path: &Path, nx: usize, ny: usize, nz: usize) -> io::Result<DensityGrid> {
+        DensityGrid::parse_raw(&fs::read(path)?, nx, ny, nz)
+    }
+
+    fn parse_raw(bytes: &[u8], nx: usize, ny: usize, nz: usize) -> io::Result<DensityGrid> {
+        let size =
+            value_count(&[nx, ny, nz, 4]).ok_or_else(|| invalid_data("格子の大きさが不正です"))?;
+        if bytes.len() != size {
+            return Err(invalid_data(&format!(
+                "{}バイトのはずが{}バイトあります",
+                size,
+                bytes.len()
+            )));
+        }
+        Ok(DensityGrid::new(nx, ny, nz, read_f32s(bytes)))
+    }
+
+    /// Mitsubaのgridvolume形式(.vol)のファイルを読み込む。複数チャンネルの場合は最初のチャンネルを使う
+    ///
+    /// ヘッダーは"VOL"、バージョン(3)、エンコーディング(1 = f32)、x、y、zの解像度、チャンネル数、境界ボックス(f32 x 6)
+    pub fn load_vol(path: &Path) -> io::Result<DensityGrid> {
+        DensityGrid::parse_vol(&fs::read(path)?)
+    }
+
+    fn parse_vol(bytes: &[u8]) -> io::Result<DensityGrid> {
+        if bytes.len() < 48 || &bytes[0..3] != b"VOL" {
+            return Err(invalid_data(".volファイルのヘッダーではありません"));
+        }
+        if bytes[3] != 3 {
+            return Err(invalid_data("対応していないバージョンです"));
+        }
+        let header: Vec<i32> = bytes[4..24]
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        let (encoding, nx, ny, nz, channels) =
+            (header[0], header[1], header[2], header[3], header[4]);
+        if encoding != 1 {
+            return Err(invalid_data(
+                "f32以外のエンコーディングには対応していません",
+            ));
+        }
+        if nx <= 0 || ny <= 0 || nz <= 0 || channels <= 0 {
+            return Err(invalid_data("格子の大きさが不正です"));
+        }
+        let (nx, ny, nz, channels) = (nx as usize, ny as usize, nz as usize, channels as usize);
+        // ヘッダーの値は信用できないので、掛け算のあふれも不正なファイルとして扱う
+        let size = value_count(&[nx, ny, nz, channels, 4])
+            .ok_or_else(|| invalid_data("格子の大きさが不正です"))?;
+        let values = &bytes[48..];
+        if values.len() != size {
+            return Err(invalid_data("値の数が格子の大きさと違います"));
+        }
+        let data = read_f32s(values)
+            .chunks_exact(channels)
+            .map(|c| c[0])
+            .collect();
+        Ok(DensityGrid::new(nx, ny, nz, data))
+    }
+
+    /// 格子の各軸の点の数
+    pub fn resolution(&self) -> (usize, usize, usize) {
+        (self.nx, self.ny, self.nz)
+    }
+
+    pub fn max(&self) -> f32 {
+        self.max
+    }
+
+    fn at(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.data[x + self.nx * (y + self.ny * z)]
+    }
+
+    /// 各軸0〜1の座標pにおける値。範囲外は0
+    pub fn lookup(&self, p: Vec3) -> f32 {
+        if !(0.0..=1.0).contains(&p.x())
+            || !(0.0..=1.0).contains(&p.y())
+            || !(0.0..=1.0).contains(&p.z())
+        {
+            return 0.0;
+        }
+        // 格子点は各セルの中心にある
+        let gx = (p.x() * self.nx as f32 - 0.5).max(0.0);
+        let gy = (p.y() * self.ny as f32 - 0.5).max(0.0);
+        let gz = (p.z() * self.nz as f32 - 0.5).max(0.0);
+        let x0 = (gx as usize).min(self.nx - 1);
+        let y0 = (gy as usize).min(self.ny - 1);
+        let z0 = (gz as usize).min(self.nz - 1);
+        let x1 = (x0 + 1).min(self.nx - 1);
+        let y1 = (y0 + 1).min(self.ny - 1);
+        let z1 = (z0 + 1).min(self.nz - 1);
+        let (fx, fy, fz) = (gx - x0 as f32, gy - y0 as f32, gz - z0 as f32);
+
+        let lerp = |a: f32, b: f32, t: f32| a * (1.0 - t) + b * t;
+        let c00 = lerp(self.at(x0, y0, z0), self.at(x1, y0, z0), fx);
+        let c10 = lerp(self.at(x0, y1, z0), self.at(x1, y1, z0), fx);
+        let c01 = lerp(self.at(x0, y0, z1), self.at(x1, y0, z1), fx);
+        let c11 = lerp(self.at(x0, y1, z1), self.at(x1, y1, z1), fx);
+        lerp(lerp(c00, c10, fy), lerp(c01, c11, fy), fz)
+    }
+}
+
+/// 各軸の大きさなどを掛けた値の数。0を含むか、掛け算があふれる場合はNone
+fn value_count(factors: &[usize]) -> Option<usize> {
+    factors
+        .iter()
+        .try_fold(1_usize, |count, &factor| count.checked_mul(factor))
+        .filter(|&count| count > 0)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_f32s(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// 密度が場所によって変わる媒質。密度の格子をboundsの範囲に引き伸ばして置く
+///
+/// 散乱する位置はデルタトラッキングで求める。影のレイも同じく、途中で散乱するかどうかで透過率を確率的に表す。
+/// 温度の格子を与えると、温度に応じた黒体放射の色で光る(炎)
+pub struct HeterogeneousMedium {
+    bounds: Aabb,
+    density: DensityGrid,
+    density_scale: f32,
+    majorant: f32,
+    albedo: Vec3,
+    phase_function: Arc<dyn Material>,
+}
+
+impl HeterogeneousMedium {
+    /// 密度は格子の値にdensity_scaleをかけたもの
+    pub fn new(
+        bounds: Aabb,
+        density: DensityGrid,
+        density_scale: f32,
+        albedo: Vec3,
+    ) -> HeterogeneousMedium {
+        let majorant = density.max() * density_scale;
+        HeterogeneousMedium {
+            bounds,
+            density,
+            density_scale,
+            majorant,
+            albedo,
+            phase_function: Arc::new(VolumePhase {
+                albedo,
+                bounds,
+                temperature: None,
+                emission_scale: 0.0,
+            }),
+        }
+    }
+
+    /// 温度の格子(0〜1)を与えて光らせる。emission_scaleは温度1での明るさ
+    pub fn with_emission(
+        mut self,
+        temperature: DensityGrid,
+        emission_scale: f32,
+    ) -> HeterogeneousMedium {
+        self.phase_function = Arc::new(VolumePhase {
+            albedo: self.albedo,
+            bounds: self.bounds,
+            temperature: Some(temperature),
+            emission_scale,
+        });
+        self
+    }
+
+    /// ワールド座標の点における密度
+    pub fn density_at(&self, p: Vec3) -> f32 {
+        self.density.lookup(local_coordinates(&self.bounds, p)) * self.density_scale
+    }
+}
+
+impl Hittable for HeterogeneousMedium {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, _time: f32) -> Option<HitRecord<'_>> {
+        let (t0, t1) = self.bounds.clip(ray, tmin.max(0.0), tmax)?;
+        if self.majorant <= 0.0 {
+            return None;
+        }
+
+        // デルタトラッキング: 最大密度で仮の衝突を繰り返し、密度の比の確率で本当に衝突させる
+        let ray_length = ray.direction().length();
+        let mut t = t0;
+        loop {
+            t -= (1.0 - random_f32(0.0, 1.0)).ln() / (self.majorant * ray_length);
+            if t >= t1 {
+                return None;
+            }
+            if random_f32(0.0, 1.0) < self.density_at(ray.at(t)) / self.majorant {
+                return Some(HitRecord::new(
+                    ray.at(t),
+                    Vec3::new(1.0, 0.0, 0.0),
+                    true,
+                    t,
+                    0.0,
+                    0.0,
+                    self.phase_function.as_ref(),
+                ));
+            }
+        }
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+}
+
+/// boundsの中の点を各軸0〜1の座標に直す
+fn local_coordinates(bounds: &Aabb, p: Vec3) -> Vec3 {
+    let size = bounds.max() - bounds.min();
+    let d = p - bounds.min();
+    Vec3::new(d.x() / size.x(), d.y() / size.y(), d.z() / size.z())
+}
+
+/// 不均一な媒質の位相関数。等方的に散乱し、温度の格子があればその位置の温度に応じて光る
+struct VolumePhase {
+    albedo: Vec3,
+    bounds: Aabb,
+    temperature: Option<DensityGrid>,
+    emission_scale: f32,
+}
+
+impl Material for VolumePhase {
+    fn scatter(&self, _ray_in: &Ray, _hit_record: &HitRecord) -> Option<ScatterRecord> {
+        Some(ScatterRecord::Diffuse {
+            pdf: Box::new(UniformSpherePdf),
+        })
+    }
+
+    fn eval(&self, _ray_in: &Ray, _hit_record: &HitRecord, _scattered: &Ray) -> Vec3 {
+        self.albedo / (4.0 * PI)
+    }
+
+    fn albedo(&self, _hit_record: &HitRecord) -> Vec3 {
+        self.albedo
+    }
+
+    fn is_volume(&self) -> bool {
+        true
+    }
+
+    fn emitted(&self, _ray_in: &Ray, hit_record: &HitRecord) -> Vec3 {
+        match &self.temperature {
+            Some(temperature) => {
+                let t = temperature
+                    .lookup(local_coordinates(&self.bounds, hit_record.point()))
+                    .clamp(0.0, 1.0);
+                // 温度0〜1を1000K〜6500Kに対応させ、明るさは温度の4乗に比例させる
+                blackbody_color(1000.0 + 5500.0 * t) * (self.emission_scale * t.powi(4))
+            }
+            None => color(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/// 黒体放射の色の近似(Tanner Hellandによる式)。各成分は0〜1
+pub fn blackbody_color(kelvin: f32) -> Vec3 {
+    let t = kelvin / 100.0;
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.698_73 * (t - 60.0).powf(-0.133_204_76)
+    };
+    let g = if t <= 66.0 {
+        99.470_8 * t.ln() - 161.119_57
+    } else {
+        288.122_16 * (t - 60.0).powf(-0.075_514_85)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.517_73 * (t - 10.0).ln() - 305.044_8
+    };
+    color(
+        r.clamp(0.0, 255.0) / 255.0,
+        g.clamp(0.0, 255.0) / 255.0,
+        b.clamp(0.0, 255.0) / 255.0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// .volファイルの中身。境界ボックスは単位立方体にする
+    fn vol_bytes(
+        version: u8,
+        encoding: i32,
+        size: [i32; 3],
+        channels: i32,
+        values: &[f32],
+    ) -> Vec<u8> {
+        let mut bytes = b"VOL".to_vec();
+        bytes.push(version);
+        for value in [encoding, size[0], size[1], size[2], channels] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [0.0_f32, 0.0, 0.0, 1.0, 1.0, 1.0] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in values {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn error_kind(result: io::Result<DensityGrid>) -> io::ErrorKind {
+        match result {
+            Ok(_) => panic!("読み込めてはいけない"),
+            Err(e) => e.kind(),
+        }
+    }
+
+    #[test]
+    fn parses_vol_with_multiple_channels() {
+        // 2 x 1 x 2の格子で、チャンネルは2つ。最初のチャンネルだけを使う
+        let values = [1.0, -1.0, 2.0, -2.0, 3.0, -3.0, 4.0, -4.0];
+        let grid = DensityGrid::parse_vol(&vol_bytes(3, 1, [2, 1, 2], 2, &values)).unwrap();
+        assert_eq!(grid.resolution(), (2, 1, 2));
+        assert_eq!(grid.data, vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(grid.max(), 4.0);
+        // 格子点はセルの中心にある
+        assert_eq!(grid.lookup(Vec3::new(0.75, 0.5, 0.25)), 2.0);
+        assert_eq!(grid.lookup(Vec3::new(0.25, 0.5, 0.75)), 3.0);
+    }
+
+    #[test]
+    fn rejects_invalid_vol() {
+        let values = [0.5; 4];
+        let invalid = io::ErrorKind::InvalidData;
+        assert_eq!(
+            error_kind(DensityGrid::parse_vol(&vol_bytes(
+                2,
+                1,
+                [2, 2, 1],
+                1,
+                &values
+            ))),
+            invalid
+        );
+        assert_eq!(
+            error_kind(DensityGrid::parse_vol(&vol_bytes(
+                3,
+                2,
+                [2, 2, 1],
+                1,
+                &values
+            ))),
+            invalid
+        );
+        // 値の数が足りない、多すぎる
+        assert_eq!(
+            error_kind(DensityGrid::parse_vol(&vol_bytes(
+                3,
+                1,
+                [2, 2, 2],
+                1,
+                &values
+            ))),
+            invalid
+        );
+        assert_eq!(
+            error_kind(DensityGrid::parse_vol(&vol_bytes(
+                3,
+                1,
+                [1, 2, 1],
+                1,
+                &values
+            ))),
+            invalid
+        );
+        // 解像度を掛けるとあふれるヘッダー
+        let huge = i32::MAX;
+        assert_eq!(
+            error_kind(DensityGrid::parse_vol(&vol_bytes(
+                3,
+                1,
+                [huge, huge, huge],
+                huge,
+                &values
+            ))),
+            invalid
+        );
+        assert_eq!(
+            error_kind(DensityGrid::parse_vol(&vol_bytes(3, 1, [0, 2, 2], 1, &[]))),
+            invalid
+        );
+        assert_eq!(error_kind(DensityGrid::parse_vol(b"VOL")), invalid);
+    }
+
+    #[test]
+    fn parses_raw() {
+        let bytes: Vec<u8> = [1.0_f32, 2.0, 3.0, 4.0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let grid = DensityGrid::parse_raw(&bytes, 2, 2, 1).unwrap();
+        assert_eq!(grid.data, vec![1.0, 2.0, 3.0, 4.0]);
+        let invalid = io::ErrorKind::InvalidData;
+        assert_eq!(error_kind(DensityGrid::parse_raw(&bytes, 2, 2, 2)), invalid);
+        assert_eq!(
+            error_kind(DensityGrid::parse_raw(&bytes, usize::MAX, 2, 1)),
+            invalid
+        );
+        assert_eq!(error_kind(DensityGrid::parse_raw(&[], 0, 2, 1)), invalid);
+    }
+
+    #[test]
+    fn lookup_interpolates_trilinearly() {
+        // 格子点の値が座標の1次式なら、三線形補間した値も同じ1次式になる
+        let f = |p: Vec3| 1.0 + 2.0 * p.x() + 3.0 * p.y() + 4.0 * p.z();
+        let grid = DensityGrid::from_fn(4, 3, 2, f);
+        for p in [
+            Vec3::new(0.5, 0.5, 0.5),
+            Vec3::new(0.2, 0.3, 0.4),
+            Vec3::new(0.8, 0.7, 0.6),
+            Vec3::new(0.125, 1.0 / 6.0, 0.25),
+        ] {
+            assert!(
+                (grid.lookup(p) - f(p)).abs() < 1e-5,
+                "p: {:?}, expected: {}, actual: {}",
+                p,
+                f(p),
+                grid.lookup(p)
+            );
+        }
+        // 端の格子点より外側は端の値のまま、範囲外は0
+        assert!(
+            (grid.lookup(Vec3::new(0.05, 0.5, 0.5)) - f(Vec3::new(0.125, 0.5, 0.5))).abs() < 1e-5
+        );
+        assert_eq!(grid.lookup(Vec3::new(1.5, 0.5, 0.5)), 0.0);
+    }
+}